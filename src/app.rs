@@ -1,15 +1,376 @@
+use std::fmt;
+use quick_xml::{Reader, events::{BytesStart, Event}};
+use tracing::debug;
+use crate::{Device, Icon};
+use crate::error::{xml_error, KoruError, Result};
+
+/// App identifier, e.g. "12", "tvinput.hdmi1", or "dev" for sideloaded channels
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(transparent))]
+pub struct AppId(String);
+
+impl AppId {
+    /// Identifier as sent to and from the device
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// Whether this is the sideloaded developer channel
+    pub fn is_dev(&self) -> bool {
+        self.0 == "dev"
+    }
+}
+
+impl fmt::Display for AppId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl From<String> for AppId {
+    fn from(s: String) -> Self {
+        AppId(s)
+    }
+}
+
+impl From<&str> for AppId {
+    fn from(s: &str) -> Self {
+        AppId(String::from(s))
+    }
+}
+
+impl From<&AppId> for AppId {
+    fn from(id: &AppId) -> Self {
+        id.clone()
+    }
+}
+
+impl From<i32> for AppId {
+    fn from(id: i32) -> Self {
+        AppId(id.to_string())
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct App {
-    pub id: i32,
-    pub apptype: String,
+    pub id: AppId,
+    pub apptype: String,            // e.g. appl, tvin, menu
+    pub subtype: Option<String>,    // e.g. ndka, rsga, sdka (newer firmware only)
     pub version: String,
     pub name: String,
-    pub icon: Option<Vec<u8>>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub icon: Option<Icon>,       // Only present once fetched (see IconCache for keeping them around)
 }
 
 impl App {
-    pub async fn fetch_icon(&self) -> Result<Vec<u8>, String> {
-        // self.icon = ...
-        todo!()
+    /// Fetch this app's icon from the device it's installed on, keeping a copy in `self.icon`
+    pub async fn fetch_icon(&mut self, device: &Device) -> Result<Icon> {
+        let icon = device.fetch_icon(&self.id).await?;
+        self.icon = Some(icon.clone());
+        Ok(icon)
+    }
+
+    /// Parse apps XML (e.g. from query/apps) into a list of apps
+    pub fn parse_list(xml: &str) -> Result<Vec<App>> {
+        // Parsed apps
+        let mut apps_parsed: Vec<App> = Vec::new();
+        // Create XML reader
+        let mut reader = Reader::from_str(xml);
+        reader.trim_text(true);
+        // XML event buffer
+        let mut buffer = Vec::new();
+        // App from the currently-open tag, waiting for its name
+        let mut app: Option<App> = None;
+        // Loop the XML
+        loop {
+            match reader.read_event(&mut buffer) {
+                // Read each <app> tag
+                Ok(Event::Start(ref e)) if e.name() == b"app" => app = App::from_list_tag(&reader, e)?,
+                // Self-closing <app/> tags have no name
+                Ok(Event::Empty(ref e)) if e.name() == b"app" => apps_parsed.extend(App::from_list_tag(&reader, e)?),
+                // Update currently-parsed app name
+                Ok(Event::Text(e)) => {
+                    if let Some(app) = app.as_mut() {
+                        app.name = e.unescape_and_decode(&reader)
+                            .map_err(|e| xml_error(&reader, e))?
+                            .replace('\u{a0}', "");     // There are newline characters in some names
+                    }
+                },
+                // Add app to list of parsed apps
+                Ok(Event::End(ref e)) if e.name() == b"app" => {
+                    if let Some(app) = app.take() {
+                        apps_parsed.push(app);
+                    }
+                },
+                // Break at EOF
+                Ok(Event::Eof) => break,
+                Err(e) => return Err(xml_error(&reader, e)),
+                _ => (),
+            }
+            buffer.clear();
+        }
+        // Return list of apps
+        Ok(apps_parsed)
+    }
+
+    /// Create an app from an <app> tag in a list, skipping one w/o an id rather than failing the whole list
+    fn from_list_tag(reader: &Reader<&[u8]>, tag: &BytesStart) -> Result<Option<App>> {
+        match App::from_tag(reader, tag) {
+            Ok(app) => Ok(Some(app)),
+            Err(KoruError::Parse(message)) => {
+                debug!(position = reader.buffer_position(), "skipping app: {}", message);
+                Ok(None)
+            }
+            Err(e) => Err(e)
+        }
+    }
+
+    /// Create an app from the attributes of an <app> tag, matching them by name
+    fn from_tag(reader: &Reader<&[u8]>, tag: &BytesStart) -> Result<App> {
+        let mut app = App {
+            id: AppId::default(),
+            apptype: String::new(),
+            subtype: None,
+            version: String::new(),
+            name: String::new(),
+            icon: None
+        };
+        let mut has_id = false;
+        for attribute in tag.attributes() {
+            let attribute = attribute.map_err(|e| xml_error(reader, e))?;
+            let value = attribute.unescape_and_decode_value(reader).map_err(|e| xml_error(reader, e))?;
+            match attribute.key {
+                b"id" => {
+                    app.id = AppId::from(value);
+                    has_id = true;
+                },
+                b"type" => app.apptype = value,
+                b"subtype" => app.subtype = Some(value),
+                b"version" => app.version = value,
+                // Ignore anything newer firmware adds
+                _ => ()
+            }
+        }
+        if has_id && !app.id.as_str().is_empty() {
+            Ok(app)
+        } else {
+            Err(KoruError::Parse(String::from("app is missing its id")))
+        }
+    }
+}
+
+/// What's currently on screen, as reported by query/active-app
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ActiveApp {
+    Home { screensaver: Option<App> },              // Home screen, no app running
+    App { app: App, screensaver: Option<App> },     // An app is in the foreground
+}
+
+impl ActiveApp {
+    /// Foreground app, if not on the home screen
+    pub fn app(&self) -> Option<&App> {
+        match self {
+            ActiveApp::App { app, .. } => Some(app),
+            ActiveApp::Home { .. } => None
+        }
     }
-}
\ No newline at end of file
+
+    /// Screensaver app, if one is running
+    pub fn screensaver(&self) -> Option<&App> {
+        match self {
+            ActiveApp::Home { screensaver } | ActiveApp::App { screensaver, .. } => screensaver.as_ref()
+        }
+    }
+
+    /// Whether the home screen is in the foreground
+    pub fn is_home(&self) -> bool {
+        matches!(self, ActiveApp::Home { .. })
+    }
+
+    /// Parse active-app XML
+    pub fn from_xml(xml: &str) -> Result<ActiveApp> {
+        // Foreground app (None for the home screen) and screensaver
+        let mut foreground: Option<App> = None;
+        let mut screensaver: Option<App> = None;
+        // Create XML reader
+        let mut reader = Reader::from_str(xml);
+        reader.trim_text(true);
+        // XML event buffer
+        let mut buffer = Vec::new();
+        // App from the currently-open tag, waiting for its name
+        let mut current: Option<App> = None;
+        // Loop the XML
+        loop {
+            match reader.read_event(&mut buffer) {
+                // Read each <app>/<screensaver> tag (the home screen is an <app> w/o an id)
+                Ok(Event::Start(ref e)) if is_active_tag(e) => current = Some(App::from_tag(&reader, e)?),
+                // Self-closing tags have no name
+                Ok(Event::Empty(ref e)) if is_active_tag(e) => {
+                    let app = Some(App::from_tag(&reader, e)?);
+                    if e.name() == b"screensaver" { screensaver = app } else { foreground = app }
+                },
+                // Update currently-parsed app name
+                Ok(Event::Text(e)) => {
+                    if let Some(app) = current.as_mut() {
+                        app.name = e.unescape_and_decode(&reader).map_err(|e| xml_error(&reader, e))?;
+                    }
+                },
+                // Keep the finished app
+                Ok(Event::End(ref e)) if current.is_some() => {
+                    if e.name() == b"screensaver" { screensaver = current.take() } else { foreground = current.take() }
+                },
+                // Break at EOF
+                Ok(Event::Eof) => break,
+                Err(e) => return Err(xml_error(&reader, e)),
+                _ => (),
+            }
+            buffer.clear();
+        }
+        Ok(match foreground {
+            Some(app) => ActiveApp::App { app, screensaver },
+            None => ActiveApp::Home { screensaver }
+        })
+    }
+}
+
+/// Whether a tag is an <app> or <screensaver> with an id
+fn is_active_tag(tag: &BytesStart) -> bool {
+    (tag.name() == b"app" || tag.name() == b"screensaver") && tag.attributes().flatten().any(|a| a.key == b"id")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use quickcheck::{quickcheck, TestResult};
+
+    const FIRMWARE_7: &str = include_str!("../fixtures/apps/firmware-7.xml");
+    const FIRMWARE_9: &str = include_str!("../fixtures/apps/firmware-9.xml");
+    const FIRMWARE_10: &str = include_str!("../fixtures/apps/firmware-10.xml");
+    const MISSING_ID: &str = include_str!("../fixtures/apps/missing-id.xml");
+    const ACTIVE_HOME: &str = include_str!("../fixtures/active-app/home.xml");
+    const ACTIVE_APP: &str = include_str!("../fixtures/active-app/app.xml");
+    const ACTIVE_SCREENSAVER: &str = include_str!("../fixtures/active-app/screensaver.xml");
+
+    #[test]
+    fn parses_firmware_7_apps() {
+        let apps = App::parse_list(FIRMWARE_7).unwrap();
+        assert_eq!(apps.len(), 4);
+        assert_eq!(apps[0].id, AppId::from(31012));
+        assert_eq!(apps[0].apptype, "menu");
+        assert_eq!(apps[1].id.as_str(), "12");
+        assert_eq!(apps[1].version, "4.1.218");
+        assert_eq!(apps[1].name, "Netflix");
+        assert_eq!(apps[1].subtype, None);
+    }
+
+    #[test]
+    fn parses_firmware_9_apps() {
+        let apps = App::parse_list(FIRMWARE_9).unwrap();
+        assert_eq!(apps.len(), 6);
+        assert_eq!(apps[0].id.as_str(), "tvinput.hdmi1");
+        assert_eq!(apps[0].apptype, "tvin");
+        assert_eq!(apps[1].name, "Apple TV");
+        assert_eq!(apps[4].name, "AT&T TV");
+        assert!(apps[5].id.is_dev());
+        assert_eq!(apps[5].name, "My Dev Channel");
+    }
+
+    #[test]
+    fn parses_firmware_10_apps() {
+        let apps = App::parse_list(FIRMWARE_10).unwrap();
+        assert_eq!(apps.len(), 4);
+        assert_eq!(apps[0].subtype.as_deref(), Some("ndka"));
+        // Attribute order doesn't matter
+        assert_eq!(apps[1].id.as_str(), "12");
+        assert_eq!(apps[1].apptype, "appl");
+        assert_eq!(apps[1].version, "4.2.81179053");
+        assert_eq!(apps[1].name, "Netflix");
+        // Self-closing tags still produce an app
+        assert_eq!(apps[3].id.as_str(), "562859");
+        assert_eq!(apps[3].name, "");
+    }
+
+    #[test]
+    fn mismatched_tags_are_xml_errors() {
+        assert!(matches!(App::parse_list("<apps></spap>"), Err(KoruError::XmlParse { .. })));
+        assert!(matches!(ActiveApp::from_xml("<active-app><app id=\"12\">Netflix</ppa></active-app>"), Err(KoruError::XmlParse { .. })));
+    }
+
+    #[test]
+    fn parses_active_home_screen() {
+        let active = ActiveApp::from_xml(ACTIVE_HOME).unwrap();
+        assert!(active.is_home());
+        assert_eq!(active.app(), None);
+        assert_eq!(active.screensaver(), None);
+    }
+
+    #[test]
+    fn parses_active_app() {
+        let active = ActiveApp::from_xml(ACTIVE_APP).unwrap();
+        let app = active.app().unwrap();
+        assert_eq!(app.id.as_str(), "12");
+        assert_eq!(app.name, "Netflix");
+        assert_eq!(app.version, "4.1.218");
+        assert_eq!(active.screensaver(), None);
+    }
+
+    #[test]
+    fn parses_active_screensaver() {
+        let active = ActiveApp::from_xml(ACTIVE_SCREENSAVER).unwrap();
+        assert_eq!(active.app().unwrap().id.as_str(), "2285");
+        let screensaver = active.screensaver().unwrap();
+        assert_eq!(screensaver.id.as_str(), "55545");
+        assert_eq!(screensaver.apptype, "ssvr");
+        assert_eq!(screensaver.name, "Default screensaver");
+        // Screensaver over the home screen
+        let active = ActiveApp::from_xml("<active-app><app>Roku</app><screensaver id=\"55545\" type=\"ssvr\" version=\"2.0.1\"/></active-app>").unwrap();
+        assert!(active.is_home());
+        assert_eq!(active.screensaver().unwrap().id.as_str(), "55545");
+    }
+
+    #[test]
+    fn skips_apps_without_ids() {
+        let apps = App::parse_list(MISSING_ID).unwrap();
+        let ids = apps.iter().map(|app| app.id.as_str()).collect::<Vec<_>>();
+        assert_eq!(ids, ["tvinput.hdmi1", "12", "2285"]);
+        // The skipped app's name doesn't end up on its neighbours
+        assert_eq!(apps[1].name, "Netflix");
+        assert_eq!(apps[2].name, "Hulu");
+    }
+
+    #[test]
+    fn hostile_app_attributes_are_errors() {
+        // Malformed attribute
+        assert!(App::parse_list("<apps><app id=12 type=\"appl\" version=\"1\">Netflix</app></apps>").is_err());
+    }
+
+    #[test]
+    fn parser_never_panics_on_arbitrary_input() {
+        fn prop(input: String) -> bool {
+            let _ = App::parse_list(&input);
+            let _ = ActiveApp::from_xml(&input);
+            true
+        }
+        quickcheck(prop as fn(String) -> bool);
+    }
+
+    #[test]
+    fn parser_never_panics_on_truncated_xml() {
+        fn prop(cut: usize) -> TestResult {
+            for fixture in [FIRMWARE_7, FIRMWARE_9, FIRMWARE_10, ACTIVE_SCREENSAVER].iter() {
+                let cut = cut % (fixture.len() + 1);
+                // Only cut on character boundaries
+                if !fixture.is_char_boundary(cut) {
+                    return TestResult::discard();
+                }
+                let _ = App::parse_list(&fixture[..cut]);
+                let _ = ActiveApp::from_xml(&fixture[..cut]);
+            }
+            TestResult::passed()
+        }
+        quickcheck(prop as fn(usize) -> TestResult);
+    }
+}
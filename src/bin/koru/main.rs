@@ -0,0 +1,460 @@
+/// koru command-line tool, mirroring the Device API
+use std::error::Error;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::str::FromStr;
+use std::time::Duration;
+use clap::{ArgAction, Parser, Subcommand, ValueEnum};
+use futures::StreamExt;
+use serde::Serialize;
+use koru::gateway::Gateway;
+use koru::mqtt::{BridgeOptions, MqttBridge};
+use koru::{
+    discover_stream, scan_devices, ActiveApp, Device, DeviceRegistry, DiscoveryOptions, Ipv4Net, LaunchRequest,
+    IconCache, MediaType, RegisteredDevice, ScanOptions, WatchOptions, BUTTON, ECP_PORT, MIN_POLL_INTERVAL, POWERCOMMAND
+};
+
+mod remote;
+
+type CliResult<T> = std::result::Result<T, Box<dyn Error>>;
+
+#[derive(Debug, Parser)]
+#[command(name = "koru", version, about = "Control Roku devices over the network")]
+struct Cli {
+    /// Device to control: IP address (optionally with :port), name, serial number or saved alias
+    #[arg(short, long, global = true, env = "KORU_DEVICE")]
+    device: Option<String>,
+
+    /// Print JSON instead of text
+    #[arg(long, global = true)]
+    json: bool,
+
+    /// Saved device registry [default: ~/.config/koru/devices.json]
+    #[arg(long, global = true, env = "KORU_REGISTRY")]
+    registry: Option<PathBuf>,
+
+    /// Log diagnostics to stderr (repeat for more detail)
+    #[arg(short, long, global = true, action = ArgAction::Count)]
+    verbose: u8,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Find devices on the network and save them to the registry
+    Discover {
+        /// Seconds to wait for responses
+        #[arg(long, default_value_t = 3)]
+        timeout: u64,
+        /// Probe a subnet (e.g. 192.168.1.0/24) instead of using SSDP, for networks that drop multicast
+        #[arg(long)]
+        scan: Option<Ipv4Net>,
+    },
+    /// Show device info
+    Info,
+    /// Turn the device on or off, or show its power state
+    Power {
+        #[arg(value_enum)]
+        action: PowerAction,
+    },
+    /// List installed apps
+    Apps,
+    /// Launch an app by id or name, optionally deep linking into content
+    Launch {
+        app: String,
+        /// Content to open, e.g. a movie or episode id
+        #[arg(long)]
+        content_id: Option<String>,
+        /// Type of the content, e.g. movie, episode, series, live
+        #[arg(long)]
+        media_type: Option<String>,
+    },
+    /// Press remote buttons in order, e.g. Home Down Down Select
+    Press {
+        #[arg(required = true)]
+        buttons: Vec<String>,
+    },
+    /// Type text into the focused field
+    Type {
+        text: String,
+    },
+    /// Show the app (or home screen) in the foreground
+    ActiveApp,
+    /// Show media player state
+    Media,
+    /// Save an alias for the device, e.g. living-room
+    Alias {
+        alias: String,
+    },
+    /// Control the device interactively from the keyboard
+    Remote,
+    /// Serve saved devices as a JSON REST API until interrupted
+    Gateway {
+        /// Address to listen on
+        #[arg(long, default_value = "127.0.0.1:8090")]
+        listen: SocketAddr,
+        /// Discover devices at startup, adding them to the registry
+        #[arg(long)]
+        discover: bool,
+        /// Cache app icons in this directory
+        #[arg(long)]
+        icon_cache: Option<PathBuf>,
+        /// Poll every device this often (in seconds) for the /events feed
+        #[arg(long, value_name = "SECONDS", value_parser = parse_seconds)]
+        poll: Option<f64>,
+        /// Poll one device at its own interval, e.g. living-room=1 (repeatable)
+        #[arg(long, value_name = "DEVICE=SECONDS", value_parser = parse_poll_device)]
+        poll_device: Vec<(String, f64)>,
+    },
+    /// Bridge saved devices to an MQTT broker, with Home Assistant discovery, until interrupted
+    Mqtt {
+        /// Broker to connect to, as host or host:port
+        #[arg(long, value_name = "HOST[:PORT]", default_value = "localhost", value_parser = parse_broker)]
+        broker: (String, u16),
+        /// Username for the broker
+        #[arg(long, env = "KORU_MQTT_USERNAME")]
+        username: Option<String>,
+        /// Password for the broker
+        #[arg(long, env = "KORU_MQTT_PASSWORD", hide_env_values = true, requires = "username")]
+        password: Option<String>,
+        /// Prefix for state and command topics
+        #[arg(long, default_value = "koru")]
+        base_topic: String,
+        /// Home Assistant's discovery prefix
+        #[arg(long, default_value = "homeassistant")]
+        discovery_prefix: String,
+        /// Discover devices at startup, adding them to the registry
+        #[arg(long)]
+        discover: bool,
+        /// Poll every device this often (in seconds)
+        #[arg(long, value_name = "SECONDS", default_value = "5", value_parser = parse_seconds)]
+        poll: f64,
+    },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum PowerAction {
+    On,
+    Off,
+    Toggle,
+    Status,
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let cli = Cli::parse();
+    if cli.verbose > 0 {
+        let level = if cli.verbose > 1 { tracing::Level::TRACE } else { tracing::Level::DEBUG };
+        tracing_subscriber::fmt().with_max_level(level).with_writer(std::io::stderr).init();
+    }
+    match run(cli).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("koru: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+async fn run(cli: Cli) -> CliResult<()> {
+    let registry_path = cli.registry.clone().unwrap_or_else(default_registry_path);
+    let mut registry = DeviceRegistry::load(&registry_path).await?;
+
+    // Discovery doesn't need a device
+    if let Command::Discover { timeout, scan } = &cli.command {
+        let devices = discover(&mut registry, Duration::from_secs(*timeout), *scan).await?;
+        registry.save().await?;
+        return print(cli.json, &devices, || devices.iter().map(describe).collect::<Vec<_>>().join("\n"));
+    }
+    // Neither does the gateway, which serves every device in the registry
+    if let Command::Gateway { listen, discover, icon_cache, poll, poll_device } = cli.command {
+        let mut gateway = Gateway::new(registry);
+        if let Some(dir) = icon_cache {
+            gateway = gateway.icon_cache(IconCache::new(dir));
+        }
+        if discover {
+            gateway.discover(DiscoveryOptions::default()).await?;
+        }
+        if let Some(seconds) = poll {
+            gateway.watch_all(WatchOptions { interval: Duration::from_secs_f64(seconds), ..WatchOptions::default() }).await;
+        }
+        for (device, seconds) in poll_device {
+            gateway.watch_device(&device, WatchOptions { interval: Duration::from_secs_f64(seconds), ..WatchOptions::default() }).await?;
+        }
+        let server = gateway.serve(listen).await?;
+        eprintln!("koru: serving {} devices on http://{}", gateway.devices().await.len(), server.address());
+        tokio::signal::ctrl_c().await?;
+        return Ok(());
+    }
+    // Or the MQTT bridge
+    if let Command::Mqtt { broker: (host, port), username, password, base_topic, discovery_prefix, discover: rediscover, poll } = cli.command {
+        if rediscover {
+            discover(&mut registry, Duration::new(3, 0), None).await?;
+            registry.save().await?;
+        }
+        let devices = registry.devices().cloned().collect::<Vec<_>>();
+        if devices.is_empty() {
+            return Err("no saved devices, run koru discover first (or pass --discover)".into());
+        }
+        let options = BridgeOptions {
+            host,
+            port,
+            credentials: username.map(|username| (username, password.unwrap_or_default())),
+            base_topic,
+            discovery_prefix,
+            watch: WatchOptions { interval: Duration::from_secs_f64(poll), ..WatchOptions::default() },
+            ..BridgeOptions::default()
+        };
+        let broker = format!("{}:{}", options.host, options.port);
+        let _bridge = MqttBridge::start(options, devices.clone()).await?;
+        eprintln!("koru: bridging {} devices to mqtt://{}", devices.len(), broker);
+        tokio::signal::ctrl_c().await?;
+        return Ok(());
+    }
+
+    let device = select_device(cli.device.as_deref(), &mut registry).await?;
+    match cli.command {
+        Command::Discover { .. } | Command::Gateway { .. } | Command::Mqtt { .. } => unreachable!("handled above"),
+        Command::Info => {
+            let info = device.get_info().await?;
+            print(cli.json, &info, || {
+                let field = |name: &str, value: Option<&String>| value.map(|v| format!("{}: {}\n", name, v)).unwrap_or_default();
+                format!("{}{}{}{}Power: {}\nNetwork: {}\n{}{}",
+                        field("Name", info.display_name().map(String::from).as_ref()),
+                        field("Model", info.model_name.as_ref()),
+                        field("Serial", info.serial_number.as_ref()),
+                        field("Software", info.software_version.as_ref()),
+                        info.power_mode,
+                        info.network_type,
+                        info.wifi_mac.map(|mac| format!("Wi-Fi MAC: {}\n", mac)).unwrap_or_default(),
+                        info.ethernet_mac.map(|mac| format!("Ethernet MAC: {}\n", mac)).unwrap_or_default())
+                    .trim_end().to_string()
+            })
+        }
+        Command::Power { action } => {
+            let command = match action {
+                PowerAction::Status => {
+                    let state = device.get_power_state().await;
+                    return print(cli.json, &state, || state.to_string());
+                }
+                PowerAction::On => POWERCOMMAND::TURNON,
+                PowerAction::Off => POWERCOMMAND::TURNOFF,
+                PowerAction::Toggle => POWERCOMMAND::TOGGLE,
+            };
+            device.send_power_command(command).await?;
+            Ok(())
+        }
+        Command::Apps => {
+            let apps = device.get_installed_apps().await?;
+            print(cli.json, &apps, || apps.iter().map(|app| format!("{}\t{}\t{}", app.id, app.name, app.version)).collect::<Vec<_>>().join("\n"))
+        }
+        Command::Launch { app, content_id, media_type } => {
+            let mut request = LaunchRequest::new(resolve_app(&device, &app).await);
+            if let Some(content_id) = content_id {
+                request = request.content_id(content_id);
+            }
+            if let Some(media_type) = media_type {
+                request = request.media_type(MediaType::from(media_type.as_str()));
+            }
+            device.launch(&request).await?;
+            Ok(())
+        }
+        Command::Press { buttons } => {
+            let buttons = buttons.iter().map(|b| b.parse()).collect::<koru::Result<Vec<BUTTON>>>()?;
+            device.press_buttons(buttons).await?;
+            Ok(())
+        }
+        Command::Type { text } => {
+            device.press_keys(&text).await?;
+            Ok(())
+        }
+        Command::ActiveApp => {
+            let active = device.get_active_app().await?;
+            print(cli.json, &active, || describe_active_app(&active))
+        }
+        Command::Media => {
+            let player = device.get_media_player().await?;
+            print(cli.json, &player, || {
+                let time = |d: Option<Duration>| d.map(|d| format!("{}:{:02}", d.as_secs() / 60, d.as_secs() % 60)).unwrap_or_else(|| String::from("-"));
+                let app = player.plugin.as_ref().map(|p| format!(" in {}", p.name)).unwrap_or_default();
+                format!("{:?} {}/{}{}", player.state, time(player.position), time(player.duration), app)
+            })
+        }
+        Command::Alias { alias } => {
+            let serial = device.get_info().await?.serial_number
+                .ok_or("device didn't report a serial number")?;
+            registry.refresh(&device).await?;
+            registry.set_alias(&serial, &alias)?;
+            registry.save().await?;
+            Ok(())
+        }
+        Command::Remote => remote::run(device).await,
+    }
+}
+
+/// Print a value as JSON, or as text
+fn print<T: Serialize>(json: bool, value: &T, text: impl FnOnce() -> String) -> CliResult<()> {
+    if json {
+        println!("{}", serde_json::to_string_pretty(value)?);
+    } else {
+        let text = text();
+        if !text.is_empty() {
+            println!("{}", text);
+        }
+    }
+    Ok(())
+}
+
+/// Where the device registry lives unless told otherwise
+fn default_registry_path() -> PathBuf {
+    let config = std::env::var_os("XDG_CONFIG_HOME").map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+        .unwrap_or_else(|| PathBuf::from("."));
+    config.join("koru").join("devices.json")
+}
+
+/// Find devices and merge them into the registry
+async fn discover(registry: &mut DeviceRegistry, timeout: Duration, scan: Option<Ipv4Net>) -> CliResult<Vec<RegisteredDevice>> {
+    let devices = match scan {
+        Some(subnet) => scan_devices(&ScanOptions::subnet(subnet)).await?,
+        None => discover_stream(DiscoveryOptions { timeout, ..DiscoveryOptions::default() }).await?.collect().await
+    };
+    let mut found = Vec::new();
+    for device in devices {
+        // Devices that answer SSDP but not device-info aren't much use
+        if let Ok(registered) = registry.refresh(&device).await {
+            found.push(registered.clone());
+        }
+    }
+    Ok(found)
+}
+
+/// Pick the device to talk to: saved alias/name/serial/IP, then a literal IP, then a name found by discovery
+async fn select_device(key: Option<&str>, registry: &mut DeviceRegistry) -> CliResult<Device> {
+    let key = match key {
+        Some(key) => key,
+        // No device given, which is fine as long as there's only one around
+        None => {
+            if registry.len() == 1 {
+                return Ok(registry.devices().next().map(|d| d.device.clone()).unwrap_or_default());
+            }
+            let mut found = discover(registry, Duration::new(3, 0), None).await?;
+            registry.save().await?;
+            return match found.len() {
+                0 => Err("no devices found, pass one with --device".into()),
+                1 => Ok(found.remove(0).device),
+                _ => Err(format!("found {} devices, pick one with --device:\n{}", found.len(),
+                                 found.iter().map(describe).collect::<Vec<_>>().join("\n")).into())
+            };
+        }
+    };
+    if let Some(registered) = registry.find(key) {
+        return Ok(registered.device.clone());
+    }
+    if let Some((ip, port)) = parse_address(key) {
+        return Ok(Device::from_ipv4(&ip.to_string(), i32::from(port)));
+    }
+    // Maybe it's the name of a device we haven't saved yet
+    let found = discover(registry, Duration::new(3, 0), None).await?;
+    registry.save().await?;
+    found.into_iter()
+        .find(|d| d.matches(key))
+        .map(|d| d.device)
+        .ok_or_else(|| format!("no device named '{}'", key).into())
+}
+
+/// Parse "192.168.1.134" or "192.168.1.134:8060"
+fn parse_address(s: &str) -> Option<(Ipv4Addr, u16)> {
+    if let Ok(address) = SocketAddrV4::from_str(s) {
+        return Some((*address.ip(), address.port()));
+    }
+    Ipv4Addr::from_str(s).ok().map(|ip| (ip, ECP_PORT as u16))
+}
+
+/// Parse a polling interval in seconds, no shorter than devices are ever polled (as the gateway's watch endpoint insists)
+fn parse_seconds(s: &str) -> std::result::Result<f64, String> {
+    let min = MIN_POLL_INTERVAL.as_secs_f64();
+    match s.parse::<f64>() {
+        Ok(seconds) if seconds >= min && seconds.is_finite() => Ok(seconds),
+        Ok(seconds) if seconds.is_finite() => Err(format!("interval must be at least {}s", min)),
+        _ => Err(format!("invalid interval '{}'", s))
+    }
+}
+
+/// Parse "living-room=1.5" into a device and polling interval
+fn parse_poll_device(s: &str) -> std::result::Result<(String, f64), String> {
+    let (device, seconds) = s.rsplit_once('=').ok_or("expected DEVICE=SECONDS")?;
+    Ok((String::from(device), parse_seconds(seconds)?))
+}
+
+/// Parse "broker.lan" or "broker.lan:1883" into a host and port
+fn parse_broker(s: &str) -> std::result::Result<(String, u16), String> {
+    match s.rsplit_once(':') {
+        Some((host, port)) => port.parse().map(|port| (String::from(host), port)).map_err(|_| format!("invalid port '{}'", port)),
+        None => Ok((String::from(s), 1883))
+    }
+}
+
+/// Use the id of an installed app with this name, or assume it's an id already
+async fn resolve_app(device: &Device, app: &str) -> String {
+    device.get_installed_apps().await.ok()
+        .and_then(|apps| apps.into_iter().find(|a| a.name.eq_ignore_ascii_case(app)))
+        .map(|a| a.id.to_string())
+        .unwrap_or_else(|| String::from(app))
+}
+
+/// One-line summary of a saved device
+fn describe(registered: &RegisteredDevice) -> String {
+    let alias = registered.alias.as_ref().map(|a| format!(" ({})", a)).unwrap_or_default();
+    format!("{}{}\t{}:{}\t{}", registered.device.name, alias, registered.device.ipv4, registered.device.port, registered.serial_number)
+}
+
+/// One-line summary of what's in the foreground
+fn describe_active_app(active: &ActiveApp) -> String {
+    let screensaver = active.screensaver().map(|s| format!(" (screensaver: {})", s.name)).unwrap_or_default();
+    match active.app() {
+        Some(app) => format!("{} ({}){}", app.name, app.id, screensaver),
+        None => format!("Home{}", screensaver)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+
+    #[test]
+    fn cli_is_well_formed() {
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn parses_subcommands() {
+        let cli = Cli::try_parse_from(["koru", "-d", "living-room", "--json", "launch", "Netflix", "--content-id", "80057281", "--media-type", "movie"]).unwrap();
+        assert_eq!(cli.device.as_deref(), Some("living-room"));
+        assert!(cli.json);
+        assert!(matches!(cli.command, Command::Launch { ref app, content_id: Some(_), media_type: Some(_) } if app == "Netflix"));
+        assert!(matches!(Cli::try_parse_from(["koru", "power", "toggle"]).unwrap().command, Command::Power { action: PowerAction::Toggle }));
+        assert!(Cli::try_parse_from(["koru", "power", "sideways"]).is_err());
+        assert!(Cli::try_parse_from(["koru", "press"]).is_err());
+    }
+
+    #[test]
+    fn parses_device_addresses() {
+        assert_eq!(parse_address("192.168.1.134"), Some((Ipv4Addr::new(192, 168, 1, 134), 8060)));
+        assert_eq!(parse_address("192.168.1.134:8061"), Some((Ipv4Addr::new(192, 168, 1, 134), 8061)));
+        assert_eq!(parse_address("living-room"), None);
+        assert_eq!(parse_poll_device("living-room=1.5"), Ok((String::from("living-room"), 1.5)));
+        assert!(parse_poll_device("living-room").is_err());
+        assert!(parse_poll_device("living-room=0").is_err());
+        assert!(parse_poll_device("living-room=0.999").is_err());
+        assert_eq!(parse_poll_device("living-room=1"), Ok((String::from("living-room"), 1.0)));
+        assert!(Cli::try_parse_from(["koru", "gateway", "--poll", "0.1"]).is_err());
+        assert_eq!(parse_broker("broker.lan"), Ok((String::from("broker.lan"), 1883)));
+        assert_eq!(parse_broker("10.0.0.2:8883"), Ok((String::from("10.0.0.2"), 8883)));
+        assert!(parse_broker("broker.lan:mqtt").is_err());
+    }
+}
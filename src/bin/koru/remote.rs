@@ -0,0 +1,253 @@
+/// Interactive terminal remote (`koru remote`)
+use std::io::{stdout, Stdout, Write};
+use std::time::Duration;
+use crossterm::event::{Event, EventStream, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use crossterm::{cursor, queue, style, terminal};
+use futures::StreamExt;
+use tokio::sync::mpsc;
+use koru::{Device, KoruError, BUTTON, POWERSTATE};
+use crate::{describe_active_app, CliResult};
+
+// How often to refresh the status line
+const STATUS_INTERVAL: Duration = Duration::from_secs(2);
+
+const HELP: &str = "arrows: move  enter: OK  esc: back  backspace  home  space: play/pause  < >: rev/fwd  \
+                    i: info  + - m: volume  t: type text  q: quit";
+
+/// Whether keys are remote buttons or text to type
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Mode {
+    Remote,
+    Typing,
+}
+
+/// What a key does
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Action {
+    Press(BUTTON),  // Press a remote button
+    Type(char),     // Send a character to the focused text field
+    Switch(Mode),   // Start or stop typing
+    Quit,
+}
+
+/// What the status line shows about the device
+#[derive(Clone, Debug, Default)]
+struct Status {
+    name: String,
+    power: POWERSTATE,
+    app: Option<String>,    // Unknown while the device is off
+}
+
+/// Raw terminal mode, restored on drop (even if we bail out early)
+struct RawMode;
+
+impl RawMode {
+    fn enable() -> CliResult<RawMode> {
+        terminal::enable_raw_mode()?;
+        Ok(RawMode)
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        let _ = terminal::disable_raw_mode();
+    }
+}
+
+/// Control a device from the keyboard until the user quits
+pub async fn run(device: Device) -> CliResult<()> {
+    // Send keypresses from a task so a slow device doesn't stall the UI; the queue keeps them in order
+    let (actions, queued) = mpsc::unbounded_channel();
+    let (errors, failures) = mpsc::unbounded_channel();
+    let sender = tokio::spawn(send_actions(device.clone(), queued, errors));
+    let (statuses, updates) = mpsc::unbounded_channel();
+    let poller = tokio::spawn(poll_status(device, statuses));
+
+    let result = interact(actions, failures, updates).await;
+    sender.abort();
+    poller.abort();
+    result
+}
+
+/// Read keys and redraw the status line
+async fn interact(actions: mpsc::UnboundedSender<Action>,
+                  mut failures: mpsc::UnboundedReceiver<String>,
+                  mut updates: mpsc::UnboundedReceiver<Status>) -> CliResult<()> {
+    let mut out = stdout();
+    writeln!(out, "{}", HELP)?;
+    let _raw = RawMode::enable()?;
+    let mut events = EventStream::new();
+    let mut mode = Mode::Remote;
+    let mut status = Status::default();
+    let mut message = String::new();
+
+    loop {
+        draw(&mut out, &status, mode, &message)?;
+        tokio::select! {
+            event = events.next() => match event {
+                Some(Ok(Event::Key(key))) => match action(mode, key) {
+                    Some(Action::Quit) => break,
+                    Some(Action::Switch(new_mode)) => {
+                        mode = new_mode;
+                        message.clear();
+                    }
+                    Some(action) => {
+                        message = match &action {
+                            Action::Press(button) => button.to_string(),
+                            Action::Type(c) => format!("typed '{}'", c),
+                            _ => String::new()
+                        };
+                        // Only fails once the sender's gone, i.e. we're shutting down anyway
+                        let _ = actions.send(action);
+                    }
+                    None => {}
+                },
+                Some(Ok(_)) => {}
+                Some(Err(e)) => return Err(e.into()),
+                None => break,
+            },
+            Some(update) = updates.recv() => status = update,
+            Some(failure) = failures.recv() => message = format!("error: {}", failure),
+        }
+    }
+    // Leave the prompt on a fresh line
+    queue!(out, style::Print("\r\n"))?;
+    out.flush()?;
+    Ok(())
+}
+
+/// Map a key to what it does in the given mode
+fn action(mode: Mode, key: KeyEvent) -> Option<Action> {
+    // Ignore key releases (only reported on some platforms)
+    if key.kind == KeyEventKind::Release {
+        return None;
+    }
+    if key.modifiers.contains(KeyModifiers::CONTROL) {
+        return match key.code {
+            KeyCode::Char('c') | KeyCode::Char('d') => Some(Action::Quit),
+            _ => None
+        };
+    }
+    let button = match (mode, key.code) {
+        (_, KeyCode::Up) => BUTTON::Up,
+        (_, KeyCode::Down) => BUTTON::Down,
+        (_, KeyCode::Left) => BUTTON::Left,
+        (_, KeyCode::Right) => BUTTON::Right,
+        (_, KeyCode::Backspace) => BUTTON::Backspace,
+        (Mode::Remote, KeyCode::Enter) => BUTTON::Select,
+        (Mode::Remote, KeyCode::Esc) => BUTTON::Back,
+        (Mode::Remote, KeyCode::Home) => BUTTON::Home,
+        (Mode::Remote, KeyCode::Char(' ')) => BUTTON::Play,
+        (Mode::Remote, KeyCode::Char('<')) | (Mode::Remote, KeyCode::Char(',')) => BUTTON::Rev,
+        (Mode::Remote, KeyCode::Char('>')) | (Mode::Remote, KeyCode::Char('.')) => BUTTON::Fwd,
+        (Mode::Remote, KeyCode::Char('i')) => BUTTON::Info,
+        (Mode::Remote, KeyCode::Char('+')) | (Mode::Remote, KeyCode::Char('=')) => BUTTON::VolumeUp,
+        (Mode::Remote, KeyCode::Char('-')) => BUTTON::VolumeDown,
+        (Mode::Remote, KeyCode::Char('m')) => BUTTON::VolumeMute,
+        (Mode::Remote, KeyCode::Char('t')) => return Some(Action::Switch(Mode::Typing)),
+        (Mode::Remote, KeyCode::Char('q')) => return Some(Action::Quit),
+        // Enter submits what's been typed, Escape goes back to button mode
+        (Mode::Typing, KeyCode::Enter) => BUTTON::Enter,
+        (Mode::Typing, KeyCode::Esc) => return Some(Action::Switch(Mode::Remote)),
+        (Mode::Typing, KeyCode::Char(c)) => return Some(Action::Type(c)),
+        _ => return None
+    };
+    Some(Action::Press(button))
+}
+
+/// Send queued keypresses in order, reporting failures
+async fn send_actions(device: Device, mut queued: mpsc::UnboundedReceiver<Action>, errors: mpsc::UnboundedSender<String>) {
+    while let Some(action) = queued.recv().await {
+        let result = match action {
+            Action::Press(button) => device.press_button(button).await,
+            Action::Type(c) => device.press_key(c).await,
+            Action::Switch(_) | Action::Quit => continue,
+        };
+        if let Err(e) = result {
+            if errors.send(e.to_string()).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// Periodically fetch the device's name, power state and active app
+async fn poll_status(device: Device, statuses: mpsc::UnboundedSender<Status>) {
+    let mut interval = tokio::time::interval(STATUS_INTERVAL);
+    let mut name = None;
+    loop {
+        interval.tick().await;
+        // device-info has the user-facing name and the power state
+        let (power, display_name) = match device.get_info().await {
+            Ok(info) => (info.power_mode.clone(), info.display_name().map(String::from)),
+            // Same as get_power_state(), w/o asking twice
+            Err(KoruError::Timeout) => (POWERSTATE::OFF, None),
+            Err(_) => (POWERSTATE::UNKNOWN, None)
+        };
+        name = display_name.or(name);
+        // Don't bother asking a device that's off what it's running
+        let app = match power {
+            POWERSTATE::ON | POWERSTATE::DISPLAYOFF => device.get_active_app().await.ok().map(|active| describe_active_app(&active)),
+            _ => None
+        };
+        let status = Status {
+            name: name.clone().unwrap_or_else(|| if device.name.is_empty() { device.ipv4.clone() } else { device.name.clone() }),
+            power,
+            app
+        };
+        if statuses.send(status).is_err() {
+            return;
+        }
+    }
+}
+
+/// Redraw the status line in place
+fn draw(out: &mut Stdout, status: &Status, mode: Mode, message: &str) -> CliResult<()> {
+    let mode = match mode {
+        Mode::Remote => "remote",
+        Mode::Typing => "typing (esc to stop)",
+    };
+    let line = if status.name.is_empty() {
+        format!("connecting... [{}] {}", mode, message)
+    } else {
+        format!("{} | {} | {} | [{}] {}", status.name, status.power, status.app.as_deref().unwrap_or("-"), mode, message)
+    };
+    // Don't wrap, it would break redrawing in place
+    let width = terminal::size().ok().map(|(columns, _)| usize::from(columns)).filter(|&columns| columns > 0).unwrap_or(80);
+    let line = line.chars().take(width.saturating_sub(1)).collect::<String>();
+    queue!(out, cursor::MoveToColumn(0), terminal::Clear(terminal::ClearType::CurrentLine), style::Print(line))?;
+    out.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(code: KeyCode) -> KeyEvent {
+        KeyEvent::new(code, KeyModifiers::NONE)
+    }
+
+    #[test]
+    fn maps_keys_to_buttons() {
+        assert_eq!(action(Mode::Remote, key(KeyCode::Up)), Some(Action::Press(BUTTON::Up)));
+        assert_eq!(action(Mode::Remote, key(KeyCode::Enter)), Some(Action::Press(BUTTON::Select)));
+        assert_eq!(action(Mode::Remote, key(KeyCode::Esc)), Some(Action::Press(BUTTON::Back)));
+        assert_eq!(action(Mode::Remote, key(KeyCode::Home)), Some(Action::Press(BUTTON::Home)));
+        assert_eq!(action(Mode::Remote, key(KeyCode::Char(' '))), Some(Action::Press(BUTTON::Play)));
+        assert_eq!(action(Mode::Remote, key(KeyCode::Char('+'))), Some(Action::Press(BUTTON::VolumeUp)));
+        assert_eq!(action(Mode::Remote, key(KeyCode::Char('x'))), None);
+        assert_eq!(action(Mode::Remote, key(KeyCode::Char('q'))), Some(Action::Quit));
+        assert_eq!(action(Mode::Remote, KeyEvent::new(KeyCode::Char('c'), KeyModifiers::CONTROL)), Some(Action::Quit));
+    }
+
+    #[test]
+    fn typing_mode_forwards_characters() {
+        assert_eq!(action(Mode::Remote, key(KeyCode::Char('t'))), Some(Action::Switch(Mode::Typing)));
+        assert_eq!(action(Mode::Typing, key(KeyCode::Char('q'))), Some(Action::Type('q')));
+        assert_eq!(action(Mode::Typing, key(KeyCode::Char(' '))), Some(Action::Type(' ')));
+        assert_eq!(action(Mode::Typing, key(KeyCode::Backspace)), Some(Action::Press(BUTTON::Backspace)));
+        assert_eq!(action(Mode::Typing, key(KeyCode::Enter)), Some(Action::Press(BUTTON::Enter)));
+        assert_eq!(action(Mode::Typing, key(KeyCode::Esc)), Some(Action::Switch(Mode::Remote)));
+    }
+}
@@ -1,75 +1,211 @@
 /// Underlying HTTP client for roku device
-use std::time::Duration;
+use std::time::{Duration, Instant};
+use tracing::{debug, info, Instrument};
 use wake_on_lan::MagicPacket;
-use warp::http::StatusCode;
-
-/// GET an endpoint on the device API
-pub async fn get(ipv4: &str, endpoint: &str, timeout: Duration) -> Result<String, StatusCode> {
-    // Create client and send request
-    let response = reqwest::Client::new()
-        .get(format!("http://{}:{}/{}", ipv4, 8060, endpoint))
-        .timeout(timeout)
-        .send()
-        .await;
-    // Handle response
-    match response {
-        // Return Ok() w/ response text
-        Ok(response) => Ok(response.text().await.unwrap_or(String::new())),
-        // Return Error status, specifically for timeouts, or default to Bad Request
-        Err(e) => {
-            if e.is_timeout() {
-                Err(StatusCode::REQUEST_TIMEOUT)
-            } else {
-                Err(e.status().unwrap_or(StatusCode::BAD_REQUEST))
-            }
+use crate::MacAddress;
+use crate::error::{KoruError, Result};
+
+/// Settings for the HTTP client a device talks through
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ClientConfig {
+    pub connect_timeout:    Duration,           // Time allowed to establish a connection
+    pub query_timeout:      Duration,           // Time allowed for queries, e.g. query/device-info
+    pub command_timeout:    Duration,           // Time allowed for commands, e.g. keypresses
+    pub wake_timeout:       Duration,           // Time to wait before assuming a device is asleep in waking_post()
+    pub keep_alive:         Option<Duration>,   // How long to keep idle connections open, or None to always reconnect
+    pub user_agent:         String,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        ClientConfig {
+            connect_timeout: Duration::new(3, 0),
+            query_timeout: Duration::new(3, 0),
+            command_timeout: Duration::new(5, 0),
+            wake_timeout: Duration::new(3, 0),
+            keep_alive: Some(Duration::new(90, 0)),
+            user_agent: format!("koru/{}", env!("CARGO_PKG_VERSION")),
         }
     }
 }
 
-/// POST to an endpoint on the device API
-pub async fn post(ipv4: &str, endpoint: &str, body: Option<String>, timeout: Duration) -> Result<String, StatusCode> {
-    // Create client and send request
-    let response = reqwest::Client::new()
-        .post(format!("http://{}:{}/{}", ipv4, 8060, endpoint))
-        .body(body.unwrap_or(String::new()))
-        .timeout(timeout)
-        .send()
-        .await;
-    // Handle response
-    match response {
-        // Return Ok() w/ response text
-        Ok(response) => Ok(response.text().await.unwrap_or(String::new())),
-        // Return Error status or default to Bad Request
-        Err(e) => Err(e.status().unwrap_or(StatusCode::BAD_REQUEST))
-    }
+/// HTTP client shared by every request to a device (cheap to clone, clones share connections)
+#[derive(Clone, Debug)]
+pub struct KoruClient {
+    http: reqwest::Client,
+    config: ClientConfig,
 }
 
-/// POST to an endpoint w/o body, waking the device and retrying on timeout
-// Note: useful for e.g. cold-launching apps since it avoids potential timeouts in checking power state
-pub async fn waking_post(ipv4: &str, mac_address: &[u8; 6], endpoint: &str, timeout: Duration) -> Result<String, StatusCode> {
-    // Create client and send request
-    let response = reqwest::Client::new()
-        .post(format!("http://{}:{}/{}", ipv4, 8060, endpoint))
-        .timeout(timeout)
-        .send()
-        .await;
-
-    match response {
-        Ok(response) => {
-            Ok(response.text().await.unwrap_or(String::new()))
+impl KoruClient {
+    /// Create a client with the given settings
+    pub fn new(config: ClientConfig) -> Result<KoruClient> {
+        let mut builder = reqwest::Client::builder()
+            .connect_timeout(config.connect_timeout)
+            .user_agent(config.user_agent.clone());
+        builder = match config.keep_alive {
+            Some(idle) => builder.pool_idle_timeout(idle).tcp_keepalive(idle),
+            None => builder.pool_max_idle_per_host(0),
+        };
+        Ok(KoruClient { http: builder.build()?, config })
+    }
+
+    /// Settings this client was created with
+    pub fn config(&self) -> &ClientConfig {
+        &self.config
+    }
+
+    /// GET an endpoint on the device API, optionally overriding the query timeout
+    pub async fn get(&self, base_url: &str, endpoint: &str, timeout: Option<Duration>) -> Result<String> {
+        // Send request
+        let request = self.http
+            .get(format!("{}/{}", base_url, endpoint))
+            .timeout(timeout.unwrap_or(self.config.query_timeout));
+        let response = send(request, "GET", base_url, endpoint).await?;
+        // Return response text, or an error for non-2xx statuses
+        handle_response(response).await
+    }
+
+    /// GET an endpoint on the device API as raw bytes, along with its Content-Type
+    pub async fn get_bytes(&self, base_url: &str, endpoint: &str, timeout: Option<Duration>) -> Result<(Vec<u8>, Option<String>)> {
+        // Send request
+        let request = self.http
+            .get(format!("{}/{}", base_url, endpoint))
+            .timeout(timeout.unwrap_or(self.config.query_timeout));
+        let response = send(request, "GET", base_url, endpoint).await?;
+        // Fail on non-2xx statuses
+        if !response.status().is_success() {
+            return Err(KoruError::Http(response.status()));
         }
-        Err(e) => {
-            if e.is_timeout() {
-                // Retry w/ regular get() if W-o-L succeeds
-                match MagicPacket::new(mac_address).send() {
-                    Ok(..) => post(ipv4, endpoint, None, timeout).await,
-                    // TODO: Think of a real code to use
-                    Err(_) => Err(StatusCode::IM_A_TEAPOT)
-                }
-            } else {
-                // Return error message
-                Err(e.status().unwrap_or(StatusCode::BAD_REQUEST))
+        // Keep the content type around before consuming the body
+        let content_type = response.headers()
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .map(String::from);
+        Ok((response.bytes().await?.to_vec(), content_type))
+    }
+
+    /// POST to an endpoint on the device API, optionally overriding the command timeout
+    pub async fn post(&self, base_url: &str, endpoint: &str, body: Option<String>, timeout: Option<Duration>) -> Result<String> {
+        // Send request
+        let request = self.http
+            .post(format!("{}/{}", base_url, endpoint))
+            .body(body.unwrap_or_default())
+            .timeout(timeout.unwrap_or(self.config.command_timeout));
+        let response = send(request, "POST", base_url, endpoint).await?;
+        // Return response text, or an error for non-2xx statuses
+        handle_response(response).await
+    }
+
+    /// POST to an endpoint w/o body, waking the device and retrying on timeout
+    // Note: useful for e.g. cold-launching apps since it avoids potential timeouts in checking power state
+    pub async fn waking_post(&self, base_url: &str, mac_address: &[u8; 6], endpoint: &str, timeout: Option<Duration>) -> Result<String> {
+        let timeout = timeout.unwrap_or(self.config.wake_timeout);
+        match self.post(base_url, endpoint, None, Some(timeout)).await {
+            // Retry w/ regular post() if W-o-L succeeds
+            Err(KoruError::Timeout) => {
+                debug!(device = base_url, endpoint, "device didn't answer, assuming it's asleep");
+                send_magic_packet(mac_address)?;
+                self.post(base_url, endpoint, None, Some(timeout)).await
             }
+            result => result
+        }
+    }
+}
+
+impl Default for KoruClient {
+    fn default() -> Self {
+        KoruClient::new(ClientConfig::default()).expect("default HTTP client settings are valid")
+    }
+}
+
+// Clients are interchangeable as long as they're configured the same
+impl PartialEq for KoruClient {
+    fn eq(&self, other: &Self) -> bool {
+        self.config == other.config
+    }
+}
+
+impl Eq for KoruClient {}
+
+/// Send a Wake-on-LAN magic packet to a MAC address
+pub(crate) fn send_magic_packet(mac_address: &[u8; 6]) -> Result<()> {
+    info!(mac = %MacAddress(*mac_address), "sending Wake-on-LAN");
+    MagicPacket::new(mac_address).send().map_err(KoruError::WakeOnLan)
+}
+
+/// Send a request, tracing where it went, how long it took and how it went
+async fn send(request: reqwest::RequestBuilder, method: &str, base_url: &str, endpoint: &str) -> Result<reqwest::Response> {
+    let span = tracing::debug_span!("ecp_request", method, device = base_url, endpoint);
+    async move {
+        let started = Instant::now();
+        let result = request.send().await;
+        let latency_ms = started.elapsed().as_millis() as u64;
+        match &result {
+            Ok(response) => debug!(latency_ms, status = response.status().as_u16(), "device responded"),
+            Err(e) => debug!(latency_ms, error = %e, "request failed"),
         }
+        Ok(result?)
+    }.instrument(span).await
+}
+
+/// Turn a response into its body text, failing on non-2xx status codes
+async fn handle_response(response: reqwest::Response) -> Result<String> {
+    let status = response.status();
+    if status.is_success() {
+        Ok(response.text().await.unwrap_or_default())
+    } else {
+        Err(KoruError::Http(status))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tracing_test::traced_test;
+    use warp::Filter;
+
+    async fn mock_device() -> String {
+        let user_agent = warp::path("user-agent")
+            .and(warp::header::<String>("user-agent"))
+            .map(|agent: String| agent);
+        let slow = warp::path("slow")
+            .then(|| async {
+                tokio::time::sleep(Duration::from_millis(500)).await;
+                "done"
+            });
+        let (address, server) = warp::serve(user_agent.or(slow))
+            .try_bind_ephemeral(([127, 0, 0, 1], 0))
+            .expect("unable to bind mock device");
+        tokio::spawn(server);
+        format!("http://{}", address)
+    }
+
+    #[tokio::test]
+    async fn sends_configured_user_agent() {
+        let base_url = mock_device().await;
+        let client = KoruClient::new(ClientConfig { user_agent: String::from("living-room-dashboard"), ..ClientConfig::default() }).unwrap();
+        assert_eq!(client.get(&base_url, "user-agent", None).await.unwrap(), "living-room-dashboard");
+        assert!(KoruClient::default().get(&base_url, "user-agent", None).await.unwrap().starts_with("koru/"));
+    }
+
+    #[tokio::test]
+    async fn timeouts_are_configurable_per_client_and_request() {
+        let base_url = mock_device().await;
+        let impatient = KoruClient::new(ClientConfig { query_timeout: Duration::from_millis(100), ..ClientConfig::default() }).unwrap();
+        assert!(matches!(impatient.get(&base_url, "slow", None).await, Err(KoruError::Timeout)));
+        // Per-request overrides win over the client's settings
+        assert_eq!(impatient.get(&base_url, "slow", Some(Duration::from_secs(2))).await.unwrap(), "done");
+        assert!(matches!(KoruClient::default().get(&base_url, "slow", Some(Duration::from_millis(100))).await, Err(KoruError::Timeout)));
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn requests_are_traced() {
+        let base_url = mock_device().await;
+        KoruClient::default().get(&base_url, "user-agent", None).await.unwrap();
+        assert!(logs_contain("ecp_request{method=\"GET\""));
+        assert!(logs_contain("endpoint=\"user-agent\""));
+        assert!(logs_contain("status=200"));
+        assert!(logs_contain("latency_ms="));
     }
 }
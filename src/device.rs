@@ -1,135 +1,70 @@
-use std::collections::HashMap;
+use crate::{ActiveApp, App, AppId, DeviceInfo, KoruClient, LaunchRequest};
+use crate::client::send_magic_packet;
+use crate::error::{KoruError, Result};
+use std::fmt;
 use std::time::Duration;
-use quick_xml::{Reader, events::Event};
-use crate::{client, App};
-use std::ops::Deref;
-use wake_on_lan::MagicPacket;
-use reqwest::StatusCode;
-use std::str::FromStr;
+use tracing::{debug, info};
+
+/// Default port for the External Control Protocol (ECP) API
+pub const ECP_PORT: i32 = 8060;
+
+// How long launch_app_by_id() waits to find out what's in the foreground; a sleeping device shouldn't delay waking it
+const ACTIVE_APP_CHECK_TIMEOUT: Duration = Duration::from_millis(500);
 
 /// Device object
 #[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Device {
     pub ipv4:       String,
     pub port:       i32,
     pub name:       String,
     pub network:    NETWORKTYPE,
+    #[cfg_attr(feature = "serde", serde(with = "crate::info::mac_octets"))]
     pub mac_wlan:   [u8; 6],
+    #[cfg_attr(feature = "serde", serde(with = "crate::info::mac_octets"))]
     pub mac_eth:    [u8; 6],
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub client:     KoruClient,     // Shared by every request to this device
 }
 
 impl Device {
 
     /// Return parsed device-info XML
-    pub async fn get_info(&self) -> Result<HashMap<String, String>, String> {
+    pub async fn get_info(&self) -> Result<DeviceInfo> {
         // GET device-info endpoint
-        match client::get(&self.ipv4,"query/device-info", Duration::new(3, 0)).await {
-            // Parse response
-            Ok(xml) => {
-                // Parsed XML keys/values
-                let mut xml_parsed: HashMap<String, String> = HashMap::new();
-                // Create XML reader
-                let mut reader = Reader::from_str(&xml);
-                reader.trim_text(true);
-                // XML event buffer
-                let mut buffer = Vec::new();
-                // Current tag
-                let mut tag = String::new();
-                // Loop the XML
-                loop {
-                    match reader.read_event(&mut buffer) {
-                        // Read each tag
-                        Ok(Event::Start(ref e)) => tag = std::str::from_utf8(e.name()).unwrap_or("").to_string(),
-                        // Handle tag content
-                        Ok(Event::Text(e)) => {
-                            // Skip working with top-level tags
-                            if tag != "?xml" && tag != "device-info" {
-                                // Create new entry in hashmap
-                                xml_parsed.insert(
-                                    tag.clone(),
-                                    e.unescape_and_decode(&reader).unwrap_or(String::new())
-                                );
-                            }
-                        },
-                        // Break at EOF
-                        Ok(Event::Eof) => break,
-                        Err(e) => panic!("Error at position {}: {:?}", reader.buffer_position(), e),
-                        _ => (),
-                    }
-                    buffer.clear();
-                }
-                // Return hashmap of xml
-                Ok(xml_parsed)
-            },
-            Err(e) => Err(e.to_string())
-        }
+        let xml = self.client.get(&self.base_url(), "query/device-info", None).await?;
+        DeviceInfo::from_xml(&xml)
     }
 
     /// Get device power state
     pub async fn get_power_state(&self) -> POWERSTATE {
-        let mut power_state = POWERSTATE::UNKNOWN;
-        match client::get(&self.ipv4, "query/device-info", Duration::new(3, 0)).await {
-            // Parse the response we received
-            Ok(response) => {
-                // Create XML reader
-                let mut reader = Reader::from_str(&response);
-                reader.trim_text(true);
-                // XML event buffer
-                let mut buffer = Vec::new();
-                // Whether or not to read the tag
-                let mut read = false;
-                // Loop the XML
-                loop {
-                    match reader.read_event(&mut buffer) {
-                        // Read each tag
-                        Ok(Event::Start(ref e)) => {
-                            if e.name() == b"power-mode" {
-                                read = true;
-                            }
-                        },
-                        // Return parsed content of the <power-mode> tag
-                        Ok(Event::Text(e)) => {
-                            if read {
-                                power_state = POWERSTATE::from(e.unescape_and_decode(&reader).unwrap().to_ascii_uppercase())
-                            }
-                            // Stop reading tags
-                            read = false;
-                        },
-                        // Break at EOF
-                        Ok(Event::Eof) => break,
-                        Err(e) => panic!("Error at position {}: {:?}", reader.buffer_position(), e),
-                        _ => (),
-                    }
-                    buffer.clear();
-                }
-            }
+        match self.get_info().await {
+            Ok(info) => info.power_mode,
             // If request timed out, assume 'Off'
+            Err(KoruError::Timeout) => {
+                debug!(device = %self.base_url(), "device-info timed out, assuming device is off");
+                POWERSTATE::OFF
+            }
             Err(e) => {
-                if e == StatusCode::REQUEST_TIMEOUT {
-                    power_state = POWERSTATE::OFF;
-                }
+                debug!(device = %self.base_url(), error = %e, "unable to get power state");
+                POWERSTATE::UNKNOWN
             }
         }
-        power_state
     }
 
     /// Change device power state and return whether or not it worked.
     /// NOTE: This will attempt to wake a device that is turned off.
-    pub async fn send_power_command(&self, command: POWERCOMMAND) -> Result<bool, String> {
+    pub async fn send_power_command(&self, command: POWERCOMMAND) -> Result<bool> {
         // Get current device state
         let current_state = self.get_power_state().await;
-        // Result of attempt to change state
-        let mut result: Result<bool, String> = Ok(true);
+        info!(device = %self.base_url(), state = %current_state, ?command, "changing power state");
         // Handle the provided command
         match command {
             POWERCOMMAND::TURNOFF => {
                 // Turn off if on
                 if current_state == POWERSTATE::ON {
                     // Send PowerOff key to device
-                    result = match client::post(&self.ipv4, "keypress/PowerOff", None, Duration::new(5, 0)).await {
-                        Ok(_) => Ok(true),
-                        Err(e) => Err(e.to_string())
-                    };
+                    self.client.post(&self.base_url(), "keypress/PowerOff", None, None).await?;
                 }
             }
             POWERCOMMAND::TOGGLE => {
@@ -137,25 +72,16 @@ impl Device {
                     // Turn off if on
                     POWERSTATE::ON => {
                         // Send PowerOff key to device
-                        result = match client::post(&self.ipv4, "keypress/PowerOff", None, Duration::new(5, 0)).await {
-                            Ok(_) => Ok(true),
-                            Err(e) => Err(e.to_string())
-                        };
+                        self.client.post(&self.base_url(), "keypress/PowerOff", None, None).await?;
                     },
                     // Turn on if off
                     POWERSTATE::DISPLAYOFF => {
                         // Send undocumented PowerOn key to device
-                        result = match client::post(&self.ipv4, "keypress/PowerOn", None, Duration::new(5, 0)).await {
-                            Ok(_) => Ok(true),
-                            Err(e) => Err(e.to_string())
-                        };
+                        self.client.post(&self.base_url(), "keypress/PowerOn", None, None).await?;
                     }
                     // Send W-o-L if powered down or unknown
                     _ => {
-                        result = match MagicPacket::new(if self.network == NETWORKTYPE::ETHERNET { &self.mac_eth } else { &self.mac_wlan }).send() {
-                            Ok(..) => Ok(true),
-                            Err(_) => Err(String::from("Unable to send Wake-on-LAN"))
-                        }
+                        send_magic_packet(self.wake_mac())?;
                     }
                 }
             }
@@ -165,17 +91,11 @@ impl Device {
                     // Turn on if off
                     POWERSTATE::DISPLAYOFF => {
                         // Send undocumented PowerOn key to device
-                        result = match client::post(&self.ipv4, "keypress/PowerOn", None, Duration::new(5, 0)).await {
-                            Ok(_) => Ok(true),
-                            Err(e) => Err(e.to_string())
-                        };
+                        self.client.post(&self.base_url(), "keypress/PowerOn", None, None).await?;
                     },
                     // Send W-o-L if powered down or unknown
                     POWERSTATE::OFF | POWERSTATE::UNKNOWN => {
-                        result = match MagicPacket::new(if self.network == NETWORKTYPE::ETHERNET { &self.mac_eth } else { &self.mac_wlan }).send() {
-                            Ok(..) => Ok(true),
-                            Err(_) => Err(String::from("Unable to send Wake-on-LAN"))
-                        }
+                        send_magic_packet(self.wake_mac())?;
                     }
                     // Do nothing if already on
                     POWERSTATE::ON => ()
@@ -183,129 +103,81 @@ impl Device {
             }
         }
 
-        result
+        Ok(true)
     }
 
     /// Get list of installed apps
-    pub async fn get_installed_apps(&self) -> Result<Vec<App>, String> {
-        // GET device-info endpoint
-        let xml_raw = client::get(&self.ipv4,"query/apps", Duration::new(3, 0)).await;
-        match xml_raw {
-            Ok(xml) => {
-                // Parsed XML keys/values
-                let mut apps_parsed: Vec<App> = Vec::new();
-                // Create XML reader
-                let mut reader = Reader::from_str(&xml);
-                reader.trim_text(true);
-                // XML event buffer
-                let mut buffer = Vec::new();
-                // Whether to read tag content
-                let mut read = false;
-                // Current roku app from tag
-                let mut app = App {
-                    id: 0,
-                    apptype: "".to_string(),
-                    version: "".to_string(),
-                    name: "".to_string(),
-                    icon: None
-                };
-                // Loop the XML
-                loop {
-                    match reader.read_event(&mut buffer) {
-                        // Read each tag
-                        Ok(Event::Start(ref e)) => {
-                            if e.name() != b"?xml" && e.name() != b"apps" {
-                                // Parse and collect attributes
-                                let attributes = e.attributes()
-                                    .map(|a| a.unwrap().value)
-                                    .collect::<Vec<_>>();
-                                // Create RokuApp object from attributes
-                                app = App {
-                                    id: i32::from_str(&std::str::from_utf8(attributes[1].deref()).unwrap_or("").to_string()).unwrap(),
-                                    apptype: std::str::from_utf8(attributes[1].deref()).unwrap_or("").to_string(),
-                                    version: std::str::from_utf8(attributes[2].deref()).unwrap_or("").to_string(),
-                                    name: String::new(),
-                                    icon: None
-                                };
-                                // Prepare to read tag content
-                                read = true;
-                            }
-                        },
-                        // Handle tag content
-                        Ok(Event::Text(e)) => {
-                            // Skip working with top-level tags
-                            if read {
-                                // Update currently-parsed app name
-                                app.name = e.unescape_and_decode(&reader)
-                                    .unwrap_or(String::new())
-                                    .replace("\u{a0}", "");     // There are newline characters in some names
-                                // Add app to list of parsed apps
-                                apps_parsed.push(app.clone());
-                            }
-                        },
-                        // Break at EOF
-                        Ok(Event::Eof) => break,
-                        Err(e) => panic!("Error at position {}: {:?}", reader.buffer_position(), e),
-                        _ => (),
-                    }
-                    buffer.clear();
-                }
-                // Return list of apps
-                Ok(apps_parsed)
-            },
-            Err(e) => Err(e.to_string())
-        }
+    pub async fn get_installed_apps(&self) -> Result<Vec<App>> {
+        // GET apps endpoint
+        let xml = self.client.get(&self.base_url(), "query/apps", None).await?;
+        App::parse_list(&xml)
+    }
+
+    /// Get the app (or home screen) currently in the foreground
+    pub async fn get_active_app(&self) -> Result<ActiveApp> {
+        let xml = self.client.get(&self.base_url(), "query/active-app", None).await?;
+        ActiveApp::from_xml(&xml)
     }
 
     /// Launch an app by its id with a waking POST (useful for cold-launching)
-    pub async fn launch_app_by_id(&self, app_id: i32) -> Result<bool, String> {
-        match client::waking_post(
-            &self.ipv4,
-            // Choose MAC based on network type
-            if self.network == NETWORKTYPE::ETHERNET {&self.mac_eth} else {&self.mac_wlan},
-            &format!("launch/{}", app_id),
-            Duration::new(3, 0)
-        ).await {
-            Ok(_) => Ok(true),
-            Err(e) => Err(e.to_string())
+    /// NOTE: Does nothing if the app is already in the foreground, as long as the device says so promptly.
+    pub async fn launch_app_by_id(&self, app_id: impl Into<AppId>) -> Result<bool> {
+        let app_id = app_id.into();
+        // Skip relaunching the foreground app (if we can't tell, e.g. the device is off, launch anyway)
+        let check = self.client.get(&self.base_url(), "query/active-app", Some(ACTIVE_APP_CHECK_TIMEOUT)).await;
+        if let Ok(active) = check.and_then(|xml| ActiveApp::from_xml(&xml)) {
+            if active.app().map(|app| app.id == app_id).unwrap_or(false) {
+                return Ok(true);
+            }
         }
+        self.launch(&LaunchRequest::new(app_id)).await
     }
 
     /// Manually update this object to match real-world device
-    pub async fn update_self(&mut self) {
+    pub async fn update_self(&mut self) -> Result<()> {
         // Attempt to get complete device info (we currently only have IP & port)
-        match self.get_info().await {
-            Ok(info) => {
-                // Update device object with new info using the hashmap
-                self.name = info.get("friendly-device-name").unwrap().clone();
-                self.network = NETWORKTYPE::from(info.get("network-type").unwrap().clone().to_ascii_uppercase());
-                self.mac_wlan = split_mac(info.get("wifi-mac").unwrap());
-                // Handle failing to resolve this from the hashmap (do devices w/o support still have it?)
-                if let Some(support) = info.get("supports-ethernet") {
-                    // Check if this device supports ethernet
-                    if support.to_ascii_uppercase().as_str() == "TRUE" {
-                        // Parse the Ethernet MAC
-                        self.mac_eth = split_mac(info.get("ethernet-mac").unwrap_or(&"0:0:0:0:0:0".to_string()))
-                    }
-                }
-            },
-            Err(_) => {
+        let info = self.get_info().await?;
+        self.update_from_info(&info);
+        Ok(())
+    }
 
+    /// Update this object with already-fetched device info
+    pub fn update_from_info(&mut self, info: &DeviceInfo) {
+        if let Some(name) = &info.friendly_device_name {
+            self.name = name.clone();
+        }
+        self.network = info.network_type.clone();
+        if let Some(mac) = info.wifi_mac {
+            self.mac_wlan = mac.octets();
+        }
+        // Only trust the Ethernet MAC if this device supports ethernet (do devices w/o support still have it?)
+        if info.supports_ethernet {
+            if let Some(mac) = info.ethernet_mac {
+                self.mac_eth = mac.octets();
             }
         }
     }
 
+    /// Base URL of this device's API, e.g. "http://192.168.1.134:8060"
+    pub fn base_url(&self) -> String {
+        format!("http://{}:{}", self.ipv4, self.port)
+    }
+
+    /// Use a different (e.g. shared or custom-configured) HTTP client
+    pub fn with_client(mut self, client: KoruClient) -> Device {
+        self.client = client;
+        self
+    }
+
+    /// MAC address to send Wake-on-LAN packets to, based on network type
+    pub(crate) fn wake_mac(&self) -> &[u8; 6] {
+        if self.network == NETWORKTYPE::ETHERNET { &self.mac_eth } else { &self.mac_wlan }
+    }
+
     /// Factory
     #[inline]
     pub fn new() -> Device {
-        Device {
-            ipv4: "".to_string(),
-            port: 0,
-            name: "".to_string(),
-            network: NETWORKTYPE::WIRELESS,
-            mac_wlan: [0; 6],
-            mac_eth: [0; 6]
-        }
+        Device::default()
     }
     /// Factory w/ only IPv4 and port
     #[inline]
@@ -316,23 +188,40 @@ impl Device {
             name: "".to_string(),
             network: NETWORKTYPE::WIRELESS,
             mac_wlan: [0; 6],
-            mac_eth: [0; 6]
+            mac_eth: [0; 6],
+            client: KoruClient::default()
+        }
+    }
+}
+
+impl Default for Device {
+    fn default() -> Self {
+        Device {
+            ipv4: "".to_string(),
+            port: ECP_PORT,
+            name: "".to_string(),
+            network: NETWORKTYPE::WIRELESS,
+            mac_wlan: [0; 6],
+            mac_eth: [0; 6],
+            client: KoruClient::default()
         }
     }
 }
 
 /// Network types a device could be connected to
-#[derive(Clone, Debug, Eq, PartialEq)]
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum NETWORKTYPE {
+    #[default]
     WIRELESS,   // e.g. Wi-Fi
     ETHERNET,   // Ethernet cable
 }
 
-impl ToString for NETWORKTYPE {
-    fn to_string(&self) -> String {
+impl fmt::Display for NETWORKTYPE {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            NETWORKTYPE::WIRELESS => String::from("WIRELESS"),
-            NETWORKTYPE::ETHERNET => String::from("ETHERNET")
+            NETWORKTYPE::WIRELESS => write!(f, "WIRELESS"),
+            NETWORKTYPE::ETHERNET => write!(f, "ETHERNET")
         }
     }
 }
@@ -348,11 +237,13 @@ impl From<String> for NETWORKTYPE {
 }
 
 /// Possible power states for a device to be in
-#[derive(Clone, Debug, Eq, PartialEq)]
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum POWERSTATE {
     OFF,        // Powered down, requires wake-on-lan
     DISPLAYOFF, // Screen off, hardware on, still accessible via API
     ON,         // Screen is on
+    #[default]
     UNKNOWN,    // ???
 }
 
@@ -367,13 +258,13 @@ impl From<String> for POWERSTATE {
     }
 }
 
-impl ToString for POWERSTATE {
-    fn to_string(&self) -> String {
+impl fmt::Display for POWERSTATE {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            POWERSTATE::OFF => String::from("Off"),
-            POWERSTATE::DISPLAYOFF => String::from("DisplayOff"),
-            POWERSTATE::ON => String::from("On"),
-            POWERSTATE::UNKNOWN => String::from("Unknown"),
+            POWERSTATE::OFF => write!(f, "Off"),
+            POWERSTATE::DISPLAYOFF => write!(f, "DisplayOff"),
+            POWERSTATE::ON => write!(f, "On"),
+            POWERSTATE::UNKNOWN => write!(f, "Unknown"),
         }
     }
 }
@@ -398,14 +289,66 @@ impl From<String> for POWERCOMMAND {
     }
 }
 
-/// Split up device MACs into byte arrays
-fn split_mac(input: &str) -> [u8; 6] {
-    let mut index = 0;
-    let mut output: [u8; 6] = [0; 6];
-    str::split(input, ':')
-        .for_each(|chunk| {
-            output[index] = u8::from_str_radix(chunk, 16).unwrap();
-            index += 1;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use warp::Filter;
+
+    #[test]
+    fn base_url_uses_port() {
+        assert_eq!(Device::new().port, ECP_PORT);
+        assert_eq!(Device::from_ipv4("192.168.1.134", 8061).base_url(), "http://192.168.1.134:8061");
+    }
+
+    #[tokio::test]
+    async fn queries_device_on_any_port() {
+        let info = warp::path!("query" / "device-info")
+            .map(|| "<device-info><friendly-device-name>Den</friendly-device-name><power-mode>PowerOn</power-mode><wifi-mac>d8:31:34:33:2d:7e</wifi-mac></device-info>");
+        let apps = warp::path!("query" / "apps")
+            .map(|| r#"<apps><app id="12" type="appl" version="4.1.218">Netflix</app></apps>"#);
+        let active = warp::path!("query" / "active-app")
+            .map(|| "<active-app><app>Roku</app></active-app>");
+        let (address, server) = warp::serve(info.or(apps).or(active))
+            .try_bind_ephemeral(([127, 0, 0, 1], 0))
+            .expect("unable to bind mock device");
+        tokio::spawn(server);
+
+        let mut device = Device::from_ipv4("127.0.0.1", i32::from(address.port()));
+        device.update_self().await.unwrap();
+        assert_eq!(device.name, "Den");
+        assert_eq!(device.mac_wlan, [0xd8, 0x31, 0x34, 0x33, 0x2d, 0x7e]);
+        assert_eq!(device.get_power_state().await, POWERSTATE::ON);
+        assert_eq!(device.get_installed_apps().await.unwrap()[0].name, "Netflix");
+        assert!(device.get_active_app().await.unwrap().is_home());
+    }
+
+    #[tokio::test]
+    async fn malformed_device_info_still_reports_power() {
+        let info = warp::path!("query" / "device-info").map(|| include_str!("../fixtures/device-info/malformed.xml"));
+        let (address, server) = warp::serve(info)
+            .try_bind_ephemeral(([127, 0, 0, 1], 0))
+            .expect("unable to bind mock device");
+        tokio::spawn(server);
+
+        let device = Device::from_ipv4("127.0.0.1", i32::from(address.port()));
+        assert_eq!(device.get_power_state().await, POWERSTATE::ON);
+    }
+
+    #[tokio::test]
+    async fn slow_active_app_check_does_not_delay_launch() {
+        let active = warp::path!("query" / "active-app").then(|| async {
+            tokio::time::sleep(Duration::from_secs(5)).await;
+            "<active-app><app>Roku</app></active-app>"
         });
-    output
-}
\ No newline at end of file
+        let launch = warp::post().and(warp::path!("launch" / String)).map(|_| "");
+        let (address, server) = warp::serve(active.or(launch))
+            .try_bind_ephemeral(([127, 0, 0, 1], 0))
+            .expect("unable to bind mock device");
+        tokio::spawn(server);
+
+        let device = Device::from_ipv4("127.0.0.1", i32::from(address.port()));
+        let started = tokio::time::Instant::now();
+        assert!(device.launch_app_by_id("12").await.unwrap());
+        assert!(started.elapsed() < Duration::from_secs(2), "launch took {:?}", started.elapsed());
+    }
+}
@@ -0,0 +1,124 @@
+/// Error types for device, app, and client operations
+use std::fmt;
+use reqwest::StatusCode;
+
+/// Result type used throughout the crate
+pub type Result<T> = std::result::Result<T, KoruError>;
+
+/// Errors that can occur while talking to a device
+#[derive(Debug)]
+pub enum KoruError {
+    Timeout,                                        // Request to the device timed out
+    ConnectionRefused,                              // Device refused the connection, e.g. ECP is disabled
+    Request(reqwest::Error),                        // Any other transport-level failure
+    Http(StatusCode),                               // Device responded with a non-2xx status
+    XmlParse { position: usize, message: String },  // Device returned XML we couldn't parse
+    Parse(String),                                  // Device returned a value we couldn't interpret
+    WakeOnLan(std::io::Error),                      // Unable to send a Wake-on-LAN magic packet
+    Unsupported(String),                            // Device doesn't support the requested feature
+    InvalidArgument(String),                        // Request can't be sent as given
+    Io(std::io::Error),                             // Socket errors, e.g. during discovery
+}
+
+impl KoruError {
+    /// Whether retrying the same request might reasonably succeed
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            KoruError::Timeout | KoruError::ConnectionRefused | KoruError::Request(_) => true,
+            KoruError::Http(status) => status.is_server_error(),
+            _ => false
+        }
+    }
+}
+
+impl fmt::Display for KoruError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KoruError::Timeout => write!(f, "request to device timed out"),
+            KoruError::ConnectionRefused => write!(f, "device refused the connection"),
+            KoruError::Request(e) => write!(f, "request to device failed: {}", e),
+            KoruError::Http(status) => write!(f, "device responded with HTTP {}", status),
+            KoruError::XmlParse { position, message } => write!(f, "unable to parse XML at position {}: {}", position, message),
+            KoruError::Parse(message) => write!(f, "unable to parse device response: {}", message),
+            KoruError::WakeOnLan(e) => write!(f, "unable to send Wake-on-LAN: {}", e),
+            KoruError::Unsupported(feature) => write!(f, "device does not support {}", feature),
+            KoruError::InvalidArgument(message) => write!(f, "invalid argument: {}", message),
+            KoruError::Io(e) => write!(f, "I/O error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for KoruError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            KoruError::Request(e) => Some(e),
+            KoruError::WakeOnLan(e) | KoruError::Io(e) => Some(e),
+            _ => None
+        }
+    }
+}
+
+impl From<reqwest::Error> for KoruError {
+    fn from(e: reqwest::Error) -> Self {
+        if e.is_timeout() {
+            KoruError::Timeout
+        } else if let Some(status) = e.status() {
+            KoruError::Http(status)
+        } else if e.is_connect() && is_connection_refused(&e) {
+            KoruError::ConnectionRefused
+        } else {
+            KoruError::Request(e)
+        }
+    }
+}
+
+impl From<std::io::Error> for KoruError {
+    fn from(e: std::io::Error) -> Self {
+        KoruError::Io(e)
+    }
+}
+
+/// Wrap an XML parsing error with the reader's current position
+pub(crate) fn xml_error<B: std::io::BufRead, E: fmt::Display>(reader: &quick_xml::Reader<B>, e: E) -> KoruError {
+    KoruError::XmlParse { position: reader.buffer_position(), message: e.to_string() }
+}
+
+/// Walk the error chain looking for an I/O "connection refused"
+fn is_connection_refused(e: &(dyn std::error::Error + 'static)) -> bool {
+    let mut source = Some(e);
+    while let Some(err) = source {
+        if let Some(io) = err.downcast_ref::<std::io::Error>() {
+            if io.kind() == std::io::ErrorKind::ConnectionRefused {
+                return true;
+            }
+        }
+        source = err.source();
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::KoruClient;
+
+    #[tokio::test]
+    async fn closed_port_is_connection_refused() {
+        // Grab a free port, then stop listening on it
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        match KoruClient::default().get(&format!("http://127.0.0.1:{}", port), "query/device-info", None).await {
+            Err(e) => {
+                assert!(matches!(e, KoruError::ConnectionRefused));
+                assert!(e.is_retryable());
+            }
+            Ok(_) => panic!("expected connection to be refused")
+        }
+    }
+
+    #[test]
+    fn http_status_retryable_only_for_server_errors() {
+        assert!(KoruError::Http(StatusCode::SERVICE_UNAVAILABLE).is_retryable());
+        assert!(!KoruError::Http(StatusCode::FORBIDDEN).is_retryable());
+        assert!(!KoruError::Unsupported(String::from("find remote")).is_retryable());
+    }
+}
@@ -0,0 +1,631 @@
+/// JSON REST API exposing devices over HTTP, so anything that speaks HTTP can control them by a stable id
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use futures::{SinkExt, StreamExt};
+use serde::Deserialize;
+use serde_json::json;
+use tokio::sync::{oneshot, RwLock};
+use tracing::{debug, info, warn};
+use warp::http::StatusCode;
+use warp::reply::Response;
+use warp::ws::{Message, WebSocket};
+use warp::{Filter, Rejection, Reply};
+use crate::{
+    discover_stream, Device, DeviceEvents, DeviceRegistry, DeviceWatcher, DiscoveryOptions, IconCache, LaunchRequest,
+    MediaType, RegisteredDevice, WatchOptions, BUTTON, POWERCOMMAND
+};
+use crate::error::{KoruError, Result};
+
+/// Body of `POST /devices/{id}/launch`
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+struct LaunchBody {
+    app_id: String,
+    #[serde(default)]
+    content_id: Option<String>,
+    #[serde(default)]
+    media_type: Option<String>,             // e.g. movie, episode, live
+    #[serde(default)]
+    params: BTreeMap<String, String>,       // Any extra launch parameters
+}
+
+/// Body of `POST /devices/{id}/power`
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+struct PowerBody {
+    command: String,    // on, off or toggle
+}
+
+/// Body of `PUT /devices/{id}/watch`, anything missing keeps its default
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+struct WatchBody {
+    interval_ms: Option<u64>,
+    active_app: Option<bool>,
+    media: Option<bool>,
+}
+
+/// Why a request failed
+#[derive(Debug)]
+enum GatewayError {
+    UnknownDevice(String),  // No device with this id, alias, name or IP address
+    NotWatched(String),     // Device isn't being polled, so there's no state to report
+    Device(KoruError),      // Talking to the device failed, or the request was invalid
+}
+
+impl GatewayError {
+    /// HTTP status to report this error with
+    fn status(&self) -> StatusCode {
+        match self {
+            GatewayError::UnknownDevice(_) | GatewayError::NotWatched(_) => StatusCode::NOT_FOUND,
+            GatewayError::Device(KoruError::InvalidArgument(_)) => StatusCode::BAD_REQUEST,
+            GatewayError::Device(KoruError::Unsupported(_)) => StatusCode::NOT_IMPLEMENTED,
+            GatewayError::Device(KoruError::Timeout) => StatusCode::GATEWAY_TIMEOUT,
+            // Pass along the device saying no, e.g. 404 for an app that isn't installed
+            GatewayError::Device(KoruError::Http(status)) if status.is_client_error() => StatusCode::from_u16(status.as_u16()).unwrap_or(StatusCode::BAD_GATEWAY),
+            GatewayError::Device(_) => StatusCode::BAD_GATEWAY,
+        }
+    }
+
+    fn message(&self) -> String {
+        match self {
+            GatewayError::UnknownDevice(id) => format!("no device '{}'", id),
+            GatewayError::NotWatched(id) => format!("device '{}' isn't being watched", id),
+            GatewayError::Device(e) => e.to_string(),
+        }
+    }
+}
+
+impl From<KoruError> for GatewayError {
+    fn from(e: KoruError) -> Self {
+        GatewayError::Device(e)
+    }
+}
+
+type Handled = std::result::Result<Response, GatewayError>;
+
+/// Devices from a registry, served as a JSON REST API. Devices are identified by serial number, but can also be
+/// addressed by alias, name or IP address. Clones share the same devices.
+#[derive(Clone, Debug)]
+pub struct Gateway {
+    registry: Arc<RwLock<DeviceRegistry>>,
+    icons: Option<IconCache>,
+    watcher: Arc<DeviceWatcher>,
+    watch_defaults: Arc<Mutex<Option<WatchOptions>>>,   // Set by watch_all(), for devices added later
+}
+
+impl Gateway {
+    /// Serve the devices in a registry; devices added later are saved to it if it was loaded from a file
+    pub fn new(registry: DeviceRegistry) -> Gateway {
+        Gateway {
+            registry: Arc::new(RwLock::new(registry)),
+            icons: None,
+            watcher: Arc::new(DeviceWatcher::new()),
+            watch_defaults: Arc::default()
+        }
+    }
+
+    /// Cache icons served by the icon proxy
+    pub fn icon_cache(mut self, cache: IconCache) -> Gateway {
+        self.icons = Some(cache);
+        self
+    }
+
+    /// Poll devices with a lower floor than usual, for mock devices on localhost
+    #[cfg(test)]
+    pub(crate) fn min_interval(mut self, min_interval: Duration) -> Gateway {
+        self.watcher = Arc::new(DeviceWatcher::with_min_interval(min_interval));
+        self
+    }
+
+    /// Add (or update) a device, returning its registry entry
+    pub async fn add_device(&self, device: &Device) -> Result<RegisteredDevice> {
+        // Don't hold the lock while waiting on the device
+        let info = device.get_info().await?;
+        let mut registry = self.registry.write().await;
+        let registered = registry.update(device, &info)?.clone();
+        if registry.path().is_some() {
+            registry.save().await?;
+        }
+        // Keep watching at the new address, or start if everything's being watched
+        let id = &registered.serial_number;
+        if let Some(options) = self.watcher.options(id).or_else(|| self.watch_defaults()) {
+            self.watcher.watch(id.clone(), registered.device.clone(), options);
+        }
+        Ok(registered)
+    }
+
+    /// Discover devices and add every one that responds
+    pub async fn discover(&self, options: DiscoveryOptions) -> Result<Vec<RegisteredDevice>> {
+        let devices = discover_stream(options).await?.collect::<Vec<_>>().await;
+        let mut added = Vec::new();
+        for device in devices {
+            match self.add_device(&device).await {
+                Ok(registered) => added.push(registered),
+                Err(e) => debug!(device = %device.base_url(), error = %e, "not adding device"),
+            }
+        }
+        Ok(added)
+    }
+
+    /// Every device being served
+    pub async fn devices(&self) -> Vec<RegisteredDevice> {
+        self.registry.read().await.devices().cloned().collect()
+    }
+
+    /// Device with the given serial number, alias, name or IP address
+    pub async fn device(&self, id: &str) -> Option<Device> {
+        self.registry.read().await.find(id).map(|registered| registered.device.clone())
+    }
+
+    /// Poller behind the event feed, e.g. to subscribe to events directly
+    pub fn watcher(&self) -> &DeviceWatcher {
+        &self.watcher
+    }
+
+    /// Poll every device (including ones added later) that doesn't have its own options yet
+    pub async fn watch_all(&self, options: WatchOptions) {
+        *self.watch_defaults.lock().unwrap_or_else(|e| e.into_inner()) = Some(options.clone());
+        for registered in self.devices().await {
+            if self.watcher.options(&registered.serial_number).is_none() {
+                self.watcher.watch(registered.serial_number, registered.device, options.clone());
+            }
+        }
+    }
+
+    /// Poll a device (by serial number, alias, name or IP address) with its own options
+    pub async fn watch_device(&self, id: &str, options: WatchOptions) -> Result<()> {
+        let registered = self.registry.read().await.find(id).cloned()
+            .ok_or_else(|| KoruError::InvalidArgument(format!("no device '{}'", id)))?;
+        self.watcher.watch(registered.serial_number, registered.device, options);
+        Ok(())
+    }
+
+    /// Options for devices that don't have their own, if everything's being watched
+    fn watch_defaults(&self) -> Option<WatchOptions> {
+        self.watch_defaults.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// The API as a warp filter, e.g. to serve alongside other routes
+    pub fn routes(&self) -> impl Filter<Extract = (impl Reply,), Error = Infallible> + Clone + Send + Sync + 'static {
+        let gateway = self.clone();
+        let gateway = warp::any().map(move || gateway.clone());
+
+        let list = warp::path!("devices").and(warp::get()).and(gateway.clone()).then(list_devices);
+        let device = warp::path!("devices" / String).and(warp::get()).and(gateway.clone()).then(get_device);
+        let info = warp::path!("devices" / String / "info").and(warp::get()).and(gateway.clone()).then(get_info);
+        let power_state = warp::path!("devices" / String / "power").and(warp::get()).and(gateway.clone()).then(get_power);
+        let power = warp::path!("devices" / String / "power").and(warp::post()).and(warp::body::json()).and(gateway.clone()).then(set_power);
+        let keypress = warp::path!("devices" / String / "keypress" / String).and(warp::post()).and(gateway.clone()).then(keypress);
+        let launch = warp::path!("devices" / String / "launch").and(warp::post()).and(warp::body::json()).and(gateway.clone()).then(launch);
+        let apps = warp::path!("devices" / String / "apps").and(warp::get()).and(gateway.clone()).then(get_apps);
+        let icon = warp::path!("devices" / String / "apps" / String / "icon").and(warp::get()).and(gateway.clone()).then(get_icon);
+        let state = warp::path!("devices" / String / "state").and(warp::get()).and(gateway.clone()).then(get_state);
+        let watch = warp::path!("devices" / String / "watch").and(warp::put()).and(warp::body::json()).and(gateway.clone()).then(watch);
+        let unwatch = warp::path!("devices" / String / "watch").and(warp::delete()).and(gateway.clone()).then(unwatch);
+        let events = warp::path!("events").and(warp::ws()).and(gateway).map(|ws: warp::ws::Ws, gateway: Gateway| {
+            // Subscribe before upgrading so nothing's missed in between
+            let events = gateway.watcher.subscribe();
+            ws.on_upgrade(move |socket| send_events(socket, events)).into_response()
+        });
+
+        list.map(respond)
+            .or(device.map(respond)).unify()
+            .or(info.map(respond)).unify()
+            .or(power_state.map(respond)).unify()
+            .or(power.map(respond)).unify()
+            .or(keypress.map(respond)).unify()
+            .or(launch.map(respond)).unify()
+            .or(apps.map(respond)).unify()
+            .or(icon.map(respond)).unify()
+            .or(state.map(respond)).unify()
+            .or(watch.map(respond)).unify()
+            .or(unwatch.map(respond)).unify()
+            .or(events).unify()
+            .recover(rejected).unify()
+            .with(warp::trace::request())
+    }
+
+    /// Serve the API on the given address (port 0 picks one)
+    pub async fn serve(&self, address: SocketAddr) -> Result<GatewayServer> {
+        let (shutdown, stop) = oneshot::channel::<()>();
+        let (address, server) = warp::serve(self.routes())
+            .try_bind_with_graceful_shutdown(address, async { stop.await.ok(); })
+            .map_err(std::io::Error::other)?;
+        info!(%address, "gateway listening");
+        tokio::spawn(server);
+        Ok(GatewayServer { address, shutdown: Some(shutdown) })
+    }
+
+    /// Look up a device from a (percent-encoded) path segment
+    async fn lookup(&self, id: &str) -> std::result::Result<Device, GatewayError> {
+        Ok(self.lookup_registered(id).await?.device)
+    }
+
+    /// Look up a device's registry entry from a (percent-encoded) path segment
+    async fn lookup_registered(&self, id: &str) -> std::result::Result<RegisteredDevice, GatewayError> {
+        let id = decode(id);
+        self.registry.read().await.find(&id).cloned().ok_or(GatewayError::UnknownDevice(id))
+    }
+}
+
+/// Running gateway; dropping this shuts it down
+#[derive(Debug)]
+pub struct GatewayServer {
+    address: SocketAddr,
+    shutdown: Option<oneshot::Sender<()>>,
+}
+
+impl GatewayServer {
+    /// Address the API is being served on
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+}
+
+impl Drop for GatewayServer {
+    fn drop(&mut self) {
+        if let Some(shutdown) = self.shutdown.take() {
+            let _ = shutdown.send(());
+        }
+    }
+}
+
+async fn list_devices(gateway: Gateway) -> Handled {
+    Ok(warp::reply::json(&gateway.devices().await).into_response())
+}
+
+async fn get_device(id: String, gateway: Gateway) -> Handled {
+    Ok(warp::reply::json(&gateway.lookup_registered(&id).await?).into_response())
+}
+
+async fn get_info(id: String, gateway: Gateway) -> Handled {
+    let device = gateway.lookup(&id).await?;
+    let info = device.get_info().await?;
+    // Keep the registry's name, MACs and last-seen time current (saved the next time a device is added)
+    let _ = gateway.registry.write().await.update(&device, &info);
+    Ok(warp::reply::json(&info).into_response())
+}
+
+async fn get_power(id: String, gateway: Gateway) -> Handled {
+    let device = gateway.lookup(&id).await?;
+    Ok(warp::reply::json(&json!({ "power": device.get_power_state().await })).into_response())
+}
+
+async fn set_power(id: String, body: PowerBody, gateway: Gateway) -> Handled {
+    let device = gateway.lookup(&id).await?;
+    // POWERCOMMAND::from() assumes "on" for anything it doesn't know, which isn't what a typo should do
+    let command = match body.command.to_ascii_lowercase().as_str() {
+        "on" => POWERCOMMAND::TURNON,
+        "off" => POWERCOMMAND::TURNOFF,
+        "toggle" => POWERCOMMAND::TOGGLE,
+        _ => return Err(KoruError::InvalidArgument(format!("unknown power command '{}'", body.command)).into())
+    };
+    device.send_power_command(command).await?;
+    Ok(no_content())
+}
+
+async fn keypress(id: String, button: String, gateway: Gateway) -> Handled {
+    let device = gateway.lookup(&id).await?;
+    let button = decode(&button).parse::<BUTTON>()?;
+    device.press_button(button).await?;
+    Ok(no_content())
+}
+
+async fn launch(id: String, body: LaunchBody, gateway: Gateway) -> Handled {
+    let device = gateway.lookup(&id).await?;
+    let mut request = LaunchRequest::new(body.app_id);
+    if let Some(content_id) = body.content_id {
+        request = request.content_id(content_id);
+    }
+    if let Some(media_type) = body.media_type {
+        request = request.media_type(MediaType::from(media_type.as_str()));
+    }
+    for (key, value) in body.params {
+        request = request.param(key, value);
+    }
+    device.launch(&request).await?;
+    Ok(no_content())
+}
+
+async fn get_apps(id: String, gateway: Gateway) -> Handled {
+    let device = gateway.lookup(&id).await?;
+    Ok(warp::reply::json(&device.get_installed_apps().await?).into_response())
+}
+
+async fn get_icon(id: String, app_id: String, gateway: Gateway) -> Handled {
+    let device = gateway.lookup(&id).await?;
+    let app_id = decode(&app_id);
+    let icon = match &gateway.icons {
+        Some(cache) => {
+            // The cache is keyed by app version, so look the app up first
+            let app = device.get_installed_apps().await?.into_iter().find(|app| app.id.as_str() == app_id);
+            match app {
+                Some(app) => match cache.get(&app).await {
+                    Some(icon) => icon,
+                    None => {
+                        let icon = device.fetch_icon(&app.id).await?;
+                        // Not being able to cache the icon is no reason to fail the request
+                        if let Err(e) = cache.put(&app, &icon).await {
+                            warn!(dir = %cache.dir.display(), app = %app.id, error = %e, "unable to cache icon");
+                        }
+                        icon
+                    }
+                },
+                None => device.fetch_icon(app_id).await?
+            }
+        }
+        None => device.fetch_icon(app_id).await?
+    };
+    Ok(warp::reply::with_header(icon.data, "content-type", icon.mime).into_response())
+}
+
+async fn get_state(id: String, gateway: Gateway) -> Handled {
+    let id = gateway.lookup_registered(&id).await?.serial_number;
+    let state = gateway.watcher.state(&id).ok_or(GatewayError::NotWatched(id))?;
+    Ok(warp::reply::json(&state).into_response())
+}
+
+async fn watch(id: String, body: WatchBody, gateway: Gateway) -> Handled {
+    let registered = gateway.lookup_registered(&id).await?;
+    let defaults = gateway.watch_defaults().unwrap_or_default();
+    let interval = body.interval_ms.map(Duration::from_millis).unwrap_or(defaults.interval);
+    // Polling any faster would flood the device with requests
+    let min_interval = gateway.watcher.min_interval();
+    if interval < min_interval {
+        return Err(KoruError::InvalidArgument(format!("interval_ms must be at least {}", min_interval.as_millis())).into());
+    }
+    let options = WatchOptions {
+        interval,
+        active_app: body.active_app.unwrap_or(defaults.active_app),
+        media: body.media.unwrap_or(defaults.media),
+    };
+    gateway.watcher.watch(registered.serial_number, registered.device, options);
+    Ok(no_content())
+}
+
+async fn unwatch(id: String, gateway: Gateway) -> Handled {
+    let id = gateway.lookup_registered(&id).await?.serial_number;
+    if gateway.watcher.unwatch(&id) { Ok(no_content()) } else { Err(GatewayError::NotWatched(id)) }
+}
+
+/// Forward events to a WebSocket client as JSON until either side goes away
+async fn send_events(socket: WebSocket, mut events: DeviceEvents) {
+    let (mut sender, mut receiver) = socket.split();
+    loop {
+        tokio::select! {
+            event = events.next() => {
+                let Some(event) = event else { break };
+                let json = serde_json::to_string(&event).unwrap_or_default();
+                if sender.send(Message::text(json)).await.is_err() {
+                    break;
+                }
+            }
+            // Clients have nothing to say, but this notices when they leave
+            message = receiver.next() => match message {
+                Some(Ok(message)) if !message.is_close() => {}
+                _ => break
+            }
+        }
+    }
+    let _ = sender.close().await;
+}
+
+/// Turn a handler's result into a response, with errors as `{"error": "..."}`
+fn respond(handled: Handled) -> Response {
+    handled.unwrap_or_else(|e| {
+        debug!(error = %e.message(), "gateway request failed");
+        error_response(e.status(), &e.message())
+    })
+}
+
+/// Report unknown routes and bad bodies as JSON too
+async fn rejected(rejection: Rejection) -> std::result::Result<Response, Infallible> {
+    Ok(if let Some(e) = rejection.find::<warp::filters::body::BodyDeserializeError>() {
+        error_response(StatusCode::BAD_REQUEST, &e.to_string())
+    } else if rejection.find::<warp::reject::MethodNotAllowed>().is_some() {
+        error_response(StatusCode::METHOD_NOT_ALLOWED, "method not allowed")
+    } else if rejection.is_not_found() {
+        error_response(StatusCode::NOT_FOUND, "not found")
+    } else {
+        error_response(StatusCode::BAD_REQUEST, &format!("{:?}", rejection))
+    })
+}
+
+fn error_response(status: StatusCode, message: &str) -> Response {
+    warp::reply::with_status(warp::reply::json(&json!({ "error": message })), status).into_response()
+}
+
+fn no_content() -> Response {
+    StatusCode::NO_CONTENT.into_response()
+}
+
+/// Decode a percent-encoded path segment, e.g. an alias or name with spaces
+fn decode(s: &str) -> String {
+    urlencoding::decode(s).map(|s| s.to_string()).unwrap_or_else(|_| String::from(s))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockDevice;
+
+    async fn gateway_for(mock: &MockDevice) -> Gateway {
+        let gateway = Gateway::new(DeviceRegistry::default());
+        gateway.add_device(&mock.device()).await.unwrap();
+        gateway
+    }
+
+    async fn request(gateway: &Gateway, method: &str, path: &str, body: Option<serde_json::Value>) -> (StatusCode, Vec<u8>) {
+        let mut request = warp::test::request().method(method).path(path);
+        if let Some(body) = body {
+            request = request.json(&body);
+        }
+        let response = request.reply(&gateway.routes()).await;
+        (response.status(), response.body().to_vec())
+    }
+
+    fn json_body(body: &[u8]) -> serde_json::Value {
+        serde_json::from_slice(body).unwrap()
+    }
+
+    async fn next_event(client: &mut warp::test::WsClient) -> serde_json::Value {
+        let message = tokio::time::timeout(Duration::from_secs(3), client.recv()).await.expect("no event").unwrap();
+        json_body(message.as_bytes())
+    }
+
+    #[tokio::test]
+    async fn lists_devices_by_stable_id() {
+        let mock = MockDevice::start().await.unwrap();
+        let gateway = gateway_for(&mock).await;
+        gateway.registry.write().await.set_alias("MOCK00000001", "living room").unwrap();
+
+        let (status, body) = request(&gateway, "GET", "/devices", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(json_body(&body)[0]["serial_number"], "MOCK00000001");
+
+        // Serial number, alias (percent-encoded) and IP address all find the same device
+        for id in ["MOCK00000001", "living%20room", "127.0.0.1"].iter() {
+            let (status, body) = request(&gateway, "GET", &format!("/devices/{}/info", id), None).await;
+            assert_eq!(status, StatusCode::OK);
+            assert_eq!(json_body(&body)["serial_number"], "MOCK00000001");
+        }
+        let (status, body) = request(&gateway, "GET", "/devices/nope/info", None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(json_body(&body)["error"], "no device 'nope'");
+    }
+
+    #[tokio::test]
+    async fn controls_devices() {
+        let mock = MockDevice::start().await.unwrap();
+        let gateway = gateway_for(&mock).await;
+        mock.clear_requests();
+
+        assert_eq!(request(&gateway, "POST", "/devices/MOCK00000001/keypress/home", None).await.0, StatusCode::NO_CONTENT);
+        assert_eq!(request(&gateway, "POST", "/devices/MOCK00000001/keypress/Sideways", None).await.0, StatusCode::BAD_REQUEST);
+        assert_eq!(mock.keypresses(), vec!["Home"]);
+
+        let launch = json!({ "app_id": "12", "content_id": "80057281", "media_type": "movie" });
+        assert_eq!(request(&gateway, "POST", "/devices/MOCK00000001/launch", Some(launch)).await.0, StatusCode::NO_CONTENT);
+        assert!(mock.requests().iter().any(|r| r.path == "launch/12" && r.query.as_deref() == Some("contentId=80057281&mediaType=movie")));
+        // The device's 404 for an app that isn't installed is passed along
+        assert_eq!(request(&gateway, "POST", "/devices/MOCK00000001/launch", Some(json!({ "app_id": "999" }))).await.0, StatusCode::NOT_FOUND);
+        assert_eq!(request(&gateway, "POST", "/devices/MOCK00000001/launch", Some(json!({ "app": "12" }))).await.0, StatusCode::BAD_REQUEST);
+
+        assert_eq!(request(&gateway, "POST", "/devices/MOCK00000001/power", Some(json!({ "command": "off" }))).await.0, StatusCode::NO_CONTENT);
+        assert_eq!(mock.power(), crate::POWERSTATE::DISPLAYOFF);
+        assert_eq!(request(&gateway, "POST", "/devices/MOCK00000001/power", Some(json!({ "command": "sideways" }))).await.0, StatusCode::BAD_REQUEST);
+        let (_, body) = request(&gateway, "GET", "/devices/MOCK00000001/power", None).await;
+        assert_eq!(json_body(&body)["power"], "DISPLAYOFF");
+    }
+
+    #[tokio::test]
+    async fn serves_apps_and_icons() {
+        let mock = MockDevice::start().await.unwrap();
+        let dir = std::env::temp_dir().join(format!("koru-gateway-icons-{}", std::process::id()));
+        let gateway = gateway_for(&mock).await.icon_cache(IconCache::new(&dir));
+
+        let (status, body) = request(&gateway, "GET", "/devices/MOCK00000001/apps", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(json_body(&body).as_array().unwrap().len(), 4);
+
+        let response = warp::test::request().path("/devices/MOCK00000001/apps/12/icon").reply(&gateway.routes()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let expected = mock.with_state(|state| state.icons[&crate::AppId::from("12")].clone());
+        assert_eq!(response.headers()["content-type"], expected.mime.as_str());
+        assert_eq!(response.body().to_vec(), expected.data);
+        // Served from the cache the second time
+        mock.clear_requests();
+        assert_eq!(request(&gateway, "GET", "/devices/MOCK00000001/apps/12/icon", None).await.0, StatusCode::OK);
+        assert!(!mock.requests().iter().any(|r| r.path.starts_with("query/icon")));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn serves_icons_the_cache_cannot_store() {
+        let mock = MockDevice::start().await.unwrap();
+        // A file where the cache directory should be, so every put fails
+        let file = std::env::temp_dir().join(format!("koru-gateway-icon-file-{}", std::process::id()));
+        std::fs::write(&file, b"not a directory").unwrap();
+        let gateway = gateway_for(&mock).await.icon_cache(IconCache::new(file.join("icons")));
+
+        let (status, body) = request(&gateway, "GET", "/devices/MOCK00000001/apps/12/icon", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, mock.with_state(|state| state.icons[&crate::AppId::from("12")].data.clone()));
+
+        std::fs::remove_file(&file).unwrap();
+    }
+
+    #[tokio::test]
+    async fn streams_events_over_websocket() {
+        let mock = MockDevice::start().await.unwrap();
+        let gateway = gateway_for(&mock).await.min_interval(Duration::from_millis(10));
+        let routes = gateway.routes();
+        let mut client = warp::test::ws().path("/events").handshake(routes).await.expect("handshake failed");
+
+        // Not watched until asked
+        assert_eq!(request(&gateway, "GET", "/devices/MOCK00000001/state", None).await.0, StatusCode::NOT_FOUND);
+        // Too fast to be polled
+        let flood = json!({ "interval_ms": 0 });
+        assert_eq!(request(&gateway, "PUT", "/devices/MOCK00000001/watch", Some(flood)).await.0, StatusCode::BAD_REQUEST);
+        assert_eq!(gateway.watcher().options("MOCK00000001"), None);
+        let options = json!({ "interval_ms": 50, "media": false });
+        assert_eq!(request(&gateway, "PUT", "/devices/MOCK00000001/watch", Some(options)).await.0, StatusCode::NO_CONTENT);
+        assert_eq!(gateway.watcher().options("MOCK00000001").unwrap().interval, Duration::from_millis(50));
+
+        let appeared = next_event(&mut client).await;
+        assert_eq!(appeared["event"], "appeared");
+        assert_eq!(appeared["id"], "MOCK00000001");
+        assert_eq!(next_event(&mut client).await["power"], "ON");
+        assert_eq!(next_event(&mut client).await["event"], "active_app_changed");
+
+        mock.set_power(crate::POWERSTATE::DISPLAYOFF);
+        let changed = next_event(&mut client).await;
+        assert_eq!(changed["event"], "power_changed");
+        assert_eq!(changed["power"], "DISPLAYOFF");
+        let (_, body) = request(&gateway, "GET", "/devices/MOCK00000001/state", None).await;
+        assert_eq!(json_body(&body)["power"], "DISPLAYOFF");
+
+        assert_eq!(request(&gateway, "DELETE", "/devices/MOCK00000001/watch", None).await.0, StatusCode::NO_CONTENT);
+        assert_eq!(request(&gateway, "DELETE", "/devices/MOCK00000001/watch", None).await.0, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn rejects_watching_more_than_once_a_second() {
+        let mock = MockDevice::start().await.unwrap();
+        let gateway = gateway_for(&mock).await;
+        let too_fast = json!({ "interval_ms": 999 });
+        assert_eq!(request(&gateway, "PUT", "/devices/MOCK00000001/watch", Some(too_fast)).await.0, StatusCode::BAD_REQUEST);
+        assert_eq!(gateway.watcher().options("MOCK00000001"), None);
+        let once_a_second = json!({ "interval_ms": 1000 });
+        assert_eq!(request(&gateway, "PUT", "/devices/MOCK00000001/watch", Some(once_a_second)).await.0, StatusCode::NO_CONTENT);
+        assert_eq!(gateway.watcher().options("MOCK00000001").unwrap().interval, Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn watches_devices_added_later() {
+        let mock = MockDevice::start().await.unwrap();
+        let gateway = Gateway::new(DeviceRegistry::default());
+        gateway.watch_all(WatchOptions { interval: Duration::from_secs(60), ..WatchOptions::default() }).await;
+        assert!(gateway.watcher().watched().is_empty());
+
+        gateway.add_device(&mock.device()).await.unwrap();
+        assert_eq!(gateway.watcher().watched(), vec![String::from("MOCK00000001")]);
+        // Per-device options stick when the device is added again, e.g. after rediscovery
+        gateway.watch_device("mock roku", WatchOptions { interval: Duration::from_secs(1), ..WatchOptions::default() }).await.unwrap();
+        gateway.add_device(&mock.device()).await.unwrap();
+        assert_eq!(gateway.watcher().options("MOCK00000001").unwrap().interval, Duration::from_secs(1));
+        assert!(gateway.watch_device("nope", WatchOptions::default()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn serves_over_http() {
+        let mock = MockDevice::start().await.unwrap();
+        let server = gateway_for(&mock).await.serve(SocketAddr::from(([127, 0, 0, 1], 0))).await.unwrap();
+        let response = reqwest::get(format!("http://{}/devices/MOCK00000001", server.address())).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(json_body(&response.bytes().await.unwrap())["device"]["name"], "Mock Roku");
+        assert_eq!(reqwest::get(format!("http://{}/nowhere", server.address())).await.unwrap().status(), StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,203 @@
+/// Fetch and cache app icons
+use std::path::{Path, PathBuf};
+use futures::stream::{self, StreamExt};
+use tracing::warn;
+use crate::{App, AppId, Device};
+use crate::error::{KoruError, Result};
+
+// Number of icons to download at once in Device::fetch_all_icons()
+const CONCURRENT_DOWNLOADS: usize = 4;
+
+/// App icon image data
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Icon {
+    pub mime: String,   // e.g. image/png, image/jpeg
+    pub data: Vec<u8>,
+}
+
+impl Icon {
+    /// Create an icon, falling back to sniffing the data when the MIME type is missing or generic
+    pub fn new(data: Vec<u8>, mime: Option<&str>) -> Icon {
+        // Drop parameters, e.g. "image/png; charset=..."
+        let mime = mime.map(|m| m.split(';').next().unwrap_or("").trim().to_ascii_lowercase());
+        let mime = match mime {
+            Some(m) if m.starts_with("image/") => m,
+            _ => String::from(sniff_mime(&data))
+        };
+        Icon { mime, data }
+    }
+
+    /// File extension for this icon's MIME type
+    pub fn extension(&self) -> &str {
+        match self.mime.as_str() {
+            "image/png" => "png",
+            "image/jpeg" | "image/jpg" => "jpg",
+            "image/gif" => "gif",
+            "image/webp" => "webp",
+            _ => "bin"
+        }
+    }
+}
+
+/// Guess an image's MIME type from its magic bytes
+fn sniff_mime(data: &[u8]) -> &'static str {
+    if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+        "image/png"
+    } else if data.starts_with(b"\xff\xd8\xff") {
+        "image/jpeg"
+    } else if data.starts_with(b"GIF8") {
+        "image/gif"
+    } else if data.len() >= 12 && &data[..4] == b"RIFF" && &data[8..12] == b"WEBP" {
+        "image/webp"
+    } else {
+        "application/octet-stream"
+    }
+}
+
+/// On-disk icon cache, keyed by app id and version
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct IconCache {
+    pub dir: PathBuf,
+}
+
+impl IconCache {
+    /// Cache icons in the given directory, which is created on the first `put`
+    pub fn new(dir: impl AsRef<Path>) -> IconCache {
+        IconCache { dir: dir.as_ref().to_path_buf() }
+    }
+
+    /// Load a cached icon for this app, if there is one
+    pub async fn get(&self, app: &App) -> Option<Icon> {
+        let stem = cache_stem(app);
+        for extension in ["png", "jpg", "gif", "webp", "bin"].iter() {
+            if let Ok(data) = tokio::fs::read(self.dir.join(format!("{}.{}", stem, extension))).await {
+                return Some(Icon::new(data, mime_for_extension(extension)));
+            }
+        }
+        None
+    }
+
+    /// Store an icon for this app
+    pub async fn put(&self, app: &App, icon: &Icon) -> Result<()> {
+        tokio::fs::create_dir_all(&self.dir).await?;
+        tokio::fs::write(self.dir.join(format!("{}.{}", cache_stem(app), icon.extension())), &icon.data).await?;
+        Ok(())
+    }
+}
+
+/// Filesystem-safe cache key for an app
+fn cache_stem(app: &App) -> String {
+    let safe = |s: &str| s.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '.' || c == '-' { c } else { '_' })
+        .collect::<String>();
+    format!("{}-{}", safe(app.id.as_str()), safe(&app.version))
+}
+
+/// MIME type matching a cache file extension
+fn mime_for_extension(extension: &str) -> Option<&'static str> {
+    match extension {
+        "png" => Some("image/png"),
+        "jpg" => Some("image/jpeg"),
+        "gif" => Some("image/gif"),
+        "webp" => Some("image/webp"),
+        _ => None
+    }
+}
+
+/// Adds app icon retrieval
+impl Device {
+    /// Fetch the icon for an app
+    pub async fn fetch_icon(&self, app_id: impl Into<AppId>) -> Result<Icon> {
+        let endpoint = format!("query/icon/{}", urlencoding::encode(app_id.into().as_str()));
+        let (data, mime) = self.client.get_bytes(&self.base_url(), &endpoint, None).await?;
+        // Devices answer unknown ids with an empty body
+        if data.is_empty() {
+            return Err(KoruError::Parse(format!("empty icon from {}", endpoint)));
+        }
+        Ok(Icon::new(data, mime.as_deref()))
+    }
+
+    /// Fetch icons for every app concurrently, checking the cache (if any) first.
+    /// Icons are filled in for every app that succeeds; the first failure is returned.
+    pub async fn fetch_all_icons(&self, apps: &mut [App], cache: Option<&IconCache>) -> Result<()> {
+        let results = stream::iter(apps.iter_mut())
+            .map(|app| async move {
+                // Use the cached icon for this version if there is one
+                if let Some(icon) = match cache { Some(cache) => cache.get(app).await, None => None } {
+                    app.icon = Some(icon);
+                    return Ok(());
+                }
+                let icon = self.fetch_icon(&app.id).await?;
+                // Not being able to cache the icon is no reason to throw it away
+                if let Some(cache) = cache {
+                    if let Err(e) = cache.put(app, &icon).await {
+                        warn!(dir = %cache.dir.display(), app = %app.id, error = %e, "unable to cache icon");
+                    }
+                }
+                app.icon = Some(icon);
+                Ok(())
+            })
+            .buffer_unordered(CONCURRENT_DOWNLOADS)
+            .collect::<Vec<Result<()>>>()
+            .await;
+        results.into_iter().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn app(id: &str, version: &str) -> App {
+        App {
+            id: AppId::from(id),
+            apptype: String::from("appl"),
+            subtype: None,
+            version: String::from(version),
+            name: String::new(),
+            icon: None
+        }
+    }
+
+    #[test]
+    fn keeps_or_sniffs_mime_type() {
+        let png = b"\x89PNG\r\n\x1a\n....".to_vec();
+        let jpeg = b"\xff\xd8\xff\xe0....".to_vec();
+        assert_eq!(Icon::new(png.clone(), Some("image/png")).extension(), "png");
+        assert_eq!(Icon::new(jpeg.clone(), Some("image/jpeg; charset=binary")).mime, "image/jpeg");
+        assert_eq!(Icon::new(png, Some("application/octet-stream")).mime, "image/png");
+        assert_eq!(Icon::new(jpeg, None).extension(), "jpg");
+        assert_eq!(Icon::new(b"nope".to_vec(), None).extension(), "bin");
+    }
+
+    #[tokio::test]
+    async fn cache_round_trips_by_id_and_version() {
+        let dir = std::env::temp_dir().join(format!("koru-icon-cache-{}", std::process::id()));
+        let cache = IconCache::new(&dir);
+        let icon = Icon::new(b"\xff\xd8\xff\xe0jpeg".to_vec(), Some("image/jpeg"));
+
+        assert_eq!(cache.get(&app("tvinput.hdmi1", "1.0.0")).await, None);
+        cache.put(&app("tvinput.hdmi1", "1.0.0"), &icon).await.unwrap();
+        assert_eq!(cache.get(&app("tvinput.hdmi1", "1.0.0")).await, Some(icon));
+        // A new version misses the cache
+        assert_eq!(cache.get(&app("tvinput.hdmi1", "1.0.1")).await, None);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn keeps_icons_the_cache_cannot_store() {
+        let mock = crate::mock::MockDevice::start().await.unwrap();
+        // A file where the cache directory should be, so every put fails
+        let file = std::env::temp_dir().join(format!("koru-icon-cache-file-{}", std::process::id()));
+        std::fs::write(&file, b"not a directory").unwrap();
+        let cache = IconCache::new(file.join("icons"));
+
+        let mut apps = mock.device().get_installed_apps().await.unwrap();
+        mock.device().fetch_all_icons(&mut apps, Some(&cache)).await.unwrap();
+        assert!(apps.iter().all(|app| app.icon.is_some()));
+
+        std::fs::remove_file(&file).unwrap();
+    }
+}
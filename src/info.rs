@@ -0,0 +1,448 @@
+/// Typed representation of the query/device-info endpoint
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+use quick_xml::{Reader, events::Event};
+use tracing::debug;
+use crate::{NETWORKTYPE, POWERSTATE};
+use crate::error::{xml_error, KoruError, Result};
+
+/// Hardware (MAC) address of a network interface
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(into = "String", try_from = "String"))]
+pub struct MacAddress(pub [u8; 6]);
+
+impl MacAddress {
+    /// Raw address bytes
+    pub fn octets(&self) -> [u8; 6] {
+        self.0
+    }
+}
+
+impl From<[u8; 6]> for MacAddress {
+    fn from(octets: [u8; 6]) -> Self {
+        MacAddress(octets)
+    }
+}
+
+impl From<MacAddress> for [u8; 6] {
+    fn from(mac: MacAddress) -> Self {
+        mac.0
+    }
+}
+
+impl From<MacAddress> for String {
+    fn from(mac: MacAddress) -> Self {
+        mac.to_string()
+    }
+}
+
+impl TryFrom<String> for MacAddress {
+    type Error = KoruError;
+
+    fn try_from(s: String) -> Result<Self> {
+        MacAddress::from_str(&s)
+    }
+}
+
+impl fmt::Display for MacAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [a, b, c, d, e, g] = self.0;
+        write!(f, "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}", a, b, c, d, e, g)
+    }
+}
+
+/// Parse colon-separated MACs, e.g. "d8:31:34:33:2d:7e"
+impl FromStr for MacAddress {
+    type Err = KoruError;
+
+    fn from_str(input: &str) -> Result<Self> {
+        let mut output: [u8; 6] = [0; 6];
+        let chunks = input.trim().split(':').collect::<Vec<_>>();
+        // Expect exactly six groups
+        if chunks.len() != output.len() {
+            return Err(KoruError::Parse(format!("invalid MAC address '{}'", input)));
+        }
+        for (byte, chunk) in output.iter_mut().zip(chunks) {
+            *byte = u8::from_str_radix(chunk, 16)
+                .map_err(|_| KoruError::Parse(format!("invalid MAC address '{}'", input)))?;
+        }
+        Ok(MacAddress(output))
+    }
+}
+
+/// (De)serialize raw MAC octets as strings, e.g. "d8:31:34:33:2d:7e"
+#[cfg(feature = "serde")]
+pub(crate) mod mac_octets {
+    use std::str::FromStr;
+    use serde::{de, Deserialize, Deserializer, Serializer};
+    use super::MacAddress;
+
+    pub fn serialize<S: Serializer>(octets: &[u8; 6], serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.collect_str(&MacAddress(*octets))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> std::result::Result<[u8; 6], D::Error> {
+        let mac = String::deserialize(deserializer)?;
+        MacAddress::from_str(&mac).map(|mac| mac.octets()).map_err(de::Error::custom)
+    }
+}
+
+/// Device info as reported by the device itself
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DeviceInfo {
+    // Identity
+    pub udn:                            Option<String>,
+    pub serial_number:                  Option<String>,
+    pub device_id:                      Option<String>,
+    pub advertising_id:                 Option<String>,
+    pub vendor_name:                    Option<String>,
+    pub model_name:                     Option<String>,
+    pub model_number:                   Option<String>,
+    pub model_region:                   Option<String>,
+    pub friendly_device_name:           Option<String>,
+    pub friendly_model_name:            Option<String>,
+    pub default_device_name:            Option<String>,
+    pub user_device_name:               Option<String>,
+    pub user_device_location:           Option<String>,
+    // Hardware
+    pub is_tv:                          bool,
+    pub is_stick:                       bool,
+    pub screen_size:                    Option<u32>,    // Inches, TVs only
+    pub ui_resolution:                  Option<String>,
+    pub secure_device:                  bool,
+    pub headphones_connected:           bool,
+    // Software
+    pub software_version:               Option<String>,
+    pub software_build:                 Option<String>,
+    pub build_number:                   Option<String>,
+    pub uptime:                         Duration,
+    pub power_mode:                     POWERSTATE,
+    pub developer_enabled:              bool,
+    pub search_enabled:                 bool,
+    pub voice_search_enabled:           bool,
+    pub notifications_enabled:          bool,
+    // Network
+    pub network_type:                   NETWORKTYPE,
+    pub network_name:                   Option<String>,
+    pub wifi_mac:                       Option<MacAddress>,
+    pub wifi_driver:                    Option<String>,
+    pub ethernet_mac:                   Option<MacAddress>,
+    pub has_wifi_extender:              bool,
+    pub has_wifi_5g_support:            bool,
+    // Locale
+    pub language:                       Option<String>,
+    pub country:                        Option<String>,
+    pub locale:                         Option<String>,
+    pub time_zone_auto:                 bool,
+    pub time_zone:                      Option<String>,
+    pub time_zone_name:                 Option<String>,
+    pub time_zone_tz:                   Option<String>,
+    pub time_zone_offset:               Option<i32>,    // Minutes from UTC
+    pub clock_format:                   Option<String>,
+    // Capabilities
+    pub supports_ethernet:              bool,
+    pub supports_suspend:               bool,
+    pub supports_find_remote:           bool,
+    pub find_remote_is_possible:        bool,
+    pub supports_audio_guide:           bool,
+    pub supports_rva:                   bool,
+    pub supports_private_listening:     bool,
+    pub supports_private_listening_dtv: bool,
+    pub supports_warm_standby:          bool,
+    pub supports_audio_settings:        bool,
+    pub supports_ecs_textedit:          bool,
+    pub supports_ecs_microphone:        bool,
+    pub supports_wake_on_wlan:          bool,
+    pub supports_airplay:               bool,
+    pub has_play_on_roku:               bool,
+    pub has_mobile_screensaver:         bool,
+    // Anything we don't (yet) know about
+    pub extra:                          HashMap<String, String>,
+}
+
+impl DeviceInfo {
+    /// Parse device-info XML
+    pub fn from_xml(xml: &str) -> Result<DeviceInfo> {
+        DeviceInfo::from_map(parse_device_info(xml)?)
+    }
+
+    /// Build from a map of device-info tag names to their content
+    pub fn from_map(mut map: HashMap<String, String>) -> Result<DeviceInfo> {
+        // Values we can't make sense of stay behind in extra rather than failing the whole lot
+        let screen_size = parsed(&mut map, "screen-size");
+        let uptime = parsed(&mut map, "uptime");
+        let wifi_mac = parsed(&mut map, "wifi-mac");
+        let ethernet_mac = parsed(&mut map, "ethernet-mac");
+        let time_zone_offset = parsed(&mut map, "time-zone-offset");
+        // Take a key out of the map, leaving only unknown keys behind
+        let mut text = |key: &str| map.remove(key).filter(|v| !v.is_empty());
+        let info = DeviceInfo {
+            udn: text("udn"),
+            serial_number: text("serial-number"),
+            device_id: text("device-id"),
+            advertising_id: text("advertising-id"),
+            vendor_name: text("vendor-name"),
+            model_name: text("model-name"),
+            model_number: text("model-number"),
+            model_region: text("model-region"),
+            friendly_device_name: text("friendly-device-name"),
+            friendly_model_name: text("friendly-model-name"),
+            default_device_name: text("default-device-name"),
+            user_device_name: text("user-device-name"),
+            user_device_location: text("user-device-location"),
+            is_tv: flag(text("is-tv")),
+            is_stick: flag(text("is-stick")),
+            screen_size,
+            ui_resolution: text("ui-resolution"),
+            secure_device: flag(text("secure-device")),
+            headphones_connected: flag(text("headphones-connected")),
+            software_version: text("software-version"),
+            software_build: text("software-build"),
+            build_number: text("build-number"),
+            uptime: Duration::from_secs(uptime.unwrap_or(0)),
+            power_mode: text("power-mode").map(POWERSTATE::from).unwrap_or(POWERSTATE::UNKNOWN),
+            developer_enabled: flag(text("developer-enabled")),
+            search_enabled: flag(text("search-enabled")),
+            voice_search_enabled: flag(text("voice-search-enabled")),
+            notifications_enabled: flag(text("notifications-enabled")),
+            network_type: text("network-type").map(NETWORKTYPE::from).unwrap_or(NETWORKTYPE::WIRELESS),
+            network_name: text("network-name"),
+            wifi_mac,
+            wifi_driver: text("wifi-driver"),
+            ethernet_mac,
+            has_wifi_extender: flag(text("has-wifi-extender")),
+            has_wifi_5g_support: flag(text("has-wifi-5G-support")),
+            language: text("language"),
+            country: text("country"),
+            locale: text("locale"),
+            time_zone_auto: flag(text("time-zone-auto")),
+            time_zone: text("time-zone"),
+            time_zone_name: text("time-zone-name"),
+            time_zone_tz: text("time-zone-tz"),
+            time_zone_offset,
+            clock_format: text("clock-format"),
+            supports_ethernet: flag(text("supports-ethernet")),
+            supports_suspend: flag(text("supports-suspend")),
+            supports_find_remote: flag(text("supports-find-remote")),
+            find_remote_is_possible: flag(text("find-remote-is-possible")),
+            supports_audio_guide: flag(text("supports-audio-guide")),
+            supports_rva: flag(text("supports-rva")),
+            supports_private_listening: flag(text("supports-private-listening")),
+            supports_private_listening_dtv: flag(text("supports-private-listening-dtv")),
+            supports_warm_standby: flag(text("supports-warm-standby")),
+            supports_audio_settings: flag(text("supports-audio-settings")),
+            supports_ecs_textedit: flag(text("supports-ecs-textedit")),
+            supports_ecs_microphone: flag(text("supports-ecs-microphone")),
+            supports_wake_on_wlan: flag(text("supports-wake-on-wlan")),
+            supports_airplay: flag(text("supports-airplay")),
+            has_play_on_roku: flag(text("has-play-on-roku")),
+            has_mobile_screensaver: flag(text("has-mobile-screensaver")),
+            extra: HashMap::new(),
+        };
+        Ok(DeviceInfo { extra: map, ..info })
+    }
+
+    /// Name to show users, preferring one they've set themselves
+    pub fn display_name(&self) -> Option<&str> {
+        self.user_device_name.as_deref()
+            .or(self.friendly_device_name.as_deref())
+            .or(self.default_device_name.as_deref())
+    }
+}
+
+/// Parse an ECP boolean (device-info tag or media-player attribute), treating anything but "true" as false
+pub(crate) fn flag(value: Option<String>) -> bool {
+    value.map(|v| v.eq_ignore_ascii_case("true")).unwrap_or(false)
+}
+
+/// Take a device-info value out of the map and parse it, putting it back if it's malformed
+fn parsed<T: FromStr>(map: &mut HashMap<String, String>, key: &str) -> Option<T> {
+    let value = map.remove(key).filter(|v| !v.is_empty())?;
+    match T::from_str(value.trim()) {
+        Ok(parsed) => Some(parsed),
+        Err(_) => {
+            debug!(key, %value, "ignoring malformed device-info value");
+            map.insert(String::from(key), value);
+            None
+        }
+    }
+}
+
+/// Parse device-info XML into a map of tag names to their content
+fn parse_device_info(xml: &str) -> Result<HashMap<String, String>> {
+    // Parsed XML keys/values
+    let mut xml_parsed: HashMap<String, String> = HashMap::new();
+    // Create XML reader
+    let mut reader = Reader::from_str(xml);
+    reader.trim_text(true);
+    // XML event buffer
+    let mut buffer = Vec::new();
+    // Current tag
+    let mut tag = String::new();
+    // Loop the XML
+    loop {
+        match reader.read_event(&mut buffer) {
+            // Read each tag
+            Ok(Event::Start(ref e)) => tag = std::str::from_utf8(e.name()).unwrap_or("").to_string(),
+            // Handle tag content, skipping top-level tags
+            Ok(Event::Text(e)) if tag != "?xml" && tag != "device-info" => {
+                // Create new entry in hashmap
+                xml_parsed.insert(
+                    tag.clone(),
+                    e.unescape_and_decode(&reader).map_err(|e| xml_error(&reader, e))?
+                );
+            },
+            // Break at EOF
+            Ok(Event::Eof) => break,
+            Err(e) => return Err(xml_error(&reader, e)),
+            _ => (),
+        }
+        buffer.clear();
+    }
+    // Return hashmap of xml
+    Ok(xml_parsed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use quickcheck::{quickcheck, TestResult};
+
+    const DEVICE_INFO: &str = r#"<?xml version="1.0" encoding="UTF-8" ?>
+<device-info>
+	<udn>29380007-0800-1025-80a4-d83154332d7e</udn>
+	<serial-number>X004000AB123</serial-number>
+	<device-id>S00820AB123</device-id>
+	<vendor-name>Roku</vendor-name>
+	<model-name>Roku Express</model-name>
+	<model-number>3900X</model-number>
+	<model-region>US</model-region>
+	<is-tv>false</is-tv>
+	<is-stick>false</is-stick>
+	<supports-ethernet>true</supports-ethernet>
+	<wifi-mac>d8:31:34:33:2d:7e</wifi-mac>
+	<ethernet-mac>d8:31:34:33:2d:7f</ethernet-mac>
+	<network-type>wifi</network-type>
+	<network-name>Home</network-name>
+	<friendly-device-name>Living Room</friendly-device-name>
+	<friendly-model-name>Roku Express</friendly-model-name>
+	<default-device-name>Roku Express - X004000AB123</default-device-name>
+	<user-device-name>Living Room</user-device-name>
+	<software-version>9.2.0</software-version>
+	<software-build>4803</software-build>
+	<time-zone-auto>true</time-zone-auto>
+	<time-zone>US/Eastern</time-zone>
+	<time-zone-offset>-300</time-zone-offset>
+	<locale>en_US</locale>
+	<uptime>46853</uptime>
+	<power-mode>DisplayOff</power-mode>
+	<supports-find-remote>false</supports-find-remote>
+	<supports-ecs-textedit>true</supports-ecs-textedit>
+	<has-wifi-5G-support>true</has-wifi-5G-support>
+	<grandcentral-version>3.1.39</grandcentral-version>
+</device-info>
+"#;
+
+    const MALFORMED: &str = include_str!("../fixtures/device-info/malformed.xml");
+
+    #[test]
+    fn parses_typed_device_info() {
+        let info = DeviceInfo::from_xml(DEVICE_INFO).unwrap();
+        assert_eq!(info.serial_number.as_deref(), Some("X004000AB123"));
+        assert_eq!(info.model_number.as_deref(), Some("3900X"));
+        assert_eq!(info.display_name(), Some("Living Room"));
+        assert_eq!(info.uptime, Duration::from_secs(46853));
+        assert_eq!(info.time_zone_offset, Some(-300));
+        assert_eq!(info.power_mode, POWERSTATE::DISPLAYOFF);
+        assert_eq!(info.network_type, NETWORKTYPE::WIRELESS);
+        assert_eq!(info.wifi_mac, Some(MacAddress([0xd8, 0x31, 0x34, 0x33, 0x2d, 0x7e])));
+        assert!(info.supports_ethernet);
+        assert!(info.supports_ecs_textedit);
+        assert!(info.has_wifi_5g_support);
+        assert!(!info.supports_find_remote);
+        assert!(!info.is_tv);
+        // Unknown keys are kept around
+        assert_eq!(info.extra.get("grandcentral-version").map(String::as_str), Some("3.1.39"));
+        assert!(!info.extra.contains_key("serial-number"));
+    }
+
+    #[test]
+    fn missing_keys_use_defaults() {
+        let info = DeviceInfo::from_xml("<device-info></device-info>").unwrap();
+        assert_eq!(info.power_mode, POWERSTATE::UNKNOWN);
+        assert_eq!(info.uptime, Duration::from_secs(0));
+        assert_eq!(info.wifi_mac, None);
+        assert_eq!(info.display_name(), None);
+    }
+
+    #[test]
+    fn malformed_values_are_kept_raw() {
+        let info = DeviceInfo::from_xml(MALFORMED).unwrap();
+        assert_eq!(info.serial_number.as_deref(), Some("X004000AB123"));
+        assert_eq!(info.power_mode, POWERSTATE::ON);
+        assert_eq!((info.wifi_mac, info.uptime, info.screen_size), (None, Duration::from_secs(0), None));
+        assert_eq!(info.ethernet_mac, Some(MacAddress([0xd8, 0x31, 0x34, 0x33, 0x2d, 0x7f])));
+        assert_eq!(info.extra.get("wifi-mac").map(String::as_str), Some("d8:31"));
+        assert_eq!(info.extra.get("uptime").map(String::as_str), Some("forever"));
+        assert_eq!(info.extra.get("screen-size").map(String::as_str), Some("55in"));
+    }
+
+    #[test]
+    fn mismatched_tags_are_xml_errors() {
+        let hostile = "<device-info><power-mode>On</network-type></device-info>";
+        assert!(matches!(DeviceInfo::from_xml(hostile), Err(KoruError::XmlParse { .. })));
+    }
+
+    #[test]
+    fn bad_entities_are_xml_errors() {
+        assert!(matches!(DeviceInfo::from_xml("<device-info><udn>&bogus;</udn></device-info>"), Err(KoruError::XmlParse { .. })));
+    }
+
+    #[test]
+    fn hostile_macs_are_errors() {
+        assert_eq!(MacAddress::from_str("d8:31:34:33:2d:7e").unwrap().octets(), [0xd8, 0x31, 0x34, 0x33, 0x2d, 0x7e]);
+        assert!(MacAddress::from_str("").is_err());
+        assert!(MacAddress::from_str("d8:31:34:33:2d").is_err());
+        assert!(MacAddress::from_str("d8:31:34:33:2d:7e:00").is_err());
+        assert!(MacAddress::from_str("d8:31:34:33:2d:zz").is_err());
+        assert!(MacAddress::from_str("d8:31:34:33:2d:7e7").is_err());
+    }
+
+    #[test]
+    fn parser_never_panics_on_arbitrary_input() {
+        fn prop(input: String) -> bool {
+            let _ = DeviceInfo::from_xml(&input);
+            let _ = MacAddress::from_str(&input);
+            true
+        }
+        quickcheck(prop as fn(String) -> bool);
+    }
+
+    #[test]
+    fn parser_never_panics_on_truncated_xml() {
+        fn prop(cut: usize) -> TestResult {
+            let cut = cut % (DEVICE_INFO.len() + 1);
+            // Only cut on character boundaries
+            if !DEVICE_INFO.is_char_boundary(cut) {
+                return TestResult::discard();
+            }
+            let _ = DeviceInfo::from_xml(&DEVICE_INFO[..cut]);
+            TestResult::passed()
+        }
+        quickcheck(prop as fn(usize) -> TestResult);
+    }
+
+    #[test]
+    fn formatted_macs_round_trip() {
+        fn prop(mac: (u8, u8, u8, u8, u8, u8)) -> bool {
+            let mac = MacAddress([mac.0, mac.1, mac.2, mac.3, mac.4, mac.5]);
+            MacAddress::from_str(&mac.to_string()).unwrap() == mac
+        }
+        quickcheck(prop as fn((u8, u8, u8, u8, u8, u8)) -> bool);
+    }
+}
@@ -0,0 +1,174 @@
+/// Deep linking into apps with launch parameters
+use std::fmt;
+use crate::{AppId, Device};
+use crate::error::Result;
+
+/// Deep link media types, as defined by the Roku deep linking spec
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MediaType {
+    Movie,
+    Episode,
+    Series,
+    Season,
+    Live,
+    ShortFormVideo,
+    TvSpecial,
+    Special,
+    Other(String),  // Anything an app defines for itself
+}
+
+impl fmt::Display for MediaType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MediaType::Movie => write!(f, "movie"),
+            MediaType::Episode => write!(f, "episode"),
+            MediaType::Series => write!(f, "series"),
+            MediaType::Season => write!(f, "season"),
+            MediaType::Live => write!(f, "live"),
+            MediaType::ShortFormVideo => write!(f, "shortFormVideo"),
+            MediaType::TvSpecial => write!(f, "tvSpecial"),
+            MediaType::Special => write!(f, "special"),
+            MediaType::Other(s) => write!(f, "{}", s),
+        }
+    }
+}
+
+impl From<&str> for MediaType {
+    fn from(s: &str) -> Self {
+        match s.to_ascii_lowercase().as_str() {
+            "movie" => MediaType::Movie,
+            "episode" => MediaType::Episode,
+            "series" => MediaType::Series,
+            "season" => MediaType::Season,
+            "live" => MediaType::Live,
+            "shortformvideo" => MediaType::ShortFormVideo,
+            "tvspecial" => MediaType::TvSpecial,
+            "special" => MediaType::Special,
+            _ => MediaType::Other(String::from(s))
+        }
+    }
+}
+
+/// Endpoints that accept launch parameters
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LaunchTarget {
+    Launch,     // launch/<id>, starts (or restarts) the app
+    Input,      // input/<id>, passes parameters to the running app
+}
+
+/// App launch w/ optional deep link parameters
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LaunchRequest {
+    pub app_id: AppId,
+    pub content_id: Option<String>,
+    pub media_type: Option<MediaType>,
+    pub params: Vec<(String, String)>,  // Any extra parameters, in order
+}
+
+impl LaunchRequest {
+    /// Launch an app without any parameters
+    pub fn new(app_id: impl Into<AppId>) -> LaunchRequest {
+        LaunchRequest {
+            app_id: app_id.into(),
+            content_id: None,
+            media_type: None,
+            params: Vec::new()
+        }
+    }
+
+    /// Set the content to deep link to
+    pub fn content_id(mut self, content_id: impl Into<String>) -> LaunchRequest {
+        self.content_id = Some(content_id.into());
+        self
+    }
+
+    /// Set the type of the content being deep linked to
+    pub fn media_type(mut self, media_type: MediaType) -> LaunchRequest {
+        self.media_type = Some(media_type);
+        self
+    }
+
+    /// Add an extra parameter
+    pub fn param(mut self, key: impl Into<String>, value: impl Into<String>) -> LaunchRequest {
+        self.params.push((key.into(), value.into()));
+        self
+    }
+
+    /// Whether there are any parameters at all
+    pub fn has_params(&self) -> bool {
+        self.content_id.is_some() || self.media_type.is_some() || !self.params.is_empty()
+    }
+
+    /// URL-encoded endpoint for this request, e.g. "launch/12?contentId=80057281&mediaType=movie"
+    pub fn endpoint(&self, target: LaunchTarget) -> String {
+        let path = match target {
+            LaunchTarget::Launch => "launch",
+            LaunchTarget::Input => "input",
+        };
+        let mut query = Vec::new();
+        if let Some(content_id) = &self.content_id {
+            query.push(format!("contentId={}", urlencoding::encode(content_id)));
+        }
+        if let Some(media_type) = &self.media_type {
+            query.push(format!("mediaType={}", urlencoding::encode(&media_type.to_string())));
+        }
+        for (key, value) in self.params.iter() {
+            query.push(format!("{}={}", urlencoding::encode(key), urlencoding::encode(value)));
+        }
+        let endpoint = format!("{}/{}", path, urlencoding::encode(self.app_id.as_str()));
+        if query.is_empty() { endpoint } else { format!("{}?{}", endpoint, query.join("&")) }
+    }
+}
+
+/// Adds deep linking
+impl Device {
+    /// Launch an app with a waking POST, passing along any deep link parameters
+    pub async fn launch(&self, request: &LaunchRequest) -> Result<bool> {
+        self.client.waking_post(&self.base_url(), self.wake_mac(), &request.endpoint(LaunchTarget::Launch), None).await?;
+        Ok(true)
+    }
+
+    /// Send deep link parameters to an app that's already running
+    pub async fn send_input(&self, request: &LaunchRequest) -> Result<bool> {
+        self.client.post(&self.base_url(), &request.endpoint(LaunchTarget::Input), None, None).await?;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bare_launch_has_no_query() {
+        assert_eq!(LaunchRequest::new(12).endpoint(LaunchTarget::Launch), "launch/12");
+        assert!(!LaunchRequest::new("dev").has_params());
+    }
+
+    #[test]
+    fn deep_link_parameters_are_encoded() {
+        let request = LaunchRequest::new(12)
+            .content_id("80057281")
+            .media_type(MediaType::Movie);
+        assert_eq!(request.endpoint(LaunchTarget::Launch), "launch/12?contentId=80057281&mediaType=movie");
+        assert_eq!(request.endpoint(LaunchTarget::Input), "input/12?contentId=80057281&mediaType=movie");
+
+        let request = LaunchRequest::new("dev")
+            .content_id("s01e02 & more/stuff")
+            .media_type(MediaType::ShortFormVideo)
+            .param("start time", "90=1:30");
+        assert_eq!(
+            request.endpoint(LaunchTarget::Launch),
+            "launch/dev?contentId=s01e02%20%26%20more%2Fstuff&mediaType=shortFormVideo&start%20time=90%3D1%3A30"
+        );
+    }
+
+    #[test]
+    fn media_types_round_trip() {
+        for media_type in [MediaType::Movie, MediaType::Episode, MediaType::Series, MediaType::Season, MediaType::Live,
+                           MediaType::ShortFormVideo, MediaType::TvSpecial, MediaType::Special].iter() {
+            assert_eq!(&MediaType::from(media_type.to_string().as_str()), media_type);
+        }
+        assert_eq!(MediaType::from("podcast"), MediaType::Other(String::from("podcast")));
+    }
+}
@@ -1,15 +1,45 @@
 
 mod app;
+mod icon;
 mod remote;
 mod device;
+mod info;
+mod launch;
+mod media;
+mod search;
 mod client;
 mod ssdp;
+mod presence;
+mod scan;
+mod watch;
+#[cfg(feature = "serde")]
+mod registry;
+#[cfg(feature = "gateway")]
+pub mod gateway;
+#[cfg(feature = "mqtt")]
+pub mod mqtt;
+mod error;
+#[cfg(any(test, feature = "mock"))]
+pub mod mock;
 
 // Re-export higher-level stuff
 pub use crate::app::*;
+pub use crate::icon::*;
 pub use crate::remote::*;
 pub use crate::device::*;
-pub use crate::ssdp::discover_devices;
+pub use crate::info::*;
+pub use crate::launch::*;
+pub use crate::media::*;
+pub use crate::search::*;
+pub use crate::presence::{PresenceEvent, PresenceMonitor, PresenceOptions};
+#[cfg(feature = "serde")]
+pub use crate::registry::{DeviceRegistry, RegisteredDevice};
+pub use crate::scan::{scan_devices, ScanOptions};
+pub use crate::watch::{DeviceEvent, DeviceEvents, DeviceState, DeviceWatcher, WatchOptions, MIN_POLL_INTERVAL};
+pub use crate::ssdp::{discover_devices, discover_stream, DiscoveryOptions, DiscoveryStream};
+pub use crate::error::{KoruError, Result};
+pub use crate::client::{ClientConfig, KoruClient};
+pub use ipnet::Ipv4Net;
 
 #[cfg(test)]
 mod tests {
@@ -17,6 +47,7 @@ mod tests {
     use std::time::Duration;
 
     #[tokio::test]
+    #[ignore = "needs a Roku on the LAN"]
     async fn try_discover_devices() {
         println!("[-] Attempting device discovery...");
         match discover_devices(Duration::new(5, 0)).await {
@@ -27,23 +58,33 @@ mod tests {
                 println!("------------------------------");
                 assert_ne!(devices.len(), 0)
             }
-            Err(_) => assert!(false)
+            Err(e) => panic!("{}", e)
         }
     }
 
     #[tokio::test]
+    #[ignore = "needs a Roku on the LAN"]
     async fn power_on_device() {
         // This tries to power on the first discovered device
         match discover_devices(Duration::new(5, 0)).await {
             Ok(devices) => {
                 match devices[0].send_power_command(POWERCOMMAND::TURNON).await {
                     Ok(response) => assert!(response),
-                    _ => assert!(false)
+                    Err(e) => panic!("{}", e)
                 }
             }
-            Err(_) => assert!(false)
+            Err(e) => panic!("{}", e)
         }
 
     }
-}
 
+    #[tokio::test]
+    async fn power_on_mock_device() {
+        // Same as above, against a mock device with its screen off
+        let mock = mock::MockDevice::start().await.unwrap();
+        mock.set_power(POWERSTATE::DISPLAYOFF);
+        assert!(mock.device().send_power_command(POWERCOMMAND::TURNON).await.unwrap());
+        assert_eq!(mock.keypresses(), vec!["PowerOn"]);
+        assert_eq!(mock.power(), POWERSTATE::ON);
+    }
+}
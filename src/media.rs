@@ -0,0 +1,283 @@
+/// Query media playback state
+use std::fmt;
+use std::time::Duration;
+use quick_xml::{Reader, events::{BytesStart, Event}};
+use crate::{AppId, Device};
+use crate::error::{xml_error, KoruError, Result};
+use crate::info::flag;
+
+/// Media player states
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum PlayerState {
+    #[default]
+    None,               // Nothing has been played
+    Startup,            // Player is starting up
+    Buffering,          // Waiting on the stream
+    Play,
+    Pause,
+    Stop,
+    Close,              // Player has been closed
+    Unknown(String),    // Anything newer firmware reports
+}
+
+impl From<&str> for PlayerState {
+    fn from(s: &str) -> Self {
+        match s.to_ascii_lowercase().as_str() {
+            "none" | "" => PlayerState::None,
+            "startup" => PlayerState::Startup,
+            "buffer" | "buffering" => PlayerState::Buffering,
+            "play" => PlayerState::Play,
+            "pause" => PlayerState::Pause,
+            "stop" => PlayerState::Stop,
+            "close" => PlayerState::Close,
+            _ => PlayerState::Unknown(String::from(s))
+        }
+    }
+}
+
+impl fmt::Display for PlayerState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PlayerState::None => write!(f, "none"),
+            PlayerState::Startup => write!(f, "startup"),
+            PlayerState::Buffering => write!(f, "buffer"),
+            PlayerState::Play => write!(f, "play"),
+            PlayerState::Pause => write!(f, "pause"),
+            PlayerState::Stop => write!(f, "stop"),
+            PlayerState::Close => write!(f, "close"),
+            PlayerState::Unknown(s) => write!(f, "{}", s),
+        }
+    }
+}
+
+/// App (plugin) that owns the media player
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MediaPlugin {
+    pub id: AppId,
+    pub name: String,
+    pub bandwidth: Option<String>,  // e.g. "10372550 bps"
+}
+
+/// Format of the current stream
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MediaFormat {
+    pub audio: Option<String>,      // e.g. aac, eac3
+    pub video: Option<String>,      // e.g. hevc, mpeg4_10b
+    pub captions: Option<String>,
+    pub drm: Option<String>,
+    pub video_res: Option<String>,  // e.g. 1920x1080
+}
+
+/// Buffer fill levels
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Buffering {
+    pub current: u32,
+    pub max: u32,
+    pub target: u32,
+}
+
+impl Buffering {
+    /// Buffer fill level from 0.0 to 1.0
+    pub fn progress(&self) -> f32 {
+        if self.max == 0 { 0.0 } else { (self.current as f32 / self.max as f32).min(1.0) }
+    }
+}
+
+/// Media player state, as reported by query/media-player
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MediaPlayerState {
+    pub state: PlayerState,
+    pub error: bool,
+    pub plugin: Option<MediaPlugin>,
+    pub format: Option<MediaFormat>,
+    pub buffering: Option<Buffering>,
+    pub position: Option<Duration>,
+    pub duration: Option<Duration>,
+    pub is_live: bool,
+    pub runtime: Option<Duration>,
+}
+
+impl MediaPlayerState {
+    /// Whether something is playing (or about to)
+    pub fn is_active(&self) -> bool {
+        matches!(self.state, PlayerState::Play | PlayerState::Buffering | PlayerState::Startup)
+    }
+
+    /// Parse media-player XML
+    pub fn from_xml(xml: &str) -> Result<MediaPlayerState> {
+        let mut player = MediaPlayerState::default();
+        // Create XML reader
+        let mut reader = Reader::from_str(xml);
+        reader.trim_text(true);
+        // XML event buffer
+        let mut buffer = Vec::new();
+        // Current tag
+        let mut tag = Vec::new();
+        // Loop the XML
+        loop {
+            match reader.read_event(&mut buffer) {
+                // Read each tag's attributes
+                Ok(Event::Start(ref e)) | Ok(Event::Empty(ref e)) => {
+                    tag = e.name().to_vec();
+                    let attribute = |name: &[u8]| attribute(&reader, e, name);
+                    match e.name() {
+                        b"player" => {
+                            player.state = PlayerState::from(attribute(b"state")?.unwrap_or_default().as_str());
+                            player.error = flag(attribute(b"error")?);
+                        },
+                        b"plugin" => player.plugin = Some(MediaPlugin {
+                            id: AppId::from(attribute(b"id")?.unwrap_or_default()),
+                            name: attribute(b"name")?.unwrap_or_default(),
+                            bandwidth: attribute(b"bandwidth")?
+                        }),
+                        b"format" => player.format = Some(MediaFormat {
+                            audio: attribute(b"audio")?,
+                            video: attribute(b"video")?,
+                            captions: attribute(b"captions")?,
+                            drm: attribute(b"drm")?,
+                            video_res: attribute(b"video_res")?
+                        }),
+                        b"buffering" => player.buffering = Some(Buffering {
+                            current: number(b"current", attribute(b"current")?)?,
+                            max: number(b"max", attribute(b"max")?)?,
+                            target: number(b"target", attribute(b"target")?)?
+                        }),
+                        _ => ()
+                    }
+                },
+                // Handle tag content
+                Ok(Event::Text(e)) => {
+                    let text = e.unescape_and_decode(&reader).map_err(|e| xml_error(&reader, e))?;
+                    match tag.as_slice() {
+                        b"position" => player.position = Some(millis(&text)?),
+                        b"duration" => player.duration = Some(millis(&text)?),
+                        b"runtime" => player.runtime = Some(millis(&text)?),
+                        b"is_live" => player.is_live = flag(Some(text)),
+                        _ => ()
+                    }
+                },
+                Ok(Event::End(_)) => tag.clear(),
+                // Break at EOF
+                Ok(Event::Eof) => break,
+                Err(e) => return Err(xml_error(&reader, e)),
+                _ => (),
+            }
+            buffer.clear();
+        }
+        Ok(player)
+    }
+}
+
+/// Look up an attribute on a tag by name
+fn attribute(reader: &Reader<&[u8]>, tag: &BytesStart, name: &[u8]) -> Result<Option<String>> {
+    for attribute in tag.attributes() {
+        let attribute = attribute.map_err(|e| xml_error(reader, e))?;
+        if attribute.key == name {
+            return attribute.unescape_and_decode_value(reader).map(Some).map_err(|e| xml_error(reader, e));
+        }
+    }
+    Ok(None)
+}
+
+/// Parse a numeric attribute, defaulting to zero when missing
+fn number(name: &[u8], value: Option<String>) -> Result<u32> {
+    match value {
+        Some(v) => v.trim().parse().map_err(|_| KoruError::Parse(format!("invalid {} '{}'", String::from_utf8_lossy(name), v))),
+        None => Ok(0)
+    }
+}
+
+/// Parse durations like "12345 ms"
+fn millis(text: &str) -> Result<Duration> {
+    text.trim().trim_end_matches("ms").trim().parse::<u64>()
+        .map(Duration::from_millis)
+        .map_err(|_| KoruError::Parse(format!("invalid duration '{}'", text)))
+}
+
+/// Adds media player queries
+impl Device {
+    /// Get the state of the media player
+    pub async fn get_media_player(&self) -> Result<MediaPlayerState> {
+        let xml = self.client.get(&self.base_url(), "query/media-player", None).await?;
+        MediaPlayerState::from_xml(&xml)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use quickcheck::{quickcheck, TestResult};
+
+    const PLAYING: &str = include_str!("../fixtures/media-player/playing.xml");
+    const BUFFERING: &str = include_str!("../fixtures/media-player/buffering.xml");
+    const IDLE: &str = include_str!("../fixtures/media-player/idle.xml");
+
+    #[test]
+    fn parses_playing() {
+        let player = MediaPlayerState::from_xml(PLAYING).unwrap();
+        assert_eq!(player.state, PlayerState::Play);
+        assert!(player.is_active());
+        assert!(!player.error);
+        let plugin = player.plugin.unwrap();
+        assert_eq!(plugin.id.as_str(), "12");
+        assert_eq!(plugin.name, "Netflix");
+        assert_eq!(player.format.unwrap().video_res.as_deref(), Some("3840x2160"));
+        assert_eq!(player.position, Some(Duration::from_millis(2402853)));
+        assert_eq!(player.duration, Some(Duration::from_millis(3139000)));
+        assert_eq!(player.runtime, Some(Duration::from_millis(2402853)));
+        assert!(!player.is_live);
+    }
+
+    #[test]
+    fn parses_buffering() {
+        let player = MediaPlayerState::from_xml(BUFFERING).unwrap();
+        assert_eq!(player.state, PlayerState::Buffering);
+        assert!((player.buffering.unwrap().progress() - 0.35).abs() < f32::EPSILON);
+        assert_eq!(player.duration, None);
+        assert!(player.is_live);
+    }
+
+    #[test]
+    fn parses_idle() {
+        let player = MediaPlayerState::from_xml(IDLE).unwrap();
+        assert_eq!(player.state, PlayerState::Close);
+        assert!(!player.is_active());
+        assert_eq!(player.plugin, None);
+    }
+
+    #[test]
+    fn hostile_values_are_errors() {
+        assert!(matches!(MediaPlayerState::from_xml("<player state=\"play\"><position>soon</position></player>"), Err(KoruError::Parse(_))));
+        assert!(matches!(MediaPlayerState::from_xml("<player><buffering current=\"-1\"/></player>"), Err(KoruError::Parse(_))));
+        assert!(matches!(MediaPlayerState::from_xml("<player></plugin>"), Err(KoruError::XmlParse { .. })));
+        assert_eq!(MediaPlayerState::from_xml("<player state=\"rewind\"/>").unwrap().state, PlayerState::Unknown(String::from("rewind")));
+    }
+
+    #[test]
+    fn parser_never_panics_on_arbitrary_input() {
+        fn prop(input: String) -> bool {
+            let _ = MediaPlayerState::from_xml(&input);
+            true
+        }
+        quickcheck(prop as fn(String) -> bool);
+    }
+
+    #[test]
+    fn parser_never_panics_on_truncated_xml() {
+        fn prop(cut: usize) -> TestResult {
+            let cut = cut % (PLAYING.len() + 1);
+            // Only cut on character boundaries
+            if !PLAYING.is_char_boundary(cut) {
+                return TestResult::discard();
+            }
+            let _ = MediaPlayerState::from_xml(&PLAYING[..cut]);
+            TestResult::passed()
+        }
+        quickcheck(prop as fn(usize) -> TestResult);
+    }
+}
@@ -0,0 +1,591 @@
+/// Fake Roku devices serving the ECP API and answering SSDP searches on localhost, for testing without hardware
+use std::collections::{BTreeMap, HashMap};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::sync::oneshot;
+use warp::Filter;
+use warp::http::{Method, Response, StatusCode};
+use crate::{ActiveApp, App, AppId, Device, Icon, MediaPlayerState, POWERSTATE};
+
+#[cfg(feature = "mqtt")]
+mod broker;
+#[cfg(feature = "mqtt")]
+pub use broker::MockBroker;
+
+// How long an "off" device leaves requests hanging (i.e. longer than any sensible client timeout)
+const OFF_DELAY: Duration = Duration::from_secs(3600);
+
+/// A request the mock device received
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MockRequest {
+    pub method: String,         // e.g. GET, POST
+    pub path: String,           // e.g. keypress/Home, w/o leading slash
+    pub query: Option<String>,  // Raw query string, if any
+}
+
+/// Everything the mock device reports and has received
+#[derive(Clone, Debug)]
+pub struct MockState {
+    pub device_info: BTreeMap<String, String>,  // device-info tags, except power-mode which comes from `power`
+    pub power: POWERSTATE,                      // OFF leaves every request hanging, like a device that's unplugged
+    pub apps: Vec<App>,
+    pub active_app: ActiveApp,
+    pub media_player: MediaPlayerState,
+    pub icons: HashMap<AppId, Icon>,
+    pub delay: Option<Duration>,                // Extra latency added to every response
+    pub requests: Vec<MockRequest>,
+}
+
+impl Default for MockState {
+    fn default() -> Self {
+        let device_info = [
+            ("udn", "29380007-0800-1025-80a4-0000000000001"),
+            ("serial-number", "MOCK00000001"),
+            ("device-id", "MOCK0000001"),
+            ("vendor-name", "Roku"),
+            ("model-name", "Roku Express"),
+            ("model-number", "3900X"),
+            ("is-tv", "false"),
+            ("is-stick", "false"),
+            ("supports-ethernet", "true"),
+            ("wifi-mac", "02:00:00:00:00:01"),
+            ("ethernet-mac", "02:00:00:00:00:02"),
+            ("network-type", "wifi"),
+            ("friendly-device-name", "Mock Roku"),
+            ("software-version", "9.2.0"),
+            ("software-build", "4803"),
+            ("uptime", "3600"),
+            ("supports-find-remote", "true"),
+        ].iter().map(|(k, v)| (String::from(*k), String::from(*v))).collect();
+        let apps = vec![
+            mock_app("12", "appl", "4.2.81179053", "Netflix"),
+            mock_app("2285", "appl", "6.37.1", "Hulu"),
+            mock_app("837", "appl", "2.21.90000000", "YouTube"),
+            mock_app("tvinput.hdmi1", "tvin", "1.0.0", "HDMI 1"),
+        ];
+        // Every app gets a tiny PNG icon
+        let icons = apps.iter()
+            .map(|app| (app.id.clone(), Icon::new(b"\x89PNG\r\n\x1a\nmock".to_vec(), Some("image/png"))))
+            .collect();
+        MockState {
+            device_info,
+            power: POWERSTATE::ON,
+            apps,
+            active_app: ActiveApp::Home { screensaver: None },
+            media_player: MediaPlayerState::default(),
+            icons,
+            delay: None,
+            requests: Vec::new()
+        }
+    }
+}
+
+/// Fake device listening on an ephemeral localhost port; shuts down when dropped
+#[derive(Debug)]
+pub struct MockDevice {
+    address: SocketAddr,
+    state: Arc<Mutex<MockState>>,
+    shutdown: Option<oneshot::Sender<()>>,
+}
+
+impl MockDevice {
+    /// Start a mock device with default state
+    pub async fn start() -> std::io::Result<MockDevice> {
+        MockDevice::start_with(MockState::default()).await
+    }
+
+    /// Start a mock device with the given state
+    pub async fn start_with(state: MockState) -> std::io::Result<MockDevice> {
+        let state = Arc::new(Mutex::new(state));
+        let shared = state.clone();
+        let routes = warp::method()
+            .and(warp::path::full())
+            .and(warp::query::raw().map(Some).or(warp::any().map(|| None)).unify())
+            .and_then(move |method: Method, path: warp::path::FullPath, query: Option<String>| {
+                handle(shared.clone(), method, path.as_str().trim_start_matches('/').to_string(), query)
+            });
+        let (shutdown, stop) = oneshot::channel::<()>();
+        let (address, server) = warp::serve(routes)
+            .try_bind_with_graceful_shutdown(([127, 0, 0, 1], 0), async { stop.await.ok(); })
+            .map_err(std::io::Error::other)?;
+        tokio::spawn(server);
+        Ok(MockDevice { address, state, shutdown: Some(shutdown) })
+    }
+
+    /// Address the mock is listening on
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    /// Device object pointing at this mock
+    pub fn device(&self) -> Device {
+        Device::from_ipv4(&self.address.ip().to_string(), i32::from(self.address.port()))
+    }
+
+    /// Inspect or change the mock's state
+    pub fn with_state<R>(&self, f: impl FnOnce(&mut MockState) -> R) -> R {
+        f(&mut lock(&self.state))
+    }
+
+    /// Every request received so far, oldest first
+    pub fn requests(&self) -> Vec<MockRequest> {
+        lock(&self.state).requests.clone()
+    }
+
+    /// Keys from every keypress received so far, e.g. "Home" or "Lit_a"
+    pub fn keypresses(&self) -> Vec<String> {
+        self.requests().into_iter()
+            .filter(|r| r.method == "POST")
+            .filter_map(|r| r.path.strip_prefix("keypress/").map(String::from))
+            .collect()
+    }
+
+    /// Forget every request received so far
+    pub fn clear_requests(&self) {
+        lock(&self.state).requests.clear();
+    }
+
+    /// Current power state
+    pub fn power(&self) -> POWERSTATE {
+        lock(&self.state).power.clone()
+    }
+
+    /// Simulate a power state, e.g. OFF to make every request time out
+    pub fn set_power(&self, power: POWERSTATE) {
+        lock(&self.state).power = power;
+    }
+
+    /// Add latency to every response
+    pub fn set_delay(&self, delay: Option<Duration>) {
+        lock(&self.state).delay = delay;
+    }
+
+    /// SSDP reply advertising this mock, for use with a MockSsdpResponder
+    pub fn ssdp_reply(&self) -> SsdpReply {
+        let state = lock(&self.state);
+        let serial = state.device_info.get("serial-number").cloned().unwrap_or_default();
+        let version = state.device_info.get("software-version").cloned().unwrap_or_default();
+        let reply = SsdpReply::new(&format!("uuid:roku:ecp:{}", serial), &format!("http://{}/", self.address))
+            .server(&format!("Roku/{0} UPnP/1.0 Roku/{0}", version));
+        match state.device_info.get("wifi-mac") {
+            Some(mac) => reply.wakeup(&format!("MAC={};Timeout=10", mac)),
+            None => reply
+        }
+    }
+}
+
+impl Drop for MockDevice {
+    fn drop(&mut self) {
+        if let Some(shutdown) = self.shutdown.take() {
+            let _ = shutdown.send(());
+        }
+    }
+}
+
+/// Reply a MockSsdpResponder sends to matching searches
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SsdpReply {
+    pub usn:        String,             // e.g. uuid:roku:ecp:X004000AB123
+    pub location:   String,             // e.g. http://192.168.1.134:8060/
+    pub wakeup:     Option<String>,     // e.g. MAC=d8:31:34:33:2d:7e;Timeout=10
+    pub server:     String,
+    pub max_age:    Duration,           // CACHE-CONTROL max-age, i.e. how long the announcement is valid
+    pub delay:      Option<Duration>,   // How long to wait before replying
+    pub raw:        Option<String>,     // Sent verbatim instead, e.g. to simulate malformed replies
+}
+
+impl SsdpReply {
+    /// Reply with the given USN and LOCATION headers
+    pub fn new(usn: &str, location: &str) -> SsdpReply {
+        SsdpReply {
+            usn: String::from(usn),
+            location: String::from(location),
+            wakeup: None,
+            server: String::from("Roku/9.2.0 UPnP/1.0 Roku/9.2.0"),
+            max_age: Duration::from_secs(3600),
+            delay: None,
+            raw: None
+        }
+    }
+
+    /// Reply with an arbitrary message
+    pub fn raw(message: &str) -> SsdpReply {
+        SsdpReply { raw: Some(String::from(message)), ..SsdpReply::new("", "") }
+    }
+
+    /// Set the WAKEUP header
+    pub fn wakeup(mut self, wakeup: &str) -> Self {
+        self.wakeup = Some(String::from(wakeup));
+        self
+    }
+
+    /// Set the Server header
+    pub fn server(mut self, server: &str) -> Self {
+        self.server = String::from(server);
+        self
+    }
+
+    /// Set the CACHE-CONTROL max-age (whole seconds)
+    pub fn max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = max_age;
+        self
+    }
+
+    /// Wait before replying
+    pub fn delay(mut self, delay: Duration) -> Self {
+        self.delay = Some(delay);
+        self
+    }
+
+    /// Render the reply to a search for the given target
+    fn render(&self, search_target: &str) -> String {
+        if let Some(raw) = &self.raw {
+            return raw.clone();
+        }
+        format!("HTTP/1.1 200 OK\r\nST: {}\r\nExt: \r\n{}\r\n", search_target, self.headers())
+    }
+
+    /// NOTIFY message announcing this device, like the ones devices multicast periodically
+    pub fn alive(&self) -> String {
+        match &self.raw {
+            Some(raw) => raw.clone(),
+            None => format!("NOTIFY * HTTP/1.1\r\nHost: 239.255.255.250:1900\r\nNT: roku:ecp\r\nNTS: ssdp:alive\r\n{}\r\n", self.headers())
+        }
+    }
+
+    /// NOTIFY message saying goodbye, e.g. before the device powers down
+    pub fn byebye(&self) -> String {
+        format!("NOTIFY * HTTP/1.1\r\nHost: 239.255.255.250:1900\r\nNT: roku:ecp\r\nNTS: ssdp:byebye\r\nUSN: {}\r\n\r\n", self.usn)
+    }
+
+    /// Headers describing the device, shared by replies and announcements
+    fn headers(&self) -> String {
+        let wakeup = self.wakeup.as_ref().map(|w| format!("WAKEUP: {}\r\n", w)).unwrap_or_default();
+        format!("Cache-Control: max-age={}\r\nUSN: {}\r\nServer: {}\r\nLOCATION: {}\r\n{}",
+                self.max_age.as_secs(), self.usn, self.server, self.location, wakeup)
+    }
+}
+
+/// Fake SSDP responder answering `roku:ecp` and `ssdp:all` searches; stops when dropped
+#[derive(Debug)]
+pub struct MockSsdpResponder {
+    address: SocketAddr,
+    replies: Arc<Mutex<Vec<SsdpReply>>>,
+    searches: Arc<Mutex<Vec<String>>>,
+    task: tokio::task::JoinHandle<()>,
+}
+
+impl MockSsdpResponder {
+    /// Start a responder on an ephemeral loopback port
+    pub async fn start(replies: Vec<SsdpReply>) -> std::io::Result<MockSsdpResponder> {
+        MockSsdpResponder::bind(SocketAddr::from(([127, 0, 0, 1], 0)), replies).await
+    }
+
+    /// Start a responder on the given address, joining it if it's a multicast group
+    pub async fn bind(address: SocketAddr, replies: Vec<SsdpReply>) -> std::io::Result<MockSsdpResponder> {
+        let socket = crate::ssdp::listen(address).await?;
+        let local = socket.local_addr()?;
+        // Report the group rather than the wildcard address we're bound to
+        let address = if address.ip().is_multicast() { SocketAddr::new(address.ip(), local.port()) } else { local };
+
+        let replies = Arc::new(Mutex::new(replies));
+        let searches = Arc::new(Mutex::new(Vec::new()));
+        let task = tokio::spawn(respond(Arc::new(socket), replies.clone(), searches.clone()));
+        Ok(MockSsdpResponder { address, replies, searches, task })
+    }
+
+    /// Address to send searches to
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    /// Search targets (ST headers) received so far, including ones that weren't answered
+    pub fn searches(&self) -> Vec<String> {
+        self.searches.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// Replace the replies sent to future searches
+    pub fn set_replies(&self, replies: Vec<SsdpReply>) {
+        *self.replies.lock().unwrap_or_else(|e| e.into_inner()) = replies;
+    }
+}
+
+impl Drop for MockSsdpResponder {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Answer M-SEARCH messages until the task is aborted
+async fn respond(socket: Arc<UdpSocket>, replies: Arc<Mutex<Vec<SsdpReply>>>, searches: Arc<Mutex<Vec<String>>>) {
+    let mut buf = [0u8; 2048];
+    while let Ok((num_bytes, sender)) = socket.recv_from(&mut buf).await {
+        let search_target = match parse_search(&buf[..num_bytes]) {
+            Some(st) => st,
+            None => continue
+        };
+        searches.lock().unwrap_or_else(|e| e.into_inner()).push(search_target.clone());
+        if search_target != "roku:ecp" && search_target != "ssdp:all" {
+            continue;
+        }
+        let replies = replies.lock().unwrap_or_else(|e| e.into_inner()).clone();
+        for reply in replies {
+            let socket = socket.clone();
+            let message = reply.render("roku:ecp");
+            tokio::spawn(async move {
+                if let Some(delay) = reply.delay {
+                    tokio::time::sleep(delay).await;
+                }
+                let _ = socket.send_to(message.as_bytes(), sender).await;
+            });
+        }
+    }
+}
+
+/// Search target of an M-SEARCH message, if that's what this is
+fn parse_search(raw: &[u8]) -> Option<String> {
+    let message = std::str::from_utf8(raw).ok()?;
+    if !message.starts_with("M-SEARCH") {
+        return None;
+    }
+    crate::ssdp::parse_ssdp_header(message, "ST")
+}
+
+/// Lock state, ignoring poisoning from a panicked test
+fn lock(state: &Mutex<MockState>) -> MutexGuard<'_, MockState> {
+    state.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn mock_app(id: &str, apptype: &str, version: &str, name: &str) -> App {
+    App {
+        id: AppId::from(id),
+        apptype: String::from(apptype),
+        subtype: None,
+        version: String::from(version),
+        name: String::from(name),
+        icon: None
+    }
+}
+
+/// Respond to a request the way a real device would
+async fn handle(state: Arc<Mutex<MockState>>, method: Method, path: String, query: Option<String>) -> std::result::Result<Response<Vec<u8>>, Infallible> {
+    // Record the request and decide how long to stall
+    let delay = {
+        let mut state = lock(&state);
+        state.requests.push(MockRequest { method: method.to_string(), path: path.clone(), query });
+        if state.power == POWERSTATE::OFF { Some(OFF_DELAY) } else { state.delay }
+    };
+    if let Some(delay) = delay {
+        tokio::time::sleep(delay).await;
+    }
+
+    let mut state = lock(&state);
+    let segments = path.split('/').collect::<Vec<_>>();
+    let response = match (method.as_str(), segments.as_slice()) {
+        ("GET", ["query", "device-info"]) => xml(render_device_info(&state)),
+        ("GET", ["query", "apps"]) => xml(render_apps(&state.apps)),
+        ("GET", ["query", "active-app"]) => xml(render_active_app(&state.active_app)),
+        ("GET", ["query", "media-player"]) => xml(render_media_player(&state.media_player)),
+        ("GET", ["query", "icon", id]) => match state.icons.get(&AppId::from(decode(id))) {
+            Some(icon) => Response::builder().header("content-type", icon.mime.as_str()).body(icon.data.clone()),
+            None => status(StatusCode::NOT_FOUND)
+        },
+        ("POST", ["keypress", key]) => {
+            match *key {
+                "PowerOff" => state.power = POWERSTATE::DISPLAYOFF,
+                "PowerOn" => state.power = POWERSTATE::ON,
+                "Home" => state.active_app = ActiveApp::Home { screensaver: None },
+                _ => ()
+            }
+            status(StatusCode::OK)
+        },
+        ("POST", ["keydown", _]) | ("POST", ["keyup", _]) => status(StatusCode::OK),
+        ("POST", ["launch", id]) => {
+            let id = AppId::from(decode(id));
+            match state.apps.iter().find(|app| app.id == id).cloned() {
+                Some(app) => {
+                    // Launching wakes the display
+                    state.power = POWERSTATE::ON;
+                    state.active_app = ActiveApp::App { app, screensaver: None };
+                    status(StatusCode::OK)
+                },
+                None => status(StatusCode::NOT_FOUND)
+            }
+        },
+        ("POST", ["input", _]) | ("POST", ["input"]) | ("POST", ["search", "browse"]) => status(StatusCode::OK),
+        _ => status(StatusCode::NOT_FOUND)
+    };
+    Ok(response.unwrap_or_else(|_| Response::new(Vec::new())))
+}
+
+fn xml(body: String) -> warp::http::Result<Response<Vec<u8>>> {
+    Response::builder().header("content-type", "text/xml; charset=\"utf-8\"").body(body.into_bytes())
+}
+
+fn status(status: StatusCode) -> warp::http::Result<Response<Vec<u8>>> {
+    Response::builder().status(status).body(Vec::new())
+}
+
+fn decode(s: &str) -> String {
+    urlencoding::decode(s).map(|s| s.to_string()).unwrap_or_else(|_| String::from(s))
+}
+
+/// Escape text for use in XML content or attributes
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;").replace('\'', "&apos;")
+}
+
+const XML_HEADER: &str = "<?xml version=\"1.0\" encoding=\"UTF-8\" ?>\n";
+
+fn render_device_info(state: &MockState) -> String {
+    let mut body = format!("{}<device-info>\n", XML_HEADER);
+    for (key, value) in state.device_info.iter().filter(|(k, _)| k.as_str() != "power-mode") {
+        body.push_str(&format!("\t<{0}>{1}</{0}>\n", key, escape(value)));
+    }
+    let power = match state.power {
+        POWERSTATE::ON => "PowerOn",
+        POWERSTATE::DISPLAYOFF => "DisplayOff",
+        POWERSTATE::OFF => "PowerOff",
+        POWERSTATE::UNKNOWN => "Unknown",
+    };
+    body.push_str(&format!("\t<power-mode>{}</power-mode>\n</device-info>\n", power));
+    body
+}
+
+fn render_app(tag: &str, app: &App) -> String {
+    let subtype = app.subtype.as_ref().map(|s| format!(" subtype=\"{}\"", escape(s))).unwrap_or_default();
+    format!("<{0} id=\"{1}\" type=\"{2}\"{3} version=\"{4}\">{5}</{0}>",
+            tag, escape(app.id.as_str()), escape(&app.apptype), subtype, escape(&app.version), escape(&app.name))
+}
+
+fn render_apps(apps: &[App]) -> String {
+    let mut body = format!("{}<apps>\n", XML_HEADER);
+    for app in apps {
+        body.push_str(&format!("\t{}\n", render_app("app", app)));
+    }
+    body.push_str("</apps>\n");
+    body
+}
+
+fn render_active_app(active: &ActiveApp) -> String {
+    let mut body = format!("{}<active-app>\n", XML_HEADER);
+    match active.app() {
+        Some(app) => body.push_str(&format!("\t{}\n", render_app("app", app))),
+        None => body.push_str("\t<app>Roku</app>\n")
+    }
+    if let Some(screensaver) = active.screensaver() {
+        body.push_str(&format!("\t{}\n", render_app("screensaver", screensaver)));
+    }
+    body.push_str("</active-app>\n");
+    body
+}
+
+fn render_media_player(player: &MediaPlayerState) -> String {
+    let mut body = format!("{}<player error=\"{}\" state=\"{}\">\n", XML_HEADER, player.error, escape(&player.state.to_string()));
+    if let Some(plugin) = &player.plugin {
+        let bandwidth = plugin.bandwidth.as_ref().map(|b| format!(" bandwidth=\"{}\"", escape(b))).unwrap_or_default();
+        body.push_str(&format!("\t<plugin{} id=\"{}\" name=\"{}\"/>\n", bandwidth, escape(plugin.id.as_str()), escape(&plugin.name)));
+    }
+    if let Some(format) = &player.format {
+        let attribute = |name: &str, value: &Option<String>| value.as_ref().map(|v| format!(" {}=\"{}\"", name, escape(v))).unwrap_or_default();
+        body.push_str(&format!("\t<format{}{}{}{}{}/>\n",
+                               attribute("audio", &format.audio), attribute("captions", &format.captions), attribute("drm", &format.drm),
+                               attribute("video", &format.video), attribute("video_res", &format.video_res)));
+    }
+    if let Some(buffering) = &player.buffering {
+        body.push_str(&format!("\t<buffering current=\"{}\" max=\"{}\" target=\"{}\"/>\n", buffering.current, buffering.max, buffering.target));
+    }
+    let millis = |tag: &str, value: Option<Duration>| value.map(|v| format!("\t<{0}>{1} ms</{0}>\n", tag, v.as_millis())).unwrap_or_default();
+    body.push_str(&millis("position", player.position));
+    body.push_str(&millis("duration", player.duration));
+    body.push_str(&format!("\t<is_live>{}</is_live>\n", player.is_live));
+    body.push_str(&millis("runtime", player.runtime));
+    body.push_str("</player>\n");
+    body
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{KoruError, MediaPlugin, PlayerState, POWERCOMMAND, BUTTON};
+
+    #[tokio::test]
+    async fn serves_device_queries() {
+        let mock = MockDevice::start().await.unwrap();
+        let device = mock.device();
+        let info = device.get_info().await.unwrap();
+        assert_eq!(info.friendly_device_name.as_deref(), Some("Mock Roku"));
+        assert_eq!(info.power_mode, POWERSTATE::ON);
+        assert_eq!(device.get_installed_apps().await.unwrap().len(), 4);
+        assert!(device.get_active_app().await.unwrap().is_home());
+        assert_eq!(device.get_media_player().await.unwrap().state, PlayerState::None);
+        assert_eq!(device.fetch_icon("12").await.unwrap().mime, "image/png");
+        assert!(matches!(device.fetch_icon("404").await, Err(KoruError::Http(StatusCode::NOT_FOUND))));
+    }
+
+    #[tokio::test]
+    async fn round_trips_media_player_state() {
+        let player = MediaPlayerState {
+            state: PlayerState::Pause,
+            plugin: Some(MediaPlugin { id: AppId::from("12"), name: String::from("Net & flix"), bandwidth: None }),
+            position: Some(Duration::from_millis(1500)),
+            duration: Some(Duration::from_millis(60000)),
+            is_live: true,
+            ..MediaPlayerState::default()
+        };
+        let mock = MockDevice::start_with(MockState { media_player: player.clone(), ..MockState::default() }).await.unwrap();
+        assert_eq!(mock.device().get_media_player().await.unwrap(), player);
+    }
+
+    #[tokio::test]
+    async fn records_commands_and_tracks_state() {
+        let mock = MockDevice::start().await.unwrap();
+        let device = mock.device();
+        device.launch_app_by_id("12").await.unwrap();
+        assert_eq!(device.get_active_app().await.unwrap().app().unwrap().name, "Netflix");
+        device.press_keys("hi").await.unwrap();
+        device.press_button(BUTTON::Home).await.unwrap();
+        assert!(device.get_active_app().await.unwrap().is_home());
+        assert_eq!(mock.keypresses(), vec!["Lit_h", "Lit_i", "Home"]);
+        assert!(mock.requests().contains(&MockRequest { method: String::from("POST"), path: String::from("launch/12"), query: None }));
+
+        device.send_power_command(POWERCOMMAND::TURNOFF).await.unwrap();
+        assert_eq!(mock.power(), POWERSTATE::DISPLAYOFF);
+        device.send_power_command(POWERCOMMAND::TOGGLE).await.unwrap();
+        assert_eq!(mock.power(), POWERSTATE::ON);
+    }
+
+    #[tokio::test]
+    async fn simulates_timeouts() {
+        let mock = MockDevice::start().await.unwrap();
+        let device = mock.device().with_client(crate::KoruClient::new(crate::ClientConfig {
+            query_timeout: Duration::from_millis(100),
+            ..crate::ClientConfig::default()
+        }).unwrap());
+        mock.set_delay(Some(Duration::from_millis(300)));
+        assert!(matches!(device.get_info().await, Err(KoruError::Timeout)));
+        mock.set_delay(None);
+        mock.set_power(POWERSTATE::OFF);
+        // Unreachable devices are assumed to be off
+        assert_eq!(device.get_power_state().await, POWERSTATE::OFF);
+    }
+
+    #[tokio::test]
+    async fn answers_only_roku_searches() {
+        let responder = MockSsdpResponder::start(vec![SsdpReply::new("uuid:roku:ecp:A", "http://10.0.0.1:8060/")]).await.unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut buf = [0u8; 1024];
+        for st in &["urn:dial-multiscreen-org:service:dial:1", "ssdp:all"] {
+            let search = format!("M-SEARCH * HTTP/1.1\r\nHost: {}\r\nMan: \"ssdp:discover\"\r\nST: {}\r\n\r\n", responder.address(), st);
+            socket.send_to(search.as_bytes(), responder.address()).await.unwrap();
+        }
+        // Only the ssdp:all search gets a reply
+        let num_bytes = tokio::time::timeout(Duration::from_secs(1), socket.recv(&mut buf)).await.unwrap().unwrap();
+        assert!(std::str::from_utf8(&buf[..num_bytes]).unwrap().contains("LOCATION: http://10.0.0.1:8060/\r\n"));
+        assert!(tokio::time::timeout(Duration::from_millis(200), socket.recv(&mut buf)).await.is_err());
+        assert_eq!(responder.searches(), vec!["urn:dial-multiscreen-org:service:dial:1", "ssdp:all"]);
+    }
+}
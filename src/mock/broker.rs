@@ -0,0 +1,239 @@
+/// Fake MQTT broker on localhost, for testing the MQTT bridge without Mosquitto
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, MutexGuard};
+use bytes::BytesMut;
+use rumqttc::mqttbytes::v4::{self, ConnAck, ConnectReturnCode, Packet, PingResp, PubAck, Publish, SubAck, SubscribeReasonCode};
+use rumqttc::mqttbytes::{self, QoS};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio::task::JoinSet;
+
+// Largest packet accepted from a client
+const MAX_PACKET_SIZE: usize = 1024 * 1024;
+
+/// Connected client
+#[derive(Debug)]
+struct Client {
+    filters: Vec<String>,                           // Topic filters subscribed to
+    outgoing: mpsc::UnboundedSender<Publish>,       // Messages to forward to it
+}
+
+/// Everything the broker has received and is holding on to
+#[derive(Debug, Default)]
+struct BrokerState {
+    published: Vec<(String, String)>,       // Every message clients published, oldest first
+    retained: BTreeMap<String, Publish>,    // Last retained message on each topic
+    clients: HashMap<usize, Client>,
+    next_client: usize,
+}
+
+/// Minimal MQTT 3.1.1 broker: QoS 0 and 1, retained messages, wildcard subscriptions and last wills.
+/// Listens on an ephemeral loopback port and stops (dropping every client) when dropped.
+#[derive(Debug)]
+pub struct MockBroker {
+    address: SocketAddr,
+    state: Arc<Mutex<BrokerState>>,
+    task: tokio::task::JoinHandle<()>,
+}
+
+impl MockBroker {
+    /// Start a broker on an ephemeral loopback port
+    pub async fn start() -> std::io::Result<MockBroker> {
+        let listener = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0))).await?;
+        let address = listener.local_addr()?;
+        let state = Arc::new(Mutex::new(BrokerState::default()));
+        let task = tokio::spawn(accept(listener, state.clone()));
+        Ok(MockBroker { address, state, task })
+    }
+
+    /// Address clients should connect to
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    /// Topic and payload of every message clients published so far, oldest first
+    pub fn published(&self) -> Vec<(String, String)> {
+        lock(&self.state).published.clone()
+    }
+
+    /// Payload of the message currently retained on a topic
+    pub fn retained(&self, topic: &str) -> Option<String> {
+        lock(&self.state).retained.get(topic).map(|publish| String::from_utf8_lossy(&publish.payload).into_owned())
+    }
+
+    /// Number of clients currently connected
+    pub fn clients(&self) -> usize {
+        lock(&self.state).clients.len()
+    }
+
+    /// Send a message to every matching subscriber, as if another client had published it
+    pub fn publish(&self, topic: &str, payload: &str) {
+        route(&mut lock(&self.state), Publish::new(topic, QoS::AtMostOnce, payload));
+    }
+}
+
+impl Drop for MockBroker {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Accept clients until the task is aborted, which also disconnects every client
+async fn accept(listener: TcpListener, state: Arc<Mutex<BrokerState>>) {
+    let mut connections = JoinSet::new();
+    while let Ok((stream, _)) = listener.accept().await {
+        connections.spawn(serve(stream, state.clone()));
+        // Forget clients that have gone away
+        while connections.try_join_next().is_some() {}
+    }
+}
+
+/// Talk to a client until it disconnects, then send its last will if it didn't say goodbye
+async fn serve(mut stream: TcpStream, state: Arc<Mutex<BrokerState>>) {
+    let (outgoing, mut forwarded) = mpsc::unbounded_channel();
+    let id = {
+        let mut state = lock(&state);
+        state.next_client += 1;
+        let id = state.next_client;
+        state.clients.insert(id, Client { filters: Vec::new(), outgoing });
+        id
+    };
+    let mut will = None;
+    let clean = session(&mut stream, &state, id, &mut forwarded, &mut will).await;
+
+    let mut state = lock(&state);
+    state.clients.remove(&id);
+    if let (false, Some(will)) = (clean, will) {
+        let mut publish = Publish::from_bytes(will.topic, will.qos, will.message);
+        publish.retain = will.retain;
+        route(&mut state, publish);
+    }
+}
+
+/// Handle a client's packets, returning whether it disconnected cleanly
+async fn session(stream: &mut TcpStream, state: &Mutex<BrokerState>, id: usize,
+                 forwarded: &mut mpsc::UnboundedReceiver<Publish>, will: &mut Option<v4::LastWill>) -> bool {
+    let mut incoming = BytesMut::with_capacity(4096);
+    loop {
+        // Answer every complete packet received so far
+        loop {
+            let packet = match v4::read(&mut incoming, MAX_PACKET_SIZE) {
+                Ok(packet) => packet,
+                Err(mqttbytes::Error::InsufficientBytes(_)) => break,
+                Err(_) => return false
+            };
+            let mut reply = BytesMut::new();
+            let written = match packet {
+                Packet::Connect(connect) => {
+                    *will = connect.last_will;
+                    ConnAck::new(ConnectReturnCode::Success, false).write(&mut reply)
+                }
+                Packet::Subscribe(subscribe) => {
+                    let mut state = lock(state);
+                    if let Some(client) = state.clients.get_mut(&id) {
+                        client.filters.extend(subscribe.filters.iter().map(|filter| filter.path.clone()));
+                    }
+                    let codes = subscribe.filters.iter()
+                        .map(|filter| SubscribeReasonCode::Success(if filter.qos == QoS::AtMostOnce { QoS::AtMostOnce } else { QoS::AtLeastOnce }))
+                        .collect();
+                    let written = SubAck::new(subscribe.pkid, codes).write(&mut reply);
+                    // New subscribers get whatever's retained on the topics they asked for
+                    let retained = state.retained.values()
+                        .filter(|publish| subscribe.filters.iter().any(|filter| topic_matches(&filter.path, &publish.topic)));
+                    retained.fold(written, |written, publish| written.and_then(|_| forward(publish, true).write(&mut reply)))
+                }
+                Packet::Publish(publish) => {
+                    let written = match publish.qos {
+                        QoS::AtMostOnce => Ok(0),
+                        _ => PubAck::new(publish.pkid).write(&mut reply)
+                    };
+                    let mut state = lock(state);
+                    state.published.push((publish.topic.clone(), String::from_utf8_lossy(&publish.payload).into_owned()));
+                    route(&mut state, publish);
+                    written
+                }
+                Packet::PingReq => PingResp.write(&mut reply),
+                Packet::Disconnect => return true,
+                // QoS 2 and unsubscribing aren't needed by anything we test
+                _ => Ok(0)
+            };
+            if written.is_err() || stream.write_all(&reply).await.is_err() {
+                return false;
+            }
+        }
+
+        tokio::select! {
+            read = stream.read_buf(&mut incoming) => match read {
+                Ok(0) | Err(_) => return false,
+                Ok(_) => {}
+            },
+            Some(publish) = forwarded.recv() => {
+                let mut message = BytesMut::new();
+                if publish.write(&mut message).is_err() || stream.write_all(&message).await.is_err() {
+                    return false;
+                }
+            }
+        }
+    }
+}
+
+/// Retain a message if asked to, and forward it to every matching subscriber
+fn route(state: &mut BrokerState, publish: Publish) {
+    if publish.retain {
+        // An empty retained message clears the topic
+        if publish.payload.is_empty() {
+            state.retained.remove(&publish.topic);
+        } else {
+            state.retained.insert(publish.topic.clone(), publish.clone());
+        }
+    }
+    for client in state.clients.values() {
+        if client.filters.iter().any(|filter| topic_matches(filter, &publish.topic)) {
+            // Only fails if the client is going away anyway
+            let _ = client.outgoing.send(forward(&publish, false));
+        }
+    }
+}
+
+/// Copy of a message as sent to subscribers (always QoS 0, so there's nothing to acknowledge)
+fn forward(publish: &Publish, retain: bool) -> Publish {
+    let mut forwarded = Publish::from_bytes(publish.topic.clone(), QoS::AtMostOnce, publish.payload.clone());
+    forwarded.retain = retain;
+    forwarded
+}
+
+/// Whether a topic matches a subscription filter, with `+` and `#` wildcards
+fn topic_matches(filter: &str, topic: &str) -> bool {
+    let mut levels = topic.split('/');
+    for level in filter.split('/') {
+        match (level, levels.next()) {
+            ("#", _) => return true,
+            ("+", Some(_)) => {}
+            (level, Some(topic_level)) if level == topic_level => {}
+            _ => return false
+        }
+    }
+    levels.next().is_none()
+}
+
+/// Lock state, ignoring poisoning from a panicked test
+fn lock(state: &Mutex<BrokerState>) -> MutexGuard<'_, BrokerState> {
+    state.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_wildcard_filters() {
+        assert!(topic_matches("koru/+/+/set", "koru/X00/power/set"));
+        assert!(topic_matches("koru/#", "koru/X00/power"));
+        assert!(topic_matches("koru/#", "koru"));
+        assert!(!topic_matches("koru/+/+/set", "koru/X00/power"));
+        assert!(!topic_matches("koru/+", "koru/X00/power"));
+        assert!(!topic_matches("koru/X00", "koru/X01"));
+    }
+}
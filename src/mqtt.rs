@@ -0,0 +1,636 @@
+/// Bridge publishing device state to an MQTT broker and taking commands from it, announced to Home Assistant via MQTT discovery
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+use futures::StreamExt;
+use rumqttc::{AsyncClient, ConnectionError, Event, EventLoop, LastWill, MqttOptions, Packet, QoS};
+use serde_json::{json, Value};
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+use tracing::{debug, info, warn, Instrument};
+use crate::{
+    ActiveApp, App, AppId, Device, DeviceEvent, DeviceEvents, DeviceState, DeviceWatcher, PlayerState, RegisteredDevice, WatchOptions,
+    BUTTON, POWERCOMMAND, POWERSTATE
+};
+use crate::error::{KoruError, Result};
+
+// How long to wait for the broker to accept the first connection
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+// How long to wait before reconnecting to a broker we lost
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+// Publishes and subscriptions queued for the broker before publishing waits
+const REQUEST_CAPACITY: usize = 64;
+
+// Payloads of the bridge's availability topic
+const ONLINE: &str = "online";
+const OFFLINE: &str = "offline";
+
+// Values of the playback sensor, see player_state()
+const PLAYER_STATES: [&str; 7] = ["off", "standby", "idle", "on", "playing", "paused", "buffering"];
+
+// Remote keys announced as Home Assistant buttons (the rest can still be sent to button/set)
+const REMOTE_BUTTONS: [BUTTON; 15] = [
+    BUTTON::Home, BUTTON::Back, BUTTON::Select, BUTTON::Up, BUTTON::Down, BUTTON::Left, BUTTON::Right,
+    BUTTON::Play, BUTTON::Rev, BUTTON::Fwd, BUTTON::InstantReplay, BUTTON::Info,
+    BUTTON::VolumeUp, BUTTON::VolumeDown, BUTTON::VolumeMute,
+];
+
+/// Where the broker is and which topics to use
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BridgeOptions {
+    pub host:               String,
+    pub port:               u16,
+    pub client_id:          String,
+    pub credentials:        Option<(String, String)>,   // Username and password, if the broker wants them
+    pub base_topic:         String,                     // State and command topics go under {base_topic}/{serial}/
+    pub discovery_prefix:   String,                     // Home Assistant's discovery prefix
+    pub watch:              WatchOptions,               // How often to poll each device for its state
+}
+
+impl Default for BridgeOptions {
+    fn default() -> Self {
+        BridgeOptions {
+            host: String::from("localhost"),
+            port: 1883,
+            client_id: String::from("koru"),
+            credentials: None,
+            base_topic: String::from("koru"),
+            discovery_prefix: String::from("homeassistant"),
+            watch: WatchOptions::default(),
+        }
+    }
+}
+
+/// Command received on one of a device's `.../set` topics
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Command {
+    Power(POWERCOMMAND),    // {base}/{id}/power/set: ON, OFF or TOGGLE
+    Press(BUTTON),          // {base}/{id}/button/set: button name, e.g. Home
+    Launch(String),         // {base}/{id}/launch/set: app id or installed app name, e.g. 12 or Netflix
+    Type(String),           // {base}/{id}/keys/set: text to type
+}
+
+/// Device being bridged
+#[derive(Clone, Debug)]
+struct Bridged {
+    name: String,       // Shown in Home Assistant
+    device: Device,
+}
+
+/// What the bridge's tasks share
+#[derive(Debug)]
+struct Bridge {
+    options: BridgeOptions,
+    devices: BTreeMap<String, Bridged>,     // By topic id (sanitized serial number)
+    watcher: Arc<DeviceWatcher>,
+    client: AsyncClient,
+    apps: Mutex<BTreeMap<String, Vec<App>>>,    // Installed apps by topic id, once a device has answered
+}
+
+/// Connection to an MQTT broker on behalf of a set of devices; disconnects (and goes offline) when dropped
+#[derive(Debug)]
+pub struct MqttBridge {
+    watcher: Arc<DeviceWatcher>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl MqttBridge {
+    /// Connect to the broker, announce every device to Home Assistant and keep their state topics up to date
+    pub async fn start(options: BridgeOptions, devices: Vec<RegisteredDevice>) -> Result<MqttBridge> {
+        MqttBridge::start_with_watcher(options, devices, DeviceWatcher::new()).await
+    }
+
+    /// Start, polling the devices with a given watcher (e.g. one with a lower floor for mock devices)
+    pub(crate) async fn start_with_watcher(options: BridgeOptions, devices: Vec<RegisteredDevice>, watcher: DeviceWatcher) -> Result<MqttBridge> {
+        let mut mqtt = MqttOptions::new(options.client_id.clone(), options.host.clone(), options.port);
+        // The broker marks us offline if we go away w/o saying so
+        mqtt.set_last_will(LastWill::new(availability_topic(&options.base_topic), OFFLINE, QoS::AtLeastOnce, true));
+        if let Some((username, password)) = &options.credentials {
+            mqtt.set_credentials(username.clone(), password.clone());
+        }
+        let (client, eventloop) = AsyncClient::new(mqtt, REQUEST_CAPACITY);
+
+        let devices = devices.into_iter()
+            .map(|registered| {
+                let name = registered.alias.clone()
+                    .or_else(|| Some(registered.device.name.clone()).filter(|name| !name.is_empty()))
+                    .unwrap_or_else(|| registered.serial_number.clone());
+                (topic_id(&registered.serial_number), Bridged { name, device: registered.device })
+            })
+            .collect::<BTreeMap<_, _>>();
+        let watcher = Arc::new(watcher);
+        // Subscribe first so the first poll of each device isn't missed
+        let events = watcher.subscribe();
+        for (id, bridged) in &devices {
+            watcher.watch(id.clone(), bridged.device.clone(), options.watch.clone());
+        }
+
+        let span = tracing::info_span!("mqtt_bridge", broker = %format!("{}:{}", options.host, options.port));
+        let bridge = Arc::new(Bridge { options, devices, watcher: watcher.clone(), client, apps: Mutex::default() });
+        let (connected, connection) = oneshot::channel();
+        let (announce, announcements) = mpsc::unbounded_channel();
+        let (commands, queued) = mpsc::unbounded_channel();
+        let tasks = vec![
+            tokio::spawn(run_eventloop(eventloop, bridge.options.base_topic.clone(), connected, announce, commands).instrument(span.clone())),
+            tokio::spawn(publish_state(bridge.clone(), events, announcements).instrument(span.clone())),
+            tokio::spawn(execute_commands(bridge, queued).instrument(span)),
+        ];
+        // Stops the tasks if we bail out below
+        let bridge = MqttBridge { watcher, tasks };
+
+        match tokio::time::timeout(CONNECT_TIMEOUT, connection).await {
+            Ok(Ok(Ok(()))) => Ok(bridge),
+            Ok(Ok(Err(e))) => Err(e),
+            Ok(Err(_)) => Err(KoruError::Io(std::io::Error::other("MQTT event loop stopped before connecting"))),
+            Err(_) => Err(KoruError::Timeout)
+        }
+    }
+
+    /// Watcher polling the bridged devices, by topic id (their serial number)
+    pub fn watcher(&self) -> &DeviceWatcher {
+        &self.watcher
+    }
+}
+
+impl Drop for MqttBridge {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+/// Drive the MQTT connection, reporting the first connection attempt, (re)connections and commands
+async fn run_eventloop(mut eventloop: EventLoop, base_topic: String,
+                       connected: oneshot::Sender<Result<()>>,
+                       announce: mpsc::UnboundedSender<()>,
+                       commands: mpsc::UnboundedSender<(String, Command)>) {
+    let mut connected = Some(connected);
+    loop {
+        match eventloop.poll().await {
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                info!("connected to MQTT broker");
+                if let Some(connected) = connected.take() {
+                    let _ = connected.send(Ok(()));
+                }
+                // Subscriptions, discovery and availability need redoing on every connection
+                let _ = announce.send(());
+            }
+            Ok(Event::Incoming(Packet::Publish(publish))) => {
+                let payload = String::from_utf8_lossy(&publish.payload);
+                match parse_command(&base_topic, &publish.topic, &payload) {
+                    Ok(command) => {
+                        let _ = commands.send(command);
+                    }
+                    Err(e) => warn!(topic = %publish.topic, error = %e, "ignoring MQTT message")
+                }
+            }
+            Ok(_) => {}
+            Err(e) => match connected.take() {
+                // Nobody's going to fix the broker address for us
+                Some(connected) => {
+                    let _ = connected.send(Err(connection_error(e)));
+                    return;
+                }
+                None => {
+                    warn!(error = %e, "lost MQTT broker, reconnecting");
+                    tokio::time::sleep(RECONNECT_DELAY).await;
+                }
+            }
+        }
+    }
+}
+
+/// Publish discovery and state on every connection, and state whenever a device changes
+async fn publish_state(bridge: Arc<Bridge>, mut events: DeviceEvents, mut announcements: mpsc::UnboundedReceiver<()>) {
+    loop {
+        let result = tokio::select! {
+            Some(()) = announcements.recv() => announce(&bridge).await,
+            Some(event) = events.next() => match event {
+                DeviceEvent::Appeared { id, device } => refresh_apps(&bridge, &id, &device).await,
+                event => publish_device(&bridge, event.id()).await
+            },
+            else => return
+        };
+        if let Err(e) = result {
+            // Only happens once the event loop's gone, i.e. we're shutting down
+            debug!(error = %e, "unable to queue MQTT message");
+            return;
+        }
+    }
+}
+
+/// Announce the bridge and every device, and listen for commands
+async fn announce(bridge: &Bridge) -> std::result::Result<(), rumqttc::ClientError> {
+    let options = &bridge.options;
+    let client = &bridge.client;
+    client.subscribe(format!("{}/+/+/set", options.base_topic), QoS::AtLeastOnce).await?;
+    client.publish(availability_topic(&options.base_topic), QoS::AtLeastOnce, true, ONLINE).await?;
+    for (id, bridged) in &bridge.devices {
+        publish_discovery(bridge, id, &bridged.name).await?;
+        publish_device(bridge, id).await?;
+    }
+    Ok(())
+}
+
+/// Publish a device's discovery configs, including its app select if we know its apps
+async fn publish_discovery(bridge: &Bridge, id: &str, name: &str) -> std::result::Result<(), rumqttc::ClientError> {
+    let apps = lock(&bridge.apps).get(id).cloned().unwrap_or_default();
+    for (topic, payload) in discovery_messages(&bridge.options, id, name, &apps) {
+        bridge.client.publish(topic, QoS::AtLeastOnce, true, payload).await?;
+    }
+    Ok(())
+}
+
+/// Fetch the apps of a device that just started answering, re-announcing it if they changed, then publish its state
+async fn refresh_apps(bridge: &Bridge, id: &str, device: &Device) -> std::result::Result<(), rumqttc::ClientError> {
+    match device.get_installed_apps().await {
+        Ok(apps) => {
+            let changed = lock(&bridge.apps).insert(String::from(id), apps.clone()).as_ref() != Some(&apps);
+            if let (true, Some(bridged)) = (changed, bridge.devices.get(id)) {
+                publish_discovery(bridge, id, &bridged.name).await?;
+            }
+        }
+        Err(e) => warn!(%id, error = %e, "unable to list installed apps")
+    }
+    publish_device(bridge, id).await
+}
+
+/// Publish a device's current state, if it's been polled yet
+async fn publish_device(bridge: &Bridge, id: &str) -> std::result::Result<(), rumqttc::ClientError> {
+    if let Some(state) = bridge.watcher.state(id) {
+        for (topic, payload) in state_messages(&bridge.options.base_topic, id, &state) {
+            bridge.client.publish(topic, QoS::AtLeastOnce, true, payload).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Run commands one at a time, in the order they arrived
+async fn execute_commands(bridge: Arc<Bridge>, mut queued: mpsc::UnboundedReceiver<(String, Command)>) {
+    while let Some((id, command)) = queued.recv().await {
+        let device = match bridge.devices.get(&id) {
+            Some(bridged) => &bridged.device,
+            None => {
+                warn!(%id, "ignoring command for unknown device");
+                continue;
+            }
+        };
+        info!(%id, ?command, "running command");
+        let result = match command {
+            Command::Power(command) => device.send_power_command(command).await,
+            Command::Press(button) => device.press_button(button).await,
+            Command::Launch(app) => {
+                // Home Assistant's app select sends names; anything else is taken as an id
+                let app_id = lock(&bridge.apps).get(&id)
+                    .and_then(|apps| apps.iter().find(|installed| installed.name == app))
+                    .map_or_else(|| AppId::from(app.as_str()), |installed| installed.id.clone());
+                device.launch_app_by_id(app_id).await
+            }
+            Command::Type(text) => device.press_keys(&text).await,
+        };
+        if let Err(e) = result {
+            warn!(%id, error = %e, "command failed");
+        }
+    }
+}
+
+/// Device id and command for a message on a `{base}/{id}/{command}/set` topic
+fn parse_command(base_topic: &str, topic: &str, payload: &str) -> Result<(String, Command)> {
+    let invalid = || KoruError::InvalidArgument(format!("not a command topic: {}", topic));
+    let rest = topic.strip_prefix(base_topic).and_then(|rest| rest.strip_prefix('/')).ok_or_else(invalid)?;
+    let (id, command) = match rest.split('/').collect::<Vec<_>>().as_slice() {
+        [id, command, "set"] => (String::from(*id), *command),
+        _ => return Err(invalid())
+    };
+    let command = match command {
+        "power" => Command::Power(match payload.trim().to_ascii_uppercase().as_str() {
+            "ON" => POWERCOMMAND::TURNON,
+            "OFF" => POWERCOMMAND::TURNOFF,
+            "TOGGLE" => POWERCOMMAND::TOGGLE,
+            _ => return Err(KoruError::InvalidArgument(format!("unknown power command '{}', expected ON, OFF or TOGGLE", payload)))
+        }),
+        "button" => Command::Press(payload.trim().parse()?),
+        "launch" if !payload.trim().is_empty() => Command::Launch(String::from(payload.trim())),
+        "keys" => Command::Type(String::from(payload)),
+        _ => return Err(invalid())
+    };
+    Ok((id, command))
+}
+
+/// Retained Home Assistant discovery configs for a device, all for core MQTT platforms.
+/// The app select is only included once the device's installed apps are known.
+fn discovery_messages(options: &BridgeOptions, id: &str, name: &str, apps: &[App]) -> Vec<(String, String)> {
+    let topic = |suffix: &str| format!("{}/{}/{}", options.base_topic, id, suffix);
+    let node = format!("koru_{}", id);
+    let common = json!({
+        "device": { "identifiers": [node], "name": name, "manufacturer": "Roku" },
+        "availability_topic": availability_topic(&options.base_topic),
+        "payload_available": ONLINE,
+        "payload_not_available": OFFLINE,
+    });
+    let mut entities = vec![
+        ("switch", String::from("power"), json!({
+            "name": "Power",
+            "icon": "mdi:television",
+            "state_topic": topic("power"),
+            "command_topic": topic("power/set"),
+            "payload_on": "ON",
+            "payload_off": "OFF",
+        })),
+        ("sensor", String::from("app"), json!({
+            "name": "Active app",
+            "icon": "mdi:application",
+            "state_topic": topic("state"),
+            "value_template": "{{ value_json.app }}",
+            "json_attributes_topic": topic("state"),
+        })),
+        ("sensor", String::from("playback"), json!({
+            "name": "Playback",
+            "icon": "mdi:play-pause",
+            "device_class": "enum",
+            "options": PLAYER_STATES,
+            "state_topic": topic("state"),
+            "value_template": "{{ value_json.state }}",
+        })),
+        ("text", String::from("keys"), json!({
+            "name": "Type text",
+            "icon": "mdi:keyboard",
+            "command_topic": topic("keys/set"),
+        })),
+    ];
+    if !apps.is_empty() {
+        // Selecting an app launches it; it shows the foreground app (and nothing on the home screen)
+        entities.push(("select", String::from("app_launch"), json!({
+            "name": "Launch app",
+            "icon": "mdi:apps",
+            "options": apps.iter().map(|app| app.name.as_str()).collect::<Vec<_>>(),
+            "command_topic": topic("launch/set"),
+            "state_topic": topic("state"),
+            "value_template": "{{ value_json.app if value_json.app_id else 'None' }}",
+        })));
+    }
+    entities.extend(REMOTE_BUTTONS.iter().map(|button| {
+        let button = button.to_string();
+        ("button", format!("button_{}", button.to_ascii_lowercase()), json!({
+            "name": button,
+            "command_topic": topic("button/set"),
+            "payload_press": button,
+        }))
+    }));
+    entities.into_iter()
+        .map(|(component, object, mut config)| {
+            merge(&mut config, &common);
+            config["unique_id"] = json!(format!("{}_{}", node, object));
+            (format!("{}/{}/{}/{}/config", options.discovery_prefix, component, node, object), config.to_string())
+        })
+        .collect()
+}
+
+/// Retained state messages for a device: `power` (ON or OFF) and `state` (JSON)
+fn state_messages(base_topic: &str, id: &str, state: &DeviceState) -> Vec<(String, String)> {
+    let power = if state.power == POWERSTATE::ON { "ON" } else { "OFF" };
+    let app = state.active_app.as_ref().filter(|_| state.is_present());
+    let media = state.media.as_ref().filter(|_| state.is_present());
+    let payload = json!({
+        "power": state.power,
+        "state": player_state(state),
+        "app": app.map(|active| active.app().map_or("Home", |app| app.name.as_str())),
+        "app_id": app.and_then(ActiveApp::app).map(|app| app.id.as_str()),
+        "media": media,
+    });
+    vec![
+        (format!("{}/{}/power", base_topic, id), String::from(power)),
+        (format!("{}/{}/state", base_topic, id), payload.to_string()),
+    ]
+}
+
+/// Home Assistant media player state for a device: off, standby, idle, on, playing, paused or buffering
+fn player_state(state: &DeviceState) -> &'static str {
+    match state.power {
+        POWERSTATE::ON => {}
+        POWERSTATE::DISPLAYOFF => return "standby",
+        POWERSTATE::OFF | POWERSTATE::UNKNOWN => return "off",
+    }
+    match state.media.as_ref().map(|media| &media.state) {
+        Some(PlayerState::Play) => "playing",
+        Some(PlayerState::Pause) => "paused",
+        Some(PlayerState::Buffering) | Some(PlayerState::Startup) => "buffering",
+        _ if state.active_app.as_ref().is_some_and(ActiveApp::is_home) => "idle",
+        _ => "on"
+    }
+}
+
+/// Lock the cached app lists, ignoring poisoning from a panicked task
+fn lock(apps: &Mutex<BTreeMap<String, Vec<App>>>) -> MutexGuard<'_, BTreeMap<String, Vec<App>>> {
+    apps.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Copy every field of one JSON object into another
+fn merge(config: &mut Value, common: &Value) {
+    if let (Some(config), Some(common)) = (config.as_object_mut(), common.as_object()) {
+        config.extend(common.iter().map(|(key, value)| (key.clone(), value.clone())));
+    }
+}
+
+/// Topic the bridge reports itself online or offline on
+fn availability_topic(base_topic: &str) -> String {
+    format!("{}/bridge/availability", base_topic)
+}
+
+/// Serial number made safe for topics and Home Assistant ids
+fn topic_id(serial_number: &str) -> String {
+    serial_number.chars().map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '_' }).collect()
+}
+
+/// Error for a broker we couldn't connect to
+fn connection_error(e: ConnectionError) -> KoruError {
+    match e {
+        ConnectionError::Io(e) => KoruError::Io(e),
+        ConnectionError::NetworkTimeout => KoruError::Timeout,
+        e => KoruError::Io(std::io::Error::other(format!("unable to connect to MQTT broker: {}", e)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{MockBroker, MockDevice};
+    use crate::MediaPlayerState;
+
+    fn registered(mock: &MockDevice) -> RegisteredDevice {
+        RegisteredDevice {
+            serial_number: String::from("X00000000000"),
+            alias: Some(String::from("living-room")),
+            device: mock.device(),
+            last_seen: None,
+        }
+    }
+
+    fn options(broker: &MockBroker) -> BridgeOptions {
+        BridgeOptions {
+            host: broker.address().ip().to_string(),
+            port: broker.address().port(),
+            watch: WatchOptions { interval: Duration::from_millis(50), ..WatchOptions::default() },
+            ..BridgeOptions::default()
+        }
+    }
+
+    /// Wait for a condition that should become true shortly
+    async fn eventually(what: &str, mut condition: impl FnMut() -> bool) {
+        for _ in 0..100 {
+            if condition() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(30)).await;
+        }
+        panic!("timed out waiting for {}", what);
+    }
+
+    #[test]
+    fn parses_commands() {
+        assert_eq!(parse_command("koru", "koru/X00/power/set", "toggle").unwrap(), (String::from("X00"), Command::Power(POWERCOMMAND::TOGGLE)));
+        assert_eq!(parse_command("koru", "koru/X00/button/set", "Home").unwrap().1, Command::Press(BUTTON::Home));
+        assert_eq!(parse_command("koru", "koru/X00/launch/set", "12").unwrap().1, Command::Launch(String::from("12")));
+        assert_eq!(parse_command("koru", "koru/X00/keys/set", "hi there").unwrap().1, Command::Type(String::from("hi there")));
+        for (topic, payload) in [("koru/X00/power/set", "maybe"), ("koru/X00/button/set", "Nope"), ("koru/X00/launch/set", ""),
+                                 ("koru/X00/volume/set", "10"), ("koru/X00/power", "ON"), ("other/X00/power/set", "ON")] {
+            assert!(matches!(parse_command("koru", topic, payload), Err(KoruError::InvalidArgument(_))), "{} {}", topic, payload);
+        }
+    }
+
+    #[test]
+    fn describes_devices_for_home_assistant() {
+        let messages = discovery_messages(&BridgeOptions::default(), "X00", "Living room", &[]);
+        let topics = messages.iter().map(|(topic, _)| topic.as_str()).collect::<Vec<_>>();
+        assert_eq!(topics[..5], [
+            "homeassistant/switch/koru_X00/power/config",
+            "homeassistant/sensor/koru_X00/app/config",
+            "homeassistant/sensor/koru_X00/playback/config",
+            "homeassistant/text/koru_X00/keys/config",
+            "homeassistant/button/koru_X00/button_home/config",
+        ]);
+        assert_eq!(messages.len(), 4 + REMOTE_BUTTONS.len());
+        let switch = serde_json::from_str::<Value>(&messages[0].1).unwrap();
+        assert_eq!(switch["unique_id"], "koru_X00_power");
+        assert_eq!(switch["command_topic"], "koru/X00/power/set");
+        assert_eq!(switch["availability_topic"], "koru/bridge/availability");
+        assert_eq!(switch["device"]["name"], "Living room");
+        let button = serde_json::from_str::<Value>(&messages[4].1).unwrap();
+        assert_eq!((button["command_topic"].as_str(), button["payload_press"].as_str()), (Some("koru/X00/button/set"), Some("Home")));
+
+        // Once the apps are known, there's a select for launching them
+        let apps = [App {
+            id: AppId::from("12"),
+            apptype: String::from("appl"),
+            subtype: None,
+            version: String::from("4.1.218"),
+            name: String::from("Netflix"),
+            icon: None,
+        }];
+        let messages = discovery_messages(&BridgeOptions::default(), "X00", "Living room", &apps);
+        assert_eq!(messages[4].0, "homeassistant/select/koru_X00/app_launch/config");
+        let select = serde_json::from_str::<Value>(&messages[4].1).unwrap();
+        assert_eq!(select["options"], json!(["Netflix"]));
+        assert_eq!(select["command_topic"], "koru/X00/launch/set");
+    }
+
+    #[test]
+    fn reports_player_state() {
+        let app = App {
+            id: AppId::from("12"),
+            apptype: String::from("appl"),
+            subtype: None,
+            version: String::from("4.1.218"),
+            name: String::from("Netflix"),
+            icon: None,
+        };
+        let mut state = DeviceState {
+            power: POWERSTATE::ON,
+            active_app: Some(ActiveApp::App { app, screensaver: None }),
+            media: Some(MediaPlayerState { state: PlayerState::Pause, ..MediaPlayerState::default() }),
+        };
+        let messages = state_messages("koru", "X00", &state);
+        assert_eq!(messages[0], (String::from("koru/X00/power"), String::from("ON")));
+        let json = serde_json::from_str::<Value>(&messages[1].1).unwrap();
+        assert_eq!((json["state"].as_str(), json["app"].as_str(), json["app_id"].as_str()), (Some("paused"), Some("Netflix"), Some("12")));
+
+        state.media = None;
+        assert_eq!(player_state(&state), "on");
+        state.active_app = Some(ActiveApp::Home { screensaver: None });
+        assert_eq!(player_state(&state), "idle");
+        state.power = POWERSTATE::DISPLAYOFF;
+        assert_eq!(player_state(&state), "standby");
+        state.power = POWERSTATE::OFF;
+        let messages = state_messages("koru", "X00", &state);
+        assert_eq!(messages[0].1, "OFF");
+        assert_eq!(serde_json::from_str::<Value>(&messages[1].1).unwrap()["app"], Value::Null);
+    }
+
+    #[tokio::test]
+    async fn bridges_devices_to_broker() {
+        let broker = MockBroker::start().await.unwrap();
+        let mock = MockDevice::start().await.unwrap();
+        let watcher = DeviceWatcher::with_min_interval(Duration::from_millis(10));
+        let bridge = MqttBridge::start_with_watcher(options(&broker), vec![registered(&mock)], watcher).await.unwrap();
+
+        eventually("discovery", || broker.retained("homeassistant/switch/koru_X00000000000/power/config").is_some()).await;
+        eventually("app select", || broker.retained("homeassistant/select/koru_X00000000000/app_launch/config").is_some()).await;
+        eventually("state", || broker.retained("koru/X00000000000/power").as_deref() == Some("ON")).await;
+        assert_eq!(broker.retained("koru/bridge/availability").as_deref(), Some("online"));
+        let state = serde_json::from_str::<Value>(&broker.retained("koru/X00000000000/state").unwrap()).unwrap();
+        assert_eq!(state["app"], "Home");
+
+        broker.publish("koru/X00000000000/button/set", "Home");
+        eventually("keypress", || mock.keypresses() == ["Home"]).await;
+        broker.publish("koru/X00000000000/keys/set", "hi");
+        eventually("typing", || mock.keypresses().ends_with(&[String::from("Lit_h"), String::from("Lit_i")])).await;
+        broker.publish("koru/X00000000000/launch/set", "12");
+        eventually("launch", || mock.requests().iter().any(|r| r.path == "launch/12")).await;
+        eventually("app state", || broker.retained("koru/X00000000000/state").is_some_and(|state| state.contains("Netflix"))).await;
+        // The app select sends names
+        broker.publish("koru/X00000000000/launch/set", "YouTube");
+        eventually("launch by name", || mock.requests().iter().any(|r| r.path == "launch/837")).await;
+        broker.publish("koru/X00000000000/power/set", "OFF");
+        eventually("power off", || mock.keypresses().last().map(String::as_str) == Some("PowerOff")).await;
+
+        // Bad commands are ignored, not fatal
+        broker.publish("koru/X00000000000/button/set", "Nope");
+        broker.publish("koru/X00000000000/button/set", "Back");
+        eventually("keypress after bad command", || mock.keypresses().last().map(String::as_str) == Some("Back")).await;
+
+        // Going away marks the bridge offline
+        drop(bridge);
+        eventually("last will", || broker.retained("koru/bridge/availability").as_deref() == Some("offline")).await;
+    }
+
+    #[tokio::test]
+    async fn fails_without_broker() {
+        let broker = MockBroker::start().await.unwrap();
+        let options = options(&broker);
+        drop(broker);
+        assert!(MqttBridge::start(options, Vec::new()).await.is_err());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs an MQTT broker on localhost:1883"]
+    async fn bridges_to_local_broker() {
+        let mock = MockDevice::start().await.unwrap();
+        let options = BridgeOptions { client_id: String::from("koru-test"), base_topic: String::from("koru-test"), ..BridgeOptions::default() };
+        let _bridge = MqttBridge::start(options, vec![registered(&mock)]).await.unwrap();
+
+        // Check what landed on the broker from a second client
+        let (client, mut eventloop) = AsyncClient::new(MqttOptions::new("koru-test-reader", "localhost", 1883), 10);
+        client.subscribe("koru-test/X00000000000/power", QoS::AtLeastOnce).await.unwrap();
+        let power = tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                if let Event::Incoming(Packet::Publish(publish)) = eventloop.poll().await.unwrap() {
+                    return String::from_utf8_lossy(&publish.payload).into_owned();
+                }
+            }
+        }).await.expect("no state published");
+        assert_eq!(power, "ON");
+    }
+}
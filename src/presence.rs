@@ -0,0 +1,300 @@
+/// Passive device presence tracking via SSDP NOTIFY messages
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use futures::Stream;
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time::{sleep_until, Instant};
+use tracing::{debug, trace, warn, Instrument};
+use crate::{Device, KoruClient};
+use crate::error::Result;
+use crate::ssdp::{handle_ssdp_notify, listen, SsdpNotify, DEFAULT_BUFFER_SIZE, SSDP_ADDRESS};
+
+// How long to trust an announcement w/o a CACHE-CONTROL header (the minimum UPnP allows)
+const DEFAULT_MAX_AGE: Duration = Duration::from_secs(1800);
+
+/// Settings for presence monitoring
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PresenceOptions {
+    pub address:        SocketAddr,     // Where to listen, e.g. the SSDP multicast group
+    pub buffer_size:    usize,          // Max size of a NOTIFY message (bytes), anything longer gets truncated
+}
+
+impl Default for PresenceOptions {
+    fn default() -> Self {
+        PresenceOptions {
+            address: SocketAddr::from(SSDP_ADDRESS),
+            // NOTIFY messages are about the same size as search responses
+            buffer_size: DEFAULT_BUFFER_SIZE,
+        }
+    }
+}
+
+/// Changes in which devices are present on the network
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PresenceEvent {
+    DeviceAppeared(Device),     // First announcement from a device
+    DeviceUpdated(Device),      // Known device announced a different address or MAC
+    DeviceLost(Device),         // Device said goodbye, or its last announcement expired
+}
+
+impl PresenceEvent {
+    /// Device this event is about
+    pub fn device(&self) -> &Device {
+        match self {
+            PresenceEvent::DeviceAppeared(device) | PresenceEvent::DeviceUpdated(device) | PresenceEvent::DeviceLost(device) => device
+        }
+    }
+}
+
+/// Device we've heard from, and when to give up on it
+#[derive(Clone, Debug)]
+struct Presence {
+    device: Device,
+    expires: Instant,
+}
+
+/// Devices currently announcing themselves, keyed by USN
+type Known = Arc<Mutex<HashMap<String, Presence>>>;
+
+/// Listens for SSDP NOTIFY messages, yielding presence events; dropping this stops listening
+#[derive(Debug)]
+pub struct PresenceMonitor {
+    address: SocketAddr,
+    known: Known,
+    events: mpsc::UnboundedReceiver<PresenceEvent>,
+    task: JoinHandle<()>,
+}
+
+impl PresenceMonitor {
+    /// Listen on the SSDP multicast group
+    pub async fn start() -> Result<PresenceMonitor> {
+        PresenceMonitor::start_with(PresenceOptions::default()).await
+    }
+
+    /// Listen on the given address, joining it if it's a multicast group
+    pub async fn start_on(address: SocketAddr) -> Result<PresenceMonitor> {
+        PresenceMonitor::start_with(PresenceOptions { address, ..PresenceOptions::default() }).await
+    }
+
+    /// Listen with the given options, joining the address if it's a multicast group
+    pub async fn start_with(options: PresenceOptions) -> Result<PresenceMonitor> {
+        let PresenceOptions { address, buffer_size } = options;
+        let socket = listen(address).await?;
+        // Report the group rather than the wildcard address we're bound to
+        let address = if address.ip().is_multicast() { address } else { socket.local_addr()? };
+        let known = Known::default();
+        let (sender, events) = mpsc::unbounded_channel();
+        let span = tracing::info_span!("ssdp_presence", %address);
+        let task = tokio::spawn(monitor(socket, buffer_size, known.clone(), sender).instrument(span));
+        Ok(PresenceMonitor { address, known, events, task })
+    }
+
+    /// Address NOTIFY messages are received on
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    /// Devices currently present
+    pub fn devices(&self) -> Vec<Device> {
+        lock(&self.known).values().map(|presence| presence.device.clone()).collect()
+    }
+}
+
+impl Stream for PresenceMonitor {
+    type Item = PresenceEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<PresenceEvent>> {
+        self.events.poll_recv(cx)
+    }
+}
+
+impl Drop for PresenceMonitor {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Lock known devices, ignoring poisoning (the map is always left consistent)
+fn lock(known: &Known) -> std::sync::MutexGuard<'_, HashMap<String, Presence>> {
+    known.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Turn NOTIFY messages and expiries into events until nobody's listening
+async fn monitor(socket: UdpSocket, buffer_size: usize, known: Known, events: mpsc::UnboundedSender<PresenceEvent>) {
+    // HTTP client shared by all announced devices
+    let client = KoruClient::default();
+    // Buffer for received messages
+    let mut received = vec![0u8; buffer_size];
+
+    loop {
+        let next_expiry = lock(&known).values().map(|presence| presence.expires).min();
+        let event = tokio::select! {
+            result = socket.recv(&mut received) => match result {
+                Ok(num_bytes) => {
+                    if num_bytes == buffer_size {
+                        warn!(buffer_size, "SSDP NOTIFY filled the buffer and may be truncated");
+                    }
+                    // Ignore anything that isn't a Roku NOTIFY, e.g. other devices' searches
+                    match handle_ssdp_notify(&received[..num_bytes]) {
+                        Ok(notify) => apply(&mut lock(&known), notify, &client, Instant::now()),
+                        Err(e) => {
+                            trace!(error = %e, "ignoring SSDP message");
+                            None
+                        }
+                    }
+                }
+                // E.g. an ICMP error from an earlier send; the socket's still good for the next message
+                Err(e) => {
+                    warn!(error = %e, "unable to receive SSDP message");
+                    None
+                }
+            },
+            // Forget devices that haven't renewed their announcement in time
+            _ = sleep_until(next_expiry.unwrap_or_else(Instant::now)), if next_expiry.is_some() => {
+                let now = Instant::now();
+                let mut known = lock(&known);
+                let expired = known.iter().find(|(_, presence)| presence.expires <= now).map(|(usn, _)| usn.clone());
+                expired.and_then(|usn| known.remove(&usn)).map(|presence| PresenceEvent::DeviceLost(presence.device))
+            }
+        };
+        if let Some(event) = event {
+            match &event {
+                PresenceEvent::DeviceAppeared(device) => debug!(device = %device.base_url(), "device appeared"),
+                PresenceEvent::DeviceUpdated(device) => debug!(device = %device.base_url(), "device updated"),
+                PresenceEvent::DeviceLost(device) => debug!(device = %device.base_url(), "device lost"),
+            }
+            if events.send(event).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// Update known devices with a NOTIFY message, returning what changed (if anything)
+fn apply(known: &mut HashMap<String, Presence>, notify: SsdpNotify, client: &KoruClient, now: Instant) -> Option<PresenceEvent> {
+    match notify {
+        SsdpNotify::Alive { usn, device, max_age } => {
+            let expires = now + max_age.unwrap_or(DEFAULT_MAX_AGE);
+            match known.get_mut(&usn) {
+                Some(presence) => {
+                    presence.expires = expires;
+                    // Only the address and MAC come from SSDP, keep anything else we know (e.g. the name)
+                    let mut updated = presence.device.clone();
+                    updated.ipv4 = device.ipv4;
+                    updated.port = device.port;
+                    if device.mac_wlan != [0; 6] {
+                        updated.mac_wlan = device.mac_wlan;
+                        updated.mac_eth = device.mac_eth;
+                    }
+                    if updated == presence.device {
+                        return None;
+                    }
+                    presence.device = updated.clone();
+                    Some(PresenceEvent::DeviceUpdated(updated))
+                }
+                None => {
+                    let device = device.with_client(client.clone());
+                    known.insert(usn, Presence { device: device.clone(), expires });
+                    Some(PresenceEvent::DeviceAppeared(device))
+                }
+            }
+        }
+        SsdpNotify::ByeBye { usn } => known.remove(&usn).map(|presence| PresenceEvent::DeviceLost(presence.device)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::SsdpReply;
+    use futures::StreamExt;
+
+    async fn send(monitor: &PresenceMonitor, message: &str) {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        socket.send_to(message.as_bytes(), monitor.address()).await.unwrap();
+    }
+
+    async fn next(monitor: &mut PresenceMonitor) -> PresenceEvent {
+        tokio::time::timeout(Duration::from_secs(3), monitor.next()).await.expect("no presence event").unwrap()
+    }
+
+    #[test]
+    fn tracks_alive_update_and_byebye() {
+        let client = KoruClient::default();
+        let now = Instant::now();
+        let mut known = HashMap::new();
+        let alive = |location: &str| handle_ssdp_notify(SsdpReply::new("uuid:roku:ecp:A", location).alive().as_bytes()).unwrap();
+
+        let appeared = apply(&mut known, alive("http://10.0.0.1:8060/"), &client, now);
+        assert!(matches!(appeared, Some(PresenceEvent::DeviceAppeared(ref d)) if d.ipv4 == "10.0.0.1"));
+        // Renewing the same announcement changes nothing
+        assert_eq!(apply(&mut known, alive("http://10.0.0.1:8060/"), &client, now), None);
+        // New IP, e.g. after a DHCP lease change
+        let updated = apply(&mut known, alive("http://10.0.0.7:8060/"), &client, now);
+        assert!(matches!(updated, Some(PresenceEvent::DeviceUpdated(ref d)) if d.ipv4 == "10.0.0.7"));
+
+        let byebye = handle_ssdp_notify(SsdpReply::new("uuid:roku:ecp:A", "").byebye().as_bytes()).unwrap();
+        assert!(matches!(apply(&mut known, byebye.clone(), &client, now), Some(PresenceEvent::DeviceLost(ref d)) if d.ipv4 == "10.0.0.7"));
+        // Goodbyes from devices we never saw are ignored
+        assert_eq!(apply(&mut known, byebye, &client, now), None);
+        assert!(known.is_empty());
+    }
+
+    #[test]
+    fn ignores_non_notify_messages() {
+        assert!(handle_ssdp_notify(b"M-SEARCH * HTTP/1.1\r\nST: roku:ecp\r\n\r\n").is_err());
+        assert!(handle_ssdp_notify(b"NOTIFY * HTTP/1.1\r\nNTS: ssdp:alive\r\n\r\n").is_err());
+        assert!(handle_ssdp_notify(b"NOTIFY * HTTP/1.1\r\nUSN: uuid:roku:ecp:A\r\nNTS: ssdp:propchange\r\n\r\n").is_err());
+    }
+
+    #[tokio::test]
+    async fn monitor_emits_events_for_announcements() {
+        let mut monitor = PresenceMonitor::start_on(SocketAddr::from(([127, 0, 0, 1], 0))).await.unwrap();
+        let reply = SsdpReply::new("uuid:roku:ecp:A", "http://10.0.0.1:8060/").wakeup("MAC=d8:31:34:33:2d:7e;Timeout=10");
+
+        send(&monitor, "M-SEARCH * HTTP/1.1\r\nST: roku:ecp\r\n\r\n").await;
+        send(&monitor, &reply.alive()).await;
+        match next(&mut monitor).await {
+            PresenceEvent::DeviceAppeared(device) => assert_eq!(device.mac_wlan, [0xd8, 0x31, 0x34, 0x33, 0x2d, 0x7e]),
+            event => panic!("unexpected {:?}", event)
+        }
+        assert_eq!(monitor.devices().len(), 1);
+
+        send(&monitor, &reply.byebye()).await;
+        assert!(matches!(next(&mut monitor).await, PresenceEvent::DeviceLost(_)));
+        assert!(monitor.devices().is_empty());
+    }
+
+    #[tokio::test]
+    async fn monitor_expires_silent_devices() {
+        let mut monitor = PresenceMonitor::start_on(SocketAddr::from(([127, 0, 0, 1], 0))).await.unwrap();
+        let reply = SsdpReply::new("uuid:roku:ecp:A", "http://10.0.0.1:8060/").max_age(Duration::from_secs(1));
+
+        send(&monitor, &reply.alive()).await;
+        assert!(matches!(next(&mut monitor).await, PresenceEvent::DeviceAppeared(_)));
+        let started = Instant::now();
+        assert!(matches!(next(&mut monitor).await, PresenceEvent::DeviceLost(ref d) if d.ipv4 == "10.0.0.1"));
+        assert!(started.elapsed() >= Duration::from_millis(900));
+    }
+
+    #[tokio::test]
+    async fn long_notifies_need_a_bigger_buffer() {
+        // LOCATION comes after Server, so it's lost if the message gets truncated
+        let chatty = SsdpReply::new("uuid:roku:ecp:A", "http://10.0.0.1:8060/").server(&"Roku/9.2.0 ".repeat(120));
+        let local = SocketAddr::from(([127, 0, 0, 1], 0));
+
+        let mut truncated = PresenceMonitor::start_with(PresenceOptions { address: local, buffer_size: 1024 }).await.unwrap();
+        send(&truncated, &chatty.alive()).await;
+        assert!(tokio::time::timeout(Duration::from_millis(300), truncated.next()).await.is_err());
+
+        let mut monitor = PresenceMonitor::start_on(local).await.unwrap();
+        send(&monitor, &chatty.alive()).await;
+        assert!(matches!(next(&mut monitor).await, PresenceEvent::DeviceAppeared(ref d) if d.ipv4 == "10.0.0.1"));
+    }
+}
@@ -0,0 +1,216 @@
+/// Saved devices, so they can be found (and woken) w/o rediscovering them
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use serde::{Deserialize, Serialize};
+use crate::{Device, DeviceInfo};
+use crate::error::{KoruError, Result};
+
+/// Device the registry knows about
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RegisteredDevice {
+    pub serial_number:  String,
+    pub alias:          Option<String>,         // User-assigned name, e.g. "living-room"
+    pub device:         Device,                 // Last known address, name and MACs
+    pub last_seen:      Option<SystemTime>,     // When we last heard from the device
+}
+
+impl RegisteredDevice {
+    /// Whether this device goes by the given alias, serial number, name or IP address
+    pub fn matches(&self, key: &str) -> bool {
+        self.alias.as_deref() == Some(key)
+            || self.serial_number.eq_ignore_ascii_case(key)
+            || (!self.device.name.is_empty() && self.device.name.eq_ignore_ascii_case(key))
+            || self.device.ipv4 == key
+    }
+}
+
+/// Known devices keyed by serial number, saved to a JSON file
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeviceRegistry {
+    #[serde(skip)]
+    path: Option<PathBuf>,
+    devices: BTreeMap<String, RegisteredDevice>,
+}
+
+impl DeviceRegistry {
+    /// Load a registry from a JSON file, starting empty if the file doesn't exist yet
+    pub async fn load(path: impl AsRef<Path>) -> Result<DeviceRegistry> {
+        let path = path.as_ref().to_path_buf();
+        let mut registry = match tokio::fs::read_to_string(&path).await {
+            Ok(json) => serde_json::from_str::<DeviceRegistry>(&json)
+                .map_err(|e| KoruError::Parse(format!("invalid device registry {}: {}", path.display(), e)))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => DeviceRegistry::default(),
+            Err(e) => return Err(e.into())
+        };
+        registry.path = Some(path);
+        Ok(registry)
+    }
+
+    /// Save the registry back to the file it was loaded from
+    pub async fn save(&self) -> Result<()> {
+        let path = self.path.as_ref()
+            .ok_or_else(|| KoruError::InvalidArgument(String::from("registry wasn't loaded from a file")))?;
+        self.save_to(path).await
+    }
+
+    /// Save the registry to a JSON file
+    pub async fn save_to(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        let json = serde_json::to_string_pretty(self)
+            .map_err(|e| KoruError::Parse(format!("unable to serialize device registry: {}", e)))?;
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            tokio::fs::create_dir_all(dir).await?;
+        }
+        // Write to a temporary file first so a crash can't leave a half-written registry behind
+        let temp = path.with_extension("json.tmp");
+        tokio::fs::write(&temp, json).await?;
+        tokio::fs::rename(&temp, path).await?;
+        Ok(())
+    }
+
+    /// File the registry was loaded from, if any
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    /// Fetch device-info from a device (e.g. a freshly discovered one) and merge it into the registry
+    pub async fn refresh(&mut self, device: &Device) -> Result<&RegisteredDevice> {
+        let info = device.get_info().await?;
+        self.update(device, &info)
+    }
+
+    /// Merge already-fetched device info into the registry, marking the device as seen just now
+    pub fn update(&mut self, device: &Device, info: &DeviceInfo) -> Result<&RegisteredDevice> {
+        let serial_number = info.serial_number.clone()
+            .ok_or_else(|| KoruError::Parse(String::from("device-info has no serial number")))?;
+        let entry = self.devices.entry(serial_number.clone()).or_insert_with(|| RegisteredDevice {
+            serial_number,
+            alias: None,
+            device: Device::default(),
+            last_seen: None
+        });
+        // Keep MACs we already know if the device didn't report them this time (they're needed to wake it)
+        let known = entry.device.clone();
+        entry.device = device.clone();
+        entry.device.update_from_info(info);
+        if entry.device.mac_wlan == [0; 6] {
+            entry.device.mac_wlan = known.mac_wlan;
+        }
+        if entry.device.mac_eth == [0; 6] {
+            entry.device.mac_eth = known.mac_eth;
+        }
+        entry.last_seen = Some(SystemTime::now());
+        Ok(entry)
+    }
+
+    /// Give a device an alias, e.g. "living-room"
+    pub fn set_alias(&mut self, serial_number: &str, alias: &str) -> Result<()> {
+        if self.devices.values().any(|d| d.alias.as_deref() == Some(alias) && d.serial_number != serial_number) {
+            return Err(KoruError::InvalidArgument(format!("alias '{}' is already in use", alias)));
+        }
+        let entry = self.devices.get_mut(serial_number)
+            .ok_or_else(|| KoruError::InvalidArgument(format!("no device with serial number '{}'", serial_number)))?;
+        entry.alias = Some(String::from(alias));
+        Ok(())
+    }
+
+    /// Find a device by alias, serial number, name or IP address (in that order)
+    pub fn find(&self, key: &str) -> Option<&RegisteredDevice> {
+        self.devices.values().find(|d| d.alias.as_deref() == Some(key))
+            .or_else(|| self.devices.values().find(|d| d.matches(key)))
+    }
+
+    /// Get a device by serial number
+    pub fn get(&self, serial_number: &str) -> Option<&RegisteredDevice> {
+        self.devices.get(serial_number)
+    }
+
+    /// Forget a device
+    pub fn remove(&mut self, serial_number: &str) -> Option<RegisteredDevice> {
+        self.devices.remove(serial_number)
+    }
+
+    /// Every known device, ordered by serial number
+    pub fn devices(&self) -> impl Iterator<Item = &RegisteredDevice> {
+        self.devices.values()
+    }
+
+    /// Number of known devices
+    pub fn len(&self) -> usize {
+        self.devices.len()
+    }
+
+    /// Whether the registry is empty
+    pub fn is_empty(&self) -> bool {
+        self.devices.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockDevice;
+    use crate::{NETWORKTYPE, POWERSTATE};
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("koru-registry-{}-{}", name, std::process::id())).join("devices.json")
+    }
+
+    #[test]
+    fn device_serializes_without_client() {
+        let mut device = Device::from_ipv4("192.168.1.134", 8060);
+        device.mac_wlan = [0xd8, 0x31, 0x34, 0x33, 0x2d, 0x7e];
+        device.network = NETWORKTYPE::ETHERNET;
+        let json = serde_json::to_value(&device).unwrap();
+        assert_eq!(json["mac_wlan"], "d8:31:34:33:2d:7e");
+        assert!(json.get("client").is_none());
+        assert_eq!(serde_json::from_value::<Device>(json).unwrap(), device);
+        assert_eq!(serde_json::to_string(&POWERSTATE::DISPLAYOFF).unwrap(), "\"DISPLAYOFF\"");
+    }
+
+    #[tokio::test]
+    async fn persists_devices_and_aliases() {
+        let path = temp_path("persist");
+        let mock = MockDevice::start().await.unwrap();
+
+        let mut registry = DeviceRegistry::load(&path).await.unwrap();
+        assert!(registry.is_empty());
+        registry.refresh(&mock.device()).await.unwrap();
+        registry.set_alias("MOCK00000001", "living-room").unwrap();
+        registry.save().await.unwrap();
+
+        let registry = DeviceRegistry::load(&path).await.unwrap();
+        let saved = registry.find("living-room").unwrap();
+        assert_eq!(saved.device.name, "Mock Roku");
+        assert_eq!(saved.device.port, i32::from(mock.address().port()));
+        assert_eq!(saved.device.mac_wlan, [0x02, 0, 0, 0, 0, 0x01]);
+        assert!(saved.last_seen.is_some());
+        assert_eq!(registry.find("mock roku"), Some(saved));
+        assert_eq!(registry.find("127.0.0.1"), Some(saved));
+
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn merges_new_addresses_and_keeps_known_macs() {
+        let mock = MockDevice::start().await.unwrap();
+        let info = mock.device().get_info().await.unwrap();
+        let mut registry = DeviceRegistry::default();
+        registry.update(&mock.device(), &info).unwrap();
+
+        // Same device on a new IP, not reporting its MACs this time
+        let mut moved = info.clone();
+        moved.wifi_mac = None;
+        moved.ethernet_mac = None;
+        let updated = registry.update(&Device::from_ipv4("10.0.0.7", 8060), &moved).unwrap();
+        assert_eq!(updated.device.ipv4, "10.0.0.7");
+        assert_eq!(updated.device.mac_wlan, [0x02, 0, 0, 0, 0, 0x01]);
+        assert_eq!(registry.len(), 1);
+
+        // Aliases are unique, and only for known devices
+        registry.set_alias("MOCK00000001", "den").unwrap();
+        assert!(registry.set_alias("MOCK00000002", "den").is_err());
+        assert!(matches!(registry.update(&mock.device(), &DeviceInfo::default()), Err(KoruError::Parse(_))));
+    }
+}
@@ -1,21 +1,25 @@
 /// Emulate use of a remote control, and help locate one
-use crate::{Device, client};
+use crate::Device;
+use crate::error::{KoruError, Result};
+use std::fmt;
+use std::str::FromStr;
 use std::time::Duration;
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
 
 /// Adds additional remote-control
 // NOTE: These require the device to be powered on
 impl Device {
     /// Press a button on the remote
     // IMPLEMENTATION NOTE: If implementing a remote UI, it's best to use Device.set_power_state(TOGGLE) instead of sending PowerOn/PowerOff button presses
-    pub async fn press_button(&self, button: BUTTON) -> Result<bool, String> {
-        match client::post(&self.ipv4, &format!("keypress/{}", button.to_string()), None, Duration::new(5, 0)).await {
-            Ok(_) => Ok(true),
-            Err(e) => Err(e.to_string())
-        }
+    pub async fn press_button(&self, button: BUTTON) -> Result<bool> {
+        self.client.post(&self.base_url(), &format!("keypress/{}", button), None, None).await?;
+        Ok(true)
     }
 
     /// Send multiple button presses back-to-back
-    pub async fn press_buttons(&self, buttons: Vec<BUTTON>) -> Result<bool, String> {
+    pub async fn press_buttons(&self, buttons: Vec<BUTTON>) -> Result<bool> {
         let mut result = Ok(true);
         // Send buttons until we reach the end, stop if one doesn't send
         for b in buttons.into_iter() {
@@ -28,19 +32,17 @@ impl Device {
     }
 
     /// Send UTF-8 character literal as though typed on the remote
-    pub async fn press_key(&self, key: char) -> Result<bool, String> {
+    pub async fn press_key(&self, key: char) -> Result<bool> {
         let keycode = format!("Lit_{}", urlencoding::encode(&key.to_string()));
-        match client::post(&self.ipv4, &format!("keypress/{}", keycode), None, Duration::new(5, 0)).await {
-            Ok(_) => Ok(true),
-            Err(e) => Err(e.to_string())
-        }
+        self.client.post(&self.base_url(), &format!("keypress/{}", keycode), None, None).await?;
+        Ok(true)
     }
 
     /// Send UTF-8 string as series of characters as though typed on the remote
-    pub async fn press_keys(&self, input: &str) -> Result<bool, String> {
+    pub async fn press_keys(&self, input: &str) -> Result<bool> {
         let mut result = Ok(true);
         // Send keys until we reach the end, stop if one doesn't send
-        for c in input.chars().into_iter() {
+        for c in input.chars() {
             if let Err(e) = self.press_key(c).await {
                 result = Err(e);
                 break;
@@ -49,10 +51,79 @@ impl Device {
         result
     }
 
-    // TODO: Implement "Find Remote" capability
-    // NOTE: My device is too cheap/old to have this, so not sure how I'm going to test it...
-    pub async fn find_remote(&self) {
-        todo!()
+    /// Make the remote play a sound, if the device supports it
+    pub async fn find_remote(&self) -> Result<bool> {
+        self.check_find_remote().await?;
+        self.press_button(BUTTON::FindRemote).await
+    }
+
+    /// Make the remote play a sound every `interval` until `duration` has passed or the handle is cancelled
+    pub async fn find_remote_repeating(&self, duration: Duration, interval: Duration) -> Result<FindRemoteHandle> {
+        // A zero interval would press FindRemote back to back and never reach the deadline
+        if interval.is_zero() {
+            return Err(KoruError::InvalidArgument(String::from("find remote interval must be greater than zero")));
+        }
+        // Schedule presses from when the first one is sent
+        let start = Instant::now();
+        let deadline = start + duration;
+        // Fail up front rather than from the background task
+        self.find_remote().await?;
+        let (cancel, mut cancelled) = oneshot::channel::<()>();
+        let device = self.clone();
+        let task = tokio::spawn(async move {
+            let mut next = start;
+            loop {
+                // Wait for the next press, stopping early if cancelled or out of time
+                next += interval;
+                if next >= deadline {
+                    return Ok(());
+                }
+                tokio::select! {
+                    _ = &mut cancelled => return Ok(()),
+                    _ = tokio::time::sleep_until(next) => { device.press_button(BUTTON::FindRemote).await?; },
+                }
+            }
+        });
+        Ok(FindRemoteHandle { cancel, task })
+    }
+
+    /// Check device info for find remote support
+    async fn check_find_remote(&self) -> Result<()> {
+        if self.get_info().await?.supports_find_remote {
+            Ok(())
+        } else {
+            Err(KoruError::Unsupported(String::from("find remote")))
+        }
+    }
+}
+
+/// Handle to a repeating find remote, see `Device::find_remote_repeating()`
+#[derive(Debug)]
+pub struct FindRemoteHandle {
+    cancel: oneshot::Sender<()>,
+    task: JoinHandle<Result<()>>,
+}
+
+impl FindRemoteHandle {
+    /// Stop pressing FindRemote, returning the first error if any press failed
+    pub async fn cancel(self) -> Result<()> {
+        // The task may already be done, in which case nobody's listening
+        let _ = self.cancel.send(());
+        join(self.task).await
+    }
+
+    /// Wait for the duration to run out, returning the first error if any press failed
+    pub async fn wait(self) -> Result<()> {
+        join(self.task).await
+    }
+}
+
+/// Wait for a background task to finish
+async fn join(task: JoinHandle<Result<()>>) -> Result<()> {
+    match task.await {
+        Ok(result) => result,
+        // Only happens if the task panicked or the runtime is shutting down
+        Err(e) => Err(KoruError::Io(std::io::Error::other(e)))
     }
 }
 
@@ -89,44 +160,48 @@ pub enum BUTTON {
     PowerOff,       // Requires device support
     PowerOn,        // Undocumented by works on my device (^_^')
 }
-impl ToString for BUTTON {
-    fn to_string(&self) -> String {
-        match self {
-            BUTTON::Back => String::from("Back"),
-            BUTTON::Backspace => String::from("Backspace"),
-            BUTTON::ChannelUp => String::from("ChannelUp"),
-            BUTTON::ChannelDown => String::from("ChannelDown"),
-            BUTTON::Down => String::from("Down"),
-            BUTTON::Enter => String::from("Enter"),
-            BUTTON::FindRemote => String::from("FindRemote"),
-            BUTTON::Fwd => String::from("Fwd"),
-            BUTTON::Home => String::from("Home"),
-            BUTTON::Info => String::from("Info"),
-            BUTTON::InputTuner => String::from("InputTuner"),
-            BUTTON::InputHDMI1 => String::from("InputHDMI1"),
-            BUTTON::InputHDMI2 => String::from("InputHDMI2"),
-            BUTTON::InputHDMI3 => String::from("InputHDMI3"),
-            BUTTON::InputHDMI4 => String::from("InputHDMI4"),
-            BUTTON::InputAV1 => String::from("InputAV1"),
-            BUTTON::InstantReplay => String::from("InstantReplay"),
-            BUTTON::Left => String::from("Left"),
-            BUTTON::Play => String::from("Play"),
-            BUTTON::Rev => String::from("Rev"),
-            BUTTON::Right => String::from("Right"),
-            BUTTON::Search => String::from("Search"),
-            BUTTON::Select => String::from("Select"),
-            BUTTON::Up => String::from("Up"),
-            BUTTON::VolumeDown => String::from("VolumeDown"),
-            BUTTON::VolumeMute => String::from("VolumeMute"),
-            BUTTON::VolumeUp => String::from("VolumeUp"),
-            BUTTON::PowerOff => String::from("PowerOff"),
-            BUTTON::PowerOn => String::from("PowerOn"),
-        }
+impl fmt::Display for BUTTON {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            BUTTON::Back => "Back",
+            BUTTON::Backspace => "Backspace",
+            BUTTON::ChannelUp => "ChannelUp",
+            BUTTON::ChannelDown => "ChannelDown",
+            BUTTON::Down => "Down",
+            BUTTON::Enter => "Enter",
+            BUTTON::FindRemote => "FindRemote",
+            BUTTON::Fwd => "Fwd",
+            BUTTON::Home => "Home",
+            BUTTON::Info => "Info",
+            BUTTON::InputTuner => "InputTuner",
+            BUTTON::InputHDMI1 => "InputHDMI1",
+            BUTTON::InputHDMI2 => "InputHDMI2",
+            BUTTON::InputHDMI3 => "InputHDMI3",
+            BUTTON::InputHDMI4 => "InputHDMI4",
+            BUTTON::InputAV1 => "InputAV1",
+            BUTTON::InstantReplay => "InstantReplay",
+            BUTTON::Left => "Left",
+            BUTTON::Play => "Play",
+            BUTTON::Rev => "Rev",
+            BUTTON::Right => "Right",
+            BUTTON::Search => "Search",
+            BUTTON::Select => "Select",
+            BUTTON::Up => "Up",
+            BUTTON::VolumeDown => "VolumeDown",
+            BUTTON::VolumeMute => "VolumeMute",
+            BUTTON::VolumeUp => "VolumeUp",
+            BUTTON::PowerOff => "PowerOff",
+            BUTTON::PowerOn => "PowerOn",
+        };
+        write!(f, "{}", name)
     }
 }
-impl From<String> for BUTTON {
-    fn from(s: String) -> Self {
-        match s.to_ascii_uppercase().as_str() {
+impl FromStr for BUTTON {
+    type Err = KoruError;
+
+    /// Parse a button name, case-insensitively
+    fn from_str(s: &str) -> Result<Self> {
+        let button = match s.to_ascii_uppercase().as_str() {
             "BACK" => BUTTON::Back,
             "BACKSPACE" => BUTTON::Backspace,
             "CHANNELUP" => BUTTON::ChannelUp,
@@ -155,7 +230,94 @@ impl From<String> for BUTTON {
             "VOLUMEMUTE" => BUTTON::VolumeMute,
             "VOLUMEUP" => BUTTON::VolumeUp,
             "POWEROFF" => BUTTON::PowerOff,
-            _ => BUTTON::PowerOn
-        }
+            "POWERON" => BUTTON::PowerOn,
+            _ => return Err(KoruError::InvalidArgument(format!("unknown button '{}'", s)))
+        };
+        Ok(button)
+    }
+}
+impl From<String> for BUTTON {
+    fn from(s: String) -> Self {
+        // Assume PowerOn if we're not sure
+        s.parse().unwrap_or(BUTTON::PowerOn)
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use warp::Filter;
+
+    async fn mock_device(supports_find_remote: bool) -> (Device, Arc<Mutex<Vec<String>>>) {
+        let keypresses = Arc::new(Mutex::new(Vec::new()));
+        let info = warp::get()
+            .and(warp::path!("query" / "device-info"))
+            .map(move || format!("<device-info><supports-find-remote>{}</supports-find-remote></device-info>", supports_find_remote));
+        let recorded = keypresses.clone();
+        let keypress = warp::post()
+            .and(warp::path!("keypress" / String))
+            .map(move |key: String| {
+                recorded.lock().unwrap().push(key);
+                ""
+            });
+        let (address, server) = warp::serve(info.or(keypress))
+            .try_bind_ephemeral(([127, 0, 0, 1], 0))
+            .expect("unable to bind mock device");
+        tokio::spawn(server);
+        (Device::from_ipv4(&address.ip().to_string(), i32::from(address.port())), keypresses)
+    }
+
+    #[test]
+    fn parses_button_names_strictly() {
+        assert_eq!("volumeup".parse::<BUTTON>().unwrap(), BUTTON::VolumeUp);
+        assert_eq!("PowerOn".parse::<BUTTON>().unwrap(), BUTTON::PowerOn);
+        assert!(matches!("Sideways".parse::<BUTTON>(), Err(KoruError::InvalidArgument(_))));
+        // From<String> still falls back to PowerOn
+        assert_eq!(BUTTON::from(String::from("Sideways")), BUTTON::PowerOn);
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn find_remote_requires_support() {
+        let (device, keypresses) = mock_device(false).await;
+        assert!(matches!(device.find_remote().await, Err(KoruError::Unsupported(_))));
+        assert!(matches!(device.find_remote_repeating(Duration::from_secs(1), Duration::from_millis(100)).await, Err(KoruError::Unsupported(_))));
+        assert!(keypresses.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn find_remote_rejects_zero_interval() {
+        let (device, keypresses) = mock_device(true).await;
+        let result = device.find_remote_repeating(Duration::from_secs(1), Duration::ZERO).await;
+        assert!(matches!(result, Err(KoruError::InvalidArgument(_))));
+        assert!(keypresses.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn find_remote_presses_find_remote() {
+        let (device, keypresses) = mock_device(true).await;
+        assert!(device.find_remote().await.unwrap());
+        assert_eq!(*keypresses.lock().unwrap(), vec![String::from("FindRemote")]);
+    }
+
+    #[tokio::test]
+    async fn find_remote_repeats_until_duration() {
+        let (device, keypresses) = mock_device(true).await;
+        let handle = device.find_remote_repeating(Duration::from_millis(250), Duration::from_millis(100)).await.unwrap();
+        handle.wait().await.unwrap();
+        // Initial press, then at 100ms and 200ms
+        assert_eq!(keypresses.lock().unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn find_remote_stops_when_cancelled() {
+        let (device, keypresses) = mock_device(true).await;
+        let handle = device.find_remote_repeating(Duration::from_secs(60), Duration::from_millis(100)).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        handle.cancel().await.unwrap();
+        let pressed = keypresses.lock().unwrap().len();
+        assert!(pressed >= 1);
+        // Nothing else gets pressed after cancelling
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert_eq!(keypresses.lock().unwrap().len(), pressed);
+    }
+}
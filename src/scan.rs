@@ -0,0 +1,150 @@
+/// Discovery by probing hosts directly, for networks that drop multicast
+use std::collections::HashSet;
+use std::net::Ipv4Addr;
+use std::str::FromStr;
+use std::time::Duration;
+use futures::stream::{self, StreamExt};
+use ipnet::Ipv4Net;
+use tracing::{debug, Instrument};
+use crate::{ClientConfig, Device, KoruClient, ECP_PORT};
+use crate::error::Result;
+
+// Where Linux exposes the ARP/neighbor table
+const ARP_TABLE: &str = "/proc/net/arp";
+
+/// Settings for scanning the network for devices
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ScanOptions {
+    pub subnets:        Vec<Ipv4Net>,   // Ranges to probe, e.g. 192.168.1.0/24
+    pub arp:            bool,           // Also probe every host in the ARP table (Linux only)
+    pub port:           u16,            // Port to probe for the ECP API
+    pub concurrency:    usize,          // Max hosts to probe at once
+    pub timeout:        Duration,       // Time allowed for each host to accept the connection
+}
+
+impl Default for ScanOptions {
+    fn default() -> Self {
+        ScanOptions {
+            subnets: Vec::new(),
+            arp: false,
+            port: ECP_PORT as u16,
+            concurrency: 64,
+            timeout: Duration::from_millis(500),
+        }
+    }
+}
+
+impl ScanOptions {
+    /// Probe every host in the given subnet
+    pub fn subnet(subnet: Ipv4Net) -> ScanOptions {
+        ScanOptions { subnets: vec![subnet], ..ScanOptions::default() }
+    }
+
+    /// Probe every host in the ARP table
+    pub fn arp() -> ScanOptions {
+        ScanOptions { arp: true, ..ScanOptions::default() }
+    }
+}
+
+/// Discover Roku devices by probing each host for the ECP API, then return the ones that answered
+/// NOTE: Unlike SSDP discovery, every device returned has already been updated from its device-info.
+pub async fn scan_devices(options: &ScanOptions) -> Result<Vec<Device>> {
+    // Collect hosts to probe, w/o probing any twice
+    let mut hosts: Vec<Ipv4Addr> = options.subnets.iter().flat_map(|subnet| subnet.hosts()).collect();
+    if options.arp {
+        let table = tokio::fs::read_to_string(ARP_TABLE).await?;
+        hosts.extend(parse_arp_table(&table));
+    }
+    let mut seen = HashSet::new();
+    hosts.retain(|host| seen.insert(*host));
+
+    // Don't wait around on hosts that aren't listening, but give the ones that are a normal amount of time
+    let probe_client = KoruClient::new(ClientConfig { connect_timeout: options.timeout, ..ClientConfig::default() })?;
+    // HTTP client shared by all discovered devices
+    let client = KoruClient::default();
+
+    let span = tracing::info_span!("scan", hosts = hosts.len(), port = options.port);
+    let found = stream::iter(hosts)
+        .map(|host| probe(host, options.port, probe_client.clone()))
+        .buffer_unordered(options.concurrency.max(1))
+        .filter_map(|found| async { found })
+        .collect::<Vec<_>>()
+        .instrument(span)
+        .await;
+
+    // Devices on both Wi-Fi and Ethernet answer on both addresses
+    let mut serials = HashSet::new();
+    Ok(found.into_iter()
+        .filter(|(_, serial)| serials.insert(serial.clone()))
+        .map(|(device, _)| device.with_client(client.clone()))
+        .collect())
+}
+
+/// Check whether a host is a Roku device, returning it (and its serial number) if so
+async fn probe(host: Ipv4Addr, port: u16, client: KoruClient) -> Option<(Device, String)> {
+    let mut device = Device::from_ipv4(&host.to_string(), i32::from(port)).with_client(client);
+    let info = device.get_info().await.ok()?;
+    // Anything can listen on the port, but only devices have a serial number in their device-info
+    let serial = info.serial_number.clone()?;
+    debug!(%host, %serial, "found device");
+    device.update_from_info(&info);
+    Some((device, serial))
+}
+
+/// Parse the addresses of complete entries in the ARP table
+fn parse_arp_table(table: &str) -> Vec<Ipv4Addr> {
+    table.lines()
+        // Skip the header
+        .skip(1)
+        .filter_map(|line| {
+            let columns = line.split_whitespace().collect::<Vec<_>>();
+            match columns.as_slice() {
+                // Incomplete entries have a flags value of 0x0 and an all-zero MAC
+                [ip, _, flags, mac, ..] if *flags != "0x0" && *mac != "00:00:00:00:00:00" => Ipv4Addr::from_str(ip).ok(),
+                _ => None
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockDevice;
+    use warp::Filter;
+
+    #[test]
+    fn parses_arp_table() {
+        let table = "IP address       HW type     Flags       HW address            Mask     Device\n\
+                     192.168.1.1      0x1         0x2         a0:b1:c2:d3:e4:f5     *        wlan0\n\
+                     192.168.1.134    0x1         0x2         d8:31:34:33:2d:7e     *        wlan0\n\
+                     192.168.1.77     0x1         0x0         00:00:00:00:00:00     *        wlan0\n\
+                     garbage\n";
+        assert_eq!(parse_arp_table(table), vec![Ipv4Addr::new(192, 168, 1, 1), Ipv4Addr::new(192, 168, 1, 134)]);
+        assert!(parse_arp_table("").is_empty());
+    }
+
+    #[tokio::test]
+    async fn finds_devices_in_subnet() {
+        let mock = MockDevice::start().await.unwrap();
+        // 127.0.0.1 is the mock, 127.0.0.2 isn't listening
+        let options = ScanOptions { port: mock.address().port(), ..ScanOptions::subnet("127.0.0.0/30".parse().unwrap()) };
+
+        let devices = scan_devices(&options).await.unwrap();
+        assert_eq!(devices.len(), 1);
+        assert_eq!(devices[0].ipv4, "127.0.0.1");
+        assert_eq!(devices[0].name, "Mock Roku");
+        assert_eq!(devices[0].mac_wlan, [0x02, 0, 0, 0, 0, 0x01]);
+        assert_eq!(mock.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn ignores_other_servers_on_the_port() {
+        let (address, server) = warp::serve(warp::any().map(|| "<html><body>Not a Roku</body></html>"))
+            .try_bind_ephemeral(([127, 0, 0, 1], 0))
+            .expect("unable to bind mock server");
+        tokio::spawn(server);
+        let options = ScanOptions { port: address.port(), ..ScanOptions::subnet("127.0.0.1/32".parse().unwrap()) };
+        assert!(scan_devices(&options).await.unwrap().is_empty());
+    }
+}
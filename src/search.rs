@@ -0,0 +1,168 @@
+/// Search for content with the search/browse endpoint
+use std::fmt;
+use crate::{AppId, Device};
+use crate::error::{KoruError, Result};
+
+/// Kinds of content to search for
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SearchType {
+    Movie,
+    TvShow,
+    Person,
+    Channel,
+    Game,
+}
+
+impl fmt::Display for SearchType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SearchType::Movie => write!(f, "movie"),
+            SearchType::TvShow => write!(f, "tv-show"),
+            SearchType::Person => write!(f, "person"),
+            SearchType::Channel => write!(f, "channel"),
+            SearchType::Game => write!(f, "game"),
+        }
+    }
+}
+
+/// Search query, at least one of `keyword` or `title` is required
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SearchQuery {
+    pub keyword: Option<String>,        // Content title, person or channel name, etc.
+    pub title: Option<String>,          // Exact content title
+    pub search_type: Option<SearchType>,
+    pub season: Option<u32>,            // TV shows only
+    pub tmsid: Option<String>,          // Gracenote TMS id, e.g. MV000113148000
+    pub provider_ids: Vec<AppId>,       // Preferred apps to play the content in
+    pub show_unavailable: bool,         // Include content that isn't available yet
+    pub launch: bool,                   // Launch the first provider automatically
+    pub match_any: bool,                // Launch the first matching result even if ambiguous
+}
+
+impl SearchQuery {
+    /// Search by keyword
+    pub fn keyword(keyword: impl Into<String>) -> SearchQuery {
+        SearchQuery { keyword: Some(keyword.into()), ..SearchQuery::default() }
+    }
+
+    /// Search by exact title
+    pub fn title(title: impl Into<String>) -> SearchQuery {
+        SearchQuery { title: Some(title.into()), ..SearchQuery::default() }
+    }
+
+    /// Restrict results to one type of content
+    pub fn search_type(mut self, search_type: SearchType) -> SearchQuery {
+        self.search_type = Some(search_type);
+        self
+    }
+
+    /// Select a season of a TV show
+    pub fn season(mut self, season: u32) -> SearchQuery {
+        self.season = Some(season);
+        self
+    }
+
+    /// Identify content by its TMS id
+    pub fn tmsid(mut self, tmsid: impl Into<String>) -> SearchQuery {
+        self.tmsid = Some(tmsid.into());
+        self
+    }
+
+    /// Prefer an app for playing the content, in order of preference
+    pub fn provider_id(mut self, app_id: impl Into<AppId>) -> SearchQuery {
+        self.provider_ids.push(app_id.into());
+        self
+    }
+
+    /// Include content that isn't available yet
+    pub fn show_unavailable(mut self, show_unavailable: bool) -> SearchQuery {
+        self.show_unavailable = show_unavailable;
+        self
+    }
+
+    /// Launch the content in the first available provider
+    pub fn launch(mut self, launch: bool) -> SearchQuery {
+        self.launch = launch;
+        self
+    }
+
+    /// Launch even if the search matches more than one result
+    pub fn match_any(mut self, match_any: bool) -> SearchQuery {
+        self.match_any = match_any;
+        self
+    }
+
+    /// URL-encoded search/browse endpoint for this query
+    pub fn endpoint(&self) -> Result<String> {
+        let mut query = Vec::new();
+        let mut push = |key: &str, value: &str| query.push(format!("{}={}", key, urlencoding::encode(value)));
+        if self.keyword.is_none() && self.title.is_none() {
+            return Err(KoruError::InvalidArgument(String::from("search needs a keyword or title")));
+        }
+        if let Some(keyword) = &self.keyword {
+            push("keyword", keyword);
+        }
+        if let Some(title) = &self.title {
+            push("title", title);
+        }
+        if let Some(search_type) = &self.search_type {
+            push("type", &search_type.to_string());
+        }
+        if let Some(season) = self.season {
+            push("season", &season.to_string());
+        }
+        if let Some(tmsid) = &self.tmsid {
+            push("tmsid", tmsid);
+        }
+        if !self.provider_ids.is_empty() {
+            push("provider-id", &self.provider_ids.iter().map(AppId::as_str).collect::<Vec<_>>().join(","));
+        }
+        if self.show_unavailable {
+            push("show-unavailable", "true");
+        }
+        if self.launch {
+            push("launch", "true");
+        }
+        if self.match_any {
+            push("match-any", "true");
+        }
+        Ok(format!("search/browse?{}", query.join("&")))
+    }
+}
+
+/// Adds content search
+impl Device {
+    /// Open search results for a query, or jump straight to the content if `launch` is set
+    pub async fn search(&self, query: &SearchQuery) -> Result<bool> {
+        self.client.post(&self.base_url(), &query.endpoint()?, None, None).await?;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn query_needs_keyword_or_title() {
+        assert!(matches!(SearchQuery::default().endpoint(), Err(KoruError::InvalidArgument(_))));
+        assert_eq!(SearchQuery::keyword("The Office").endpoint().unwrap(), "search/browse?keyword=The%20Office");
+    }
+
+    #[test]
+    fn query_includes_every_option() {
+        let query = SearchQuery::title("The Office")
+            .search_type(SearchType::TvShow)
+            .season(2)
+            .tmsid("SH007247740000")
+            .provider_id(12)
+            .provider_id("2285")
+            .show_unavailable(true)
+            .launch(true)
+            .match_any(true);
+        assert_eq!(
+            query.endpoint().unwrap(),
+            "search/browse?title=The%20Office&type=tv-show&season=2&tmsid=SH007247740000&provider-id=12%2C2285&show-unavailable=true&launch=true&match-any=true"
+        );
+    }
+}
@@ -1,119 +1,388 @@
+use std::collections::HashSet;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
 use std::time::Duration;
-use crate::Device;
-use std::io::Error;
-use async_std::net::UdpSocket;
+use crate::{Device, KoruClient};
+use crate::error::{KoruError, Result};
+use std::net::{Ipv4Addr, SocketAddr};
+use futures::{Stream, StreamExt};
+use ipnet::Ipv4Net;
 use regex::Regex;
+use socket2::{Domain, Protocol, Socket, Type};
 use std::str::FromStr;
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tokio::task::{JoinHandle, JoinSet};
+use tokio::time::{sleep_until, Instant};
+use tracing::{debug, trace, warn, Instrument};
 
-/// Parsing and handling of SSDP messages for device discovery
+// Parsing and handling of SSDP messages for device discovery
 
-// SSDP response buffer length (bytes)
-// Responses _should_ fit within 1024 bytes.
-// e.g. My device returns a message longer than the one in the documentation, but only 267 bytes.
-const BUFLEN: usize = 1024;
+// SSDP multicast address
+pub(crate) const SSDP_ADDRESS: ([u8; 4], u16) = ([239, 255, 255, 250], 1900);
+
+// Roku responses are a few hundred bytes (e.g. 267 from my device), but a long SERVER header or extra headers can
+// push a message past 1024 bytes and cut off LOCATION. 2048 leaves room for those, and a bit more than a full
+// Ethernet frame. Anything that still fills the buffer gets logged.
+pub(crate) const DEFAULT_BUFFER_SIZE: usize = 2048;
+
+/// Settings for SSDP discovery
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DiscoveryOptions {
+    pub address:            SocketAddr,     // Where to send searches, e.g. the SSDP multicast group
+    pub unicast:            Vec<Ipv4Net>,   // Search these hosts/subnets directly (on the address's port) instead, e.g. 192.168.1.134/32
+    pub interfaces:         Vec<Ipv4Addr>,  // Local addresses to search from, or none to let the OS pick
+    pub search_target:      String,         // ST header, e.g. roku:ecp or ssdp:all
+    pub mx:                 Option<u32>,    // MX header, i.e. max seconds devices should wait before responding
+    pub ttl:                u32,            // Multicast TTL, i.e. how many routers searches may cross
+    pub timeout:            Duration,       // How long to keep listening after the last search
+    pub searches:           u32,            // How many M-SEARCH messages to send (UDP is lossy), at least one
+    pub search_interval:    Duration,       // Time between searches
+    pub buffer_size:        usize,          // Max size of a response (bytes), anything longer gets truncated
+    pub enrich:             bool,           // Fetch device-info for each device before yielding it
+}
+
+impl Default for DiscoveryOptions {
+    fn default() -> Self {
+        DiscoveryOptions {
+            address: SocketAddr::from(SSDP_ADDRESS),
+            unicast: Vec::new(),
+            interfaces: Vec::new(),
+            search_target: String::from("roku:ecp"),
+            // Roku's docs leave MX out, and devices answer right away regardless
+            mx: None,
+            // Recommended by UPnP
+            ttl: 2,
+            timeout: Duration::new(3, 0),
+            searches: 3,
+            search_interval: Duration::from_millis(500),
+            buffer_size: DEFAULT_BUFFER_SIZE,
+            enrich: false,
+        }
+    }
+}
+
+impl DiscoveryOptions {
+    /// Addresses each search is sent to
+    fn targets(&self) -> Vec<SocketAddr> {
+        if self.unicast.is_empty() {
+            return vec![self.address];
+        }
+        self.unicast.iter()
+            .flat_map(|net| net.hosts())
+            .map(|host| SocketAddr::from((host, self.address.port())))
+            .collect()
+    }
+}
 
 /// Discover Roku devices on the network via SSDP
-pub async fn discover_devices(timeout: Duration) -> Result<Vec<Device>, Error> {
+pub async fn discover_devices(timeout: Duration) -> Result<Vec<Device>> {
+    let options = DiscoveryOptions { timeout, searches: 1, ..DiscoveryOptions::default() };
+    Ok(discover_stream(options).await?.collect().await)
+}
 
-    // List of devices
-    let mut devices: Vec<Device> = Vec::new();
+/// Discover Roku devices via SSDP, yielding each one as soon as it responds
+/// NOTE: Devices are only yielded once, even if they answer several searches.
+pub async fn discover_stream(options: DiscoveryOptions) -> Result<DiscoveryStream> {
+    // Create a socket per interface
+    let interfaces = if options.interfaces.is_empty() { vec![Ipv4Addr::UNSPECIFIED] } else { options.interfaces.clone() };
+    let sockets = interfaces.iter()
+        .map(|interface| search_socket(*interface, options.ttl).map(Arc::new))
+        .collect::<std::io::Result<Vec<_>>>()?;
 
-    // SSDP multicast address
-    let address = "239.255.255.250:1900";
+    // Send the first search up front so socket errors aren't lost in the background task
+    send_searches(&sockets, &options).await?;
 
-    // SSDP discover HTTPU message
-    let message = format!(r#"M-SEARCH * HTTP/1.1
-Host: {}
-Man: "ssdp:discover"
-ST: roku:ecp
- "#, address);
+    let (sender, devices) = mpsc::unbounded_channel();
+    let span = tracing::info_span!("ssdp_discovery", search_target = %options.search_target, targets = options.targets().len(), sockets = sockets.len());
+    let task = tokio::spawn(run_discovery(sockets, options, sender).instrument(span));
+    Ok(DiscoveryStream { devices, task })
+}
+
+/// Devices found by `discover_stream()`; dropping this stops discovery
+#[derive(Debug)]
+pub struct DiscoveryStream {
+    devices: mpsc::UnboundedReceiver<Device>,
+    task: JoinHandle<()>,
+}
 
-    // Create socket
-    let socket = UdpSocket::bind("0.0.0.0:0").await?;
+impl DiscoveryStream {
+    /// Stop discovery early; devices already found are still yielded
+    pub fn cancel(&self) {
+        self.task.abort();
+    }
+}
+
+impl Stream for DiscoveryStream {
+    type Item = Device;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Device>> {
+        self.devices.poll_recv(cx)
+    }
+}
+
+impl Drop for DiscoveryStream {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Bind a socket to search from, sending multicast out of the given interface
+fn search_socket(interface: Ipv4Addr, ttl: u32) -> std::io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
     socket.set_broadcast(true)?;
+    socket.set_multicast_ttl_v4(ttl)?;
+    if !interface.is_unspecified() {
+        socket.set_multicast_if_v4(&interface)?;
+    }
+    socket.bind(&SocketAddr::from((interface, 0)).into())?;
+    socket.set_nonblocking(true)?;
+    UdpSocket::from_std(socket.into())
+}
 
-    // Send the multicast message
-    socket.send_to(message.as_bytes(), address).await?;
+/// SSDP discover HTTPU message
+fn search_message(host: &SocketAddr, options: &DiscoveryOptions) -> String {
+    let mx = options.mx.map(|mx| format!("MX: {}\r\n", mx)).unwrap_or_default();
+    format!("M-SEARCH * HTTP/1.1\r\nHost: {}\r\nMan: \"ssdp:discover\"\r\n{}ST: {}\r\n\r\n", host, mx, options.search_target)
+}
 
-    // Handle responses
-    loop {
-        // Buffer for received message
-        let received: &mut [u8; BUFLEN] = &mut [0u8; BUFLEN];
-        // Wait for responses with timeout
-        match tokio::time::timeout(timeout, socket.recv(received)).await {
-            // Handle awaiting response until timeout
-            Ok(result) => {
-                // Handle receiving response
-                match result {
-                    Ok(num_bytes) => {
-                        // Check if we received the same amount of bytes as the buffer (indicating the buffer probably isn't long enough)
-                        if num_bytes == BUFLEN {
-                            // TODO: Should we handle handle SSDP responses > 1024 mb? Could they be from a Roku?
-                            println!("[!] WARNING: SSDP message buffer may be too small.")
-                        }
-                        // If we can parse a Device from the message, push it to the output vec
-                        if let Some(device) = handle_ssdp_response(&received[..num_bytes]) {
-                            devices.push(device)
-                        }
+/// Send a search to every target from every socket, failing only if none of them could be sent
+// Note: some unicast targets failing is normal, e.g. the network and broadcast addresses of a subnet
+async fn send_searches(sockets: &[Arc<UdpSocket>], options: &DiscoveryOptions) -> std::io::Result<()> {
+    let mut result = Ok(());
+    let mut sent_any = false;
+    for target in options.targets() {
+        let message = search_message(&target, options);
+        for socket in sockets {
+            match socket.send_to(message.as_bytes(), target).await {
+                Ok(_) => sent_any = true,
+                Err(e) => result = Err(e)
+            }
+        }
+    }
+    if sent_any { Ok(()) } else { result }
+}
+
+/// Send remaining searches and pass along new devices until the timeout runs out (or nobody's listening)
+async fn run_discovery(sockets: Vec<Arc<UdpSocket>>, options: DiscoveryOptions, devices: mpsc::UnboundedSender<Device>) {
+    // HTTP client shared by all discovered devices
+    let client = KoruClient::default();
+    // USNs (or locations, for responses w/o one) and serial numbers already seen
+    let mut seen: HashSet<String> = HashSet::new();
+    let mut serials: HashSet<String> = HashSet::new();
+    // Devices waiting on device-info
+    let mut enriching = JoinSet::new();
+
+    // Funnel responses from every socket into one channel
+    let (responses, mut received) = mpsc::unbounded_channel();
+    let mut readers = JoinSet::new();
+    for socket in &sockets {
+        let socket = socket.clone();
+        let responses = responses.clone();
+        let buffer_size = options.buffer_size;
+        readers.spawn(async move {
+            let mut buffer = vec![0u8; buffer_size];
+            while let Ok(num_bytes) = socket.recv(&mut buffer).await {
+                if num_bytes == buffer_size {
+                    warn!(buffer_size, "SSDP response filled the buffer and may be truncated");
+                }
+                if responses.send(buffer[..num_bytes].to_vec()).is_err() {
+                    break;
+                }
+            }
+        }.in_current_span());
+    }
 
+    // The first search was already sent
+    let mut sent = 1;
+    let mut next_search = Instant::now() + options.search_interval;
+    let mut deadline = Instant::now() + options.timeout;
+
+    loop {
+        tokio::select! {
+            // Repeat the search
+            _ = sleep_until(next_search), if sent < options.searches => {
+                // A lost search isn't fatal, that's why we repeat them
+                if let Err(e) = send_searches(&sockets, &options).await {
+                    debug!(error = %e, "unable to repeat search");
+                }
+                sent += 1;
+                debug!(search = sent, "repeated search");
+                next_search = Instant::now() + options.search_interval;
+                deadline = Instant::now() + options.timeout;
+            }
+            // Handle responses
+            Some(raw) = received.recv() => {
+                // If we can parse a Device from the message, pass it along (ignore anything else)
+                let device = match handle_ssdp_response(&raw) {
+                    Ok(device) => device,
+                    Err(e) => {
+                        trace!(error = %e, "ignoring SSDP response");
+                        continue;
                     }
-                    Err(_) => {}
+                };
+                let key = parse_ssdp_usn(&String::from_utf8_lossy(&raw)).unwrap_or_else(|| device.base_url());
+                if !seen.insert(key.clone()) {
+                    trace!(usn = %key, "ignoring repeated SSDP response");
+                    continue;
+                }
+                debug!(usn = %key, device = %device.base_url(), "device responded");
+                let device = device.with_client(client.clone());
+                if options.enrich {
+                    enriching.spawn(enrich(device).in_current_span());
+                } else if devices.send(device).is_err() {
+                    return;
+                }
+            }
+            // Pass along enriched devices, unless they turn out to be a duplicate (e.g. on both Wi-Fi and Ethernet)
+            Some(Ok((device, serial))) = enriching.join_next() => {
+                if serial.map(|s| serials.insert(s)).unwrap_or(true) && devices.send(device).is_err() {
+                    return;
                 }
             }
-            // Break loop on socket read timeout
-            Err(_) => break
+            // Stop listening once the last search has timed out
+            _ = sleep_until(deadline), if sent >= options.searches => break
         }
     }
-    Ok(devices)
+    debug!(found = seen.len(), "search timed out");
+
+    // Wait for in-flight device-info requests
+    while let Some(result) = enriching.join_next().await {
+        if let Ok((device, serial)) = result {
+            if serial.map(|s| serials.insert(s)).unwrap_or(true) && devices.send(device).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// Bind a socket to receive SSDP messages on, joining the address if it's a multicast group
+pub(crate) async fn listen(address: SocketAddr) -> std::io::Result<UdpSocket> {
+    match address {
+        SocketAddr::V4(v4) if v4.ip().is_multicast() => {
+            // Share the port with anything else listening on the group (e.g. the OS's own SSDP stack)
+            let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+            socket.set_reuse_address(true)?;
+            socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, v4.port())).into())?;
+            socket.join_multicast_v4(v4.ip(), &Ipv4Addr::UNSPECIFIED)?;
+            socket.set_nonblocking(true)?;
+            UdpSocket::from_std(socket.into())
+        }
+        _ => UdpSocket::bind(address).await
+    }
+}
+
+/// Fill in a discovered device's details, returning its serial number if we could get it
+async fn enrich(mut device: Device) -> (Device, Option<String>) {
+    match device.get_info().await {
+        Ok(info) => {
+            device.update_from_info(&info);
+            (device, info.serial_number)
+        }
+        // Still worth reporting since it did answer the search
+        Err(e) => {
+            debug!(device = %device.base_url(), error = %e, "unable to get device-info for discovered device");
+            (device, None)
+        }
+    }
+}
+
+/// Announcement a device multicasts on its own, i.e. w/o being searched for
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum SsdpNotify {
+    Alive { usn: String, device: Device, max_age: Option<Duration> },    // ssdp:alive (or ssdp:update)
+    ByeBye { usn: String },                                             // ssdp:byebye
+}
+
+/// Handler for SSDP NOTIFY messages
+pub(crate) fn handle_ssdp_notify(raw: &[u8]) -> Result<SsdpNotify> {
+    // Parse message bytes into string
+    let message = std::str::from_utf8(raw)
+        .map_err(|_| KoruError::Parse(String::from("SSDP message is not valid UTF-8")))?;
+    if !message.starts_with("NOTIFY ") {
+        return Err(KoruError::Parse(String::from("SSDP message is not a NOTIFY")));
+    }
+    let usn = parse_ssdp_usn(message)
+        .ok_or_else(|| KoruError::Parse(String::from("SSDP NOTIFY has no USN header")))?;
+    match parse_ssdp_header(message, "NTS").as_deref() {
+        // Goodbyes usually don't have a LOCATION (or anything else), so the USN has to do
+        Some("ssdp:byebye") => Ok(SsdpNotify::ByeBye { usn }),
+        Some("ssdp:alive") | Some("ssdp:update") => Ok(SsdpNotify::Alive {
+            usn,
+            device: handle_ssdp_response(raw)?,
+            max_age: parse_ssdp_max_age(message)
+        }),
+        _ => Err(KoruError::Parse(String::from("SSDP NOTIFY has no valid NTS header")))
+    }
 }
 
 /// Handler for SSDP responses
-fn handle_ssdp_response(raw: &[u8]) -> Option<Device>{
-    // Parse message bytes into string, fail silently
-    let message = std::str::from_utf8(raw).unwrap_or("");
+pub(crate) fn handle_ssdp_response(raw: &[u8]) -> Result<Device> {
+    // Parse message bytes into string
+    let message = std::str::from_utf8(raw)
+        .map_err(|_| KoruError::Parse(String::from("SSDP response is not valid UTF-8")))?;
 
     // Case-insensitive match for "roku"
     let roku_regex: Regex = Regex::new(r".*[rR]oku.*").unwrap();
 
     // TODO: Should we validate SSDP responses more thoroughly?
     // Continue only if this response even contains the string "roku"
-    if roku_regex.is_match(message) {
-        // Continue only if we can parse the location response
-        match parse_ssdp_location(message) {
-            Some((ipv4, port)) => {
-                // Create bare-bones device
-                let mut device = Device::from_ipv4(&ipv4, port);
-                // If there's a MAC address listed in the WAKEUP header, parse that too
-                if let Some(mac) = parse_ssdp_mac(message) {
-                    // NOTE: There's no way to know the interface, so assign the MAC to both
-                    device.mac_wlan = mac.clone();
-                    device.mac_eth = mac;
-                }
-                // Return this device
-                Some(device)
-            }
-            None => None
-        }
-    } else {
-        // Couldn't match $roku_regex
-        None
+    if !roku_regex.is_match(message) {
+        return Err(KoruError::Parse(String::from("SSDP response is not from a Roku device")));
     }
+    // Continue only if we can parse the location response
+    let (ipv4, port) = parse_ssdp_location(message)?;
+    // Create bare-bones device
+    let mut device = Device::from_ipv4(&ipv4, port);
+    // If there's a MAC address listed in the WAKEUP header, parse that too
+    if let Some(mac) = parse_ssdp_mac(message)? {
+        // NOTE: There's no way to know the interface, so assign the MAC to both
+        device.mac_wlan = mac;
+        device.mac_eth = mac;
+    }
+    // Return this device
+    Ok(device)
 }
 
 /// Parse the IP and port number from a LOCATION header in an SSDP response
-fn parse_ssdp_location(message: &str) -> Option<(String, i32)> {
+fn parse_ssdp_location(message: &str) -> Result<(String, i32)> {
     // Regex for IPv4 and port numbers in LOCATION headers
     let location_regex: Regex = Regex::new(r"LOCATION:\shttp://(\d{1,3}\.\d{1,3}\.\d{1,3}\.\d{1,3}):(\d+).+").unwrap();
 
     // Parse out IP and port, if they exist
-    match location_regex.captures(message) {
-        Some(location) => Some((String::from(&location[1]), i32::from_str(&location[2]).unwrap_or(8060))),
-        None => None
-    }
+    let location = location_regex.captures(message)
+        .ok_or_else(|| KoruError::Parse(String::from("SSDP response has no valid LOCATION header")))?;
+    let ipv4 = Ipv4Addr::from_str(&location[1])
+        .map_err(|_| KoruError::Parse(format!("invalid IPv4 address '{}' in LOCATION header", &location[1])))?;
+    let port = u16::from_str(&location[2])
+        .map_err(|_| KoruError::Parse(format!("invalid port '{}' in LOCATION header", &location[2])))?;
+    Ok((ipv4.to_string(), i32::from(port)))
+}
+
+/// Parse the value of a header from an SSDP message, ignoring case in the header name
+pub(crate) fn parse_ssdp_header(message: &str, name: &str) -> Option<String> {
+    message.lines()
+        .filter_map(|line| line.split_once(':'))
+        .find(|(header, _)| header.trim().eq_ignore_ascii_case(name))
+        .map(|(_, value)| String::from(value.trim()))
+}
+
+/// Parse the USN header from an SSDP response, e.g. "uuid:roku:ecp:X004000AB123"
+fn parse_ssdp_usn(message: &str) -> Option<String> {
+    parse_ssdp_header(message, "USN").filter(|usn| !usn.is_empty())
+}
+
+/// Parse how long an SSDP announcement is valid for from its CACHE-CONTROL header, if there is one
+fn parse_ssdp_max_age(message: &str) -> Option<Duration> {
+    let max_age_regex: Regex = Regex::new(r"(?i)max-age\s*=\s*(\d+)").unwrap();
+    let cache_control = parse_ssdp_header(message, "CACHE-CONTROL")?;
+    let max_age = max_age_regex.captures(&cache_control)?;
+    u64::from_str(&max_age[1]).ok().map(Duration::from_secs)
 }
 
-/// Parse a MAC address from the WAKEUP header in an SSDP response
-fn parse_ssdp_mac(message: &str) -> Option<[u8; 6]> {
+/// Parse a MAC address from the WAKEUP header in an SSDP response, if there is one
+fn parse_ssdp_mac(message: &str) -> Result<Option<[u8; 6]>> {
     // Regex for MAC addresses in WAKEUP headers
     let wakeup_regex: Regex = Regex::new(r"WAKEUP:.*MAC.(..):(..):(..):(..):(..):(..).*").unwrap();
 
@@ -122,14 +391,235 @@ fn parse_ssdp_mac(message: &str) -> Option<[u8; 6]> {
 
     // Parse the MAC, if it exists
     if let Some(mac) = wakeup_regex.captures(message) {
-        // Parse each address group, failing to zero
-        for i in 0..6 {
+        // Parse each address group
+        for (i, byte) in output.iter_mut().enumerate() {
             // Note that the regex match groups are 1-indexed
-            output[i] = u8::from_str_radix(&mac[i+1], 16).unwrap_or(0u8);
+            *byte = u8::from_str_radix(&mac[i+1], 16)
+                .map_err(|_| KoruError::Parse(format!("invalid MAC address in WAKEUP header '{}'", &mac[0])))?;
         }
-        Some(output)
+        Ok(Some(output))
     } else {
-        println!("No captures!");
-        None
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{MockDevice, MockSsdpResponder, SsdpReply};
+    use quickcheck::quickcheck;
+
+    const RESPONSE: &str = "HTTP/1.1 200 OK\r\n\
+Cache-Control: max-age=3600\r\n\
+ST: roku:ecp\r\n\
+USN: uuid:roku:ecp:X004000AB123\r\n\
+Ext: \r\n\
+Server: Roku/9.2.0 UPnP/1.0 Roku/9.2.0\r\n\
+LOCATION: http://192.168.1.134:8060/\r\n\
+device-group.roku.com: 46F5CCE2472F3E4A1F2C\r\n\
+WAKEUP: MAC=d8:31:34:33:2d:7e;Timeout=10\r\n\r\n";
+
+    #[test]
+    fn parses_roku_response() {
+        let device = handle_ssdp_response(RESPONSE.as_bytes()).unwrap();
+        assert_eq!(device.ipv4, "192.168.1.134");
+        assert_eq!(device.port, 8060);
+        assert_eq!(device.mac_wlan, [0xd8, 0x31, 0x34, 0x33, 0x2d, 0x7e]);
+    }
+
+    #[test]
+    fn hostile_responses_are_errors() {
+        // Not UTF-8
+        assert!(handle_ssdp_response(&[0x52, 0x6f, 0x6b, 0x75, 0xff, 0xfe]).is_err());
+        // Not a Roku
+        assert!(handle_ssdp_response(b"HTTP/1.1 200 OK\r\nLOCATION: http://10.0.0.1:80/\r\n\r\n").is_err());
+        // Missing LOCATION
+        assert!(handle_ssdp_response(b"HTTP/1.1 200 OK\r\nServer: Roku/9.2.0\r\n\r\n").is_err());
+        // Out-of-range address and port
+        assert!(parse_ssdp_location("LOCATION: http://999.1.1.1:8060/\r\n").is_err());
+        assert!(parse_ssdp_location("LOCATION: http://10.0.0.1:99999999999/\r\n").is_err());
+        // Non-hex MAC
+        assert!(parse_ssdp_mac("WAKEUP: MAC=zz:31:34:33:2d:7e;Timeout=10\r\n").is_err());
+        // No WAKEUP header at all is fine
+        assert_eq!(parse_ssdp_mac("Server: Roku/9.2.0\r\n").unwrap(), None);
+    }
+
+    #[test]
+    fn handler_never_panics_on_arbitrary_input() {
+        fn prop(raw: Vec<u8>) -> bool {
+            let _ = handle_ssdp_response(&raw);
+            true
+        }
+        quickcheck(prop as fn(Vec<u8>) -> bool);
+    }
+
+    #[test]
+    fn handler_never_panics_on_truncated_response() {
+        fn prop(cut: usize) -> bool {
+            let _ = handle_ssdp_response(&RESPONSE.as_bytes()[..cut % (RESPONSE.len() + 1)]);
+            true
+        }
+        quickcheck(prop as fn(usize) -> bool);
+    }
+
+    // Search a mock responder once, giving up quickly
+    fn mock_options(responder: &MockSsdpResponder) -> DiscoveryOptions {
+        DiscoveryOptions { address: responder.address(), timeout: Duration::from_millis(500), searches: 1, ..DiscoveryOptions::default() }
+    }
+
+    #[test]
+    fn parses_usn() {
+        assert_eq!(parse_ssdp_usn(RESPONSE).as_deref(), Some("uuid:roku:ecp:X004000AB123"));
+        assert_eq!(parse_ssdp_usn("Server: Roku/9.2.0\r\n"), None);
+    }
+
+    #[tokio::test]
+    async fn discovers_mock_devices_end_to_end() {
+        let living_room = MockDevice::start().await.unwrap();
+        let den = MockDevice::start().await.unwrap();
+        den.with_state(|state| {
+            state.device_info.insert(String::from("friendly-device-name"), String::from("Den"));
+            state.device_info.insert(String::from("serial-number"), String::from("MOCK00000002"));
+        });
+        let responder = MockSsdpResponder::start(vec![living_room.ssdp_reply(), den.ssdp_reply()]).await.unwrap();
+
+        let mut devices = discover_stream(mock_options(&responder)).await.unwrap().collect::<Vec<_>>().await;
+        assert_eq!(responder.searches(), vec!["roku:ecp"]);
+        let mut ports = devices.iter().map(|d| d.port as u16).collect::<Vec<_>>();
+        let mut expected = [living_room.address().port(), den.address().port()];
+        ports.sort_unstable();
+        expected.sort_unstable();
+        assert_eq!(ports, expected);
+        assert_eq!(devices[0].mac_wlan, [0x02, 0, 0, 0, 0, 0x01]);
+
+        // Discovered devices are usable as-is
+        let mut names = Vec::new();
+        for device in devices.iter_mut() {
+            device.update_self().await.unwrap();
+            names.push(device.name.clone());
+        }
+        names.sort();
+        assert_eq!(names, vec!["Den", "Mock Roku"]);
+    }
+
+    #[tokio::test]
+    async fn discovery_skips_malformed_and_late_replies() {
+        let responder = MockSsdpResponder::start(vec![
+            SsdpReply::new("uuid:roku:ecp:A", "http://10.0.0.1:8060/"),
+            // No WAKEUP header
+            SsdpReply::new("uuid:roku:ecp:B", "http://10.0.0.2:8060/").delay(Duration::from_millis(100)),
+            SsdpReply::raw("HTTP/1.1 200 OK\r\nServer: Roku/9.2.0\r\n\r\n"),
+            SsdpReply::raw("HTTP/1.1 200 OK\r\nServer: Linux UPnP/1.0\r\nLOCATION: http://10.0.0.9:80/\r\n\r\n"),
+            SsdpReply::new("uuid:roku:ecp:C", "http://999.0.0.3:8060/"),
+            SsdpReply::new("uuid:roku:ecp:D", "http://10.0.0.4:8060/").wakeup("MAC=zz:zz:zz:zz:zz:zz;Timeout=10"),
+            // Arrives after discovery has given up waiting
+            SsdpReply::new("uuid:roku:ecp:E", "http://10.0.0.5:8060/").delay(Duration::from_secs(2)),
+        ]).await.unwrap();
+
+        let mut addresses = discover_stream(mock_options(&responder)).await.unwrap().collect::<Vec<_>>().await
+            .into_iter()
+            .map(|d| (d.ipv4, d.mac_wlan))
+            .collect::<Vec<_>>();
+        addresses.sort();
+        assert_eq!(addresses, vec![(String::from("10.0.0.1"), [0; 6]), (String::from("10.0.0.2"), [0; 6])]);
+    }
+
+    #[tokio::test]
+    async fn stream_yields_devices_as_they_respond() {
+        let responder = MockSsdpResponder::start(vec![
+            SsdpReply::new("uuid:roku:ecp:A", "http://10.0.0.1:8060/"),
+            SsdpReply::new("uuid:roku:ecp:B", "http://10.0.0.2:8060/").delay(Duration::from_millis(300)),
+        ]).await.unwrap();
+        let options = DiscoveryOptions { timeout: Duration::from_secs(2), ..mock_options(&responder) };
+
+        let started = Instant::now();
+        let mut stream = discover_stream(options).await.unwrap();
+        assert_eq!(stream.next().await.unwrap().ipv4, "10.0.0.1");
+        // Well before the timeout
+        assert!(started.elapsed() < Duration::from_millis(300));
+        assert_eq!(stream.next().await.unwrap().ipv4, "10.0.0.2");
+        // Stop early instead of waiting out the timeout
+        stream.cancel();
+        assert!(stream.next().await.is_none());
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn repeated_searches_are_deduplicated() {
+        let responder = MockSsdpResponder::start(vec![
+            SsdpReply::new("uuid:roku:ecp:A", "http://10.0.0.1:8060/"),
+            // Same device replying twice per search
+            SsdpReply::new("uuid:roku:ecp:A", "http://10.0.0.1:8060/"),
+            SsdpReply::new("uuid:roku:ecp:B", "http://10.0.0.2:8060/"),
+        ]).await.unwrap();
+        let options = DiscoveryOptions { searches: 3, search_interval: Duration::from_millis(50), ..mock_options(&responder) };
+
+        let devices = discover_stream(options).await.unwrap().collect::<Vec<_>>().await;
+        assert_eq!(responder.searches().len(), 3);
+        assert_eq!(devices.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn enriched_devices_are_deduplicated_by_serial() {
+        let mock = MockDevice::start().await.unwrap();
+        let other = MockDevice::start().await.unwrap();
+        other.with_state(|state| state.device_info.insert(String::from("serial-number"), String::from("MOCK00000002")));
+        // The first mock answers twice under different USNs, e.g. once per network interface
+        let responder = MockSsdpResponder::start(vec![
+            mock.ssdp_reply(),
+            SsdpReply { usn: String::from("uuid:roku:ecp:eth0"), ..mock.ssdp_reply() },
+            other.ssdp_reply(),
+        ]).await.unwrap();
+        let options = DiscoveryOptions { enrich: true, ..mock_options(&responder) };
+
+        let devices = discover_stream(options).await.unwrap().collect::<Vec<_>>().await;
+        assert_eq!(devices.len(), 2);
+        assert!(devices.iter().all(|d| d.name == "Mock Roku"));
+    }
+
+    #[test]
+    fn unicast_targets_cover_subnet_hosts() {
+        let options = DiscoveryOptions {
+            unicast: vec!["10.0.0.0/30".parse().unwrap(), "192.168.1.134/32".parse().unwrap()],
+            ..DiscoveryOptions::default()
+        };
+        let targets = options.targets().iter().map(|t| t.to_string()).collect::<Vec<_>>();
+        assert_eq!(targets, vec!["10.0.0.1:1900", "10.0.0.2:1900", "192.168.1.134:1900"]);
+        assert_eq!(DiscoveryOptions::default().targets(), vec![SocketAddr::from(SSDP_ADDRESS)]);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn search_message_uses_options() {
+        let host = SocketAddr::from(SSDP_ADDRESS);
+        let options = DiscoveryOptions { mx: Some(2), search_target: String::from("ssdp:all"), ..DiscoveryOptions::default() };
+        assert_eq!(search_message(&host, &options), "M-SEARCH * HTTP/1.1\r\nHost: 239.255.255.250:1900\r\nMan: \"ssdp:discover\"\r\nMX: 2\r\nST: ssdp:all\r\n\r\n");
+        assert!(!search_message(&host, &DiscoveryOptions::default()).contains("MX"));
+    }
+
+    #[tokio::test]
+    async fn searches_from_interfaces_and_by_unicast() {
+        let responder = MockSsdpResponder::start(vec![SsdpReply::new("uuid:roku:ecp:A", "http://10.0.0.1:8060/")]).await.unwrap();
+        // Search straight at the responder's host, from loopback
+        let options = DiscoveryOptions {
+            unicast: vec![Ipv4Net::new(Ipv4Addr::LOCALHOST, 32).unwrap()],
+            interfaces: vec![Ipv4Addr::LOCALHOST],
+            search_target: String::from("ssdp:all"),
+            address: SocketAddr::from((Ipv4Addr::UNSPECIFIED, responder.address().port())),
+            ..mock_options(&responder)
+        };
+        let devices = discover_stream(options).await.unwrap().collect::<Vec<_>>().await;
+        assert_eq!(devices.len(), 1);
+        assert_eq!(responder.searches(), vec!["ssdp:all"]);
+    }
+
+    #[tokio::test]
+    async fn long_responses_need_a_bigger_buffer() {
+        // LOCATION comes after Server, so it's lost if the response gets truncated
+        let chatty = SsdpReply::new("uuid:roku:ecp:A", "http://10.0.0.1:8060/").server(&"Roku/9.2.0 ".repeat(120));
+        let responder = MockSsdpResponder::start(vec![chatty]).await.unwrap();
+
+        let truncated = DiscoveryOptions { buffer_size: 1024, ..mock_options(&responder) };
+        assert_eq!(discover_stream(truncated).await.unwrap().count().await, 0);
+        assert_eq!(discover_stream(mock_options(&responder)).await.unwrap().count().await, 1);
+    }
+}
@@ -0,0 +1,358 @@
+/// Background polling of device state, reporting what changed
+use std::collections::HashMap;
+use std::fmt;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use futures::Stream;
+use tokio::sync::broadcast;
+use tokio::task::JoinHandle;
+use tokio::time::MissedTickBehavior;
+use tracing::{debug, trace, Instrument};
+use crate::{ActiveApp, AppId, Device, MediaPlayerState, PlayerState, POWERSTATE};
+
+// Events buffered per subscriber; slower subscribers miss the oldest ones
+const EVENT_BUFFER: usize = 256;
+
+// Don't poll any device more often than this, whatever the options say; each poll is up to three ECP requests
+pub const MIN_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often to poll a device, and what for
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WatchOptions {
+    pub interval:   Duration,   // Time between polls, at least 1s
+    pub active_app: bool,       // Also poll query/active-app
+    pub media:      bool,       // Also poll query/media-player
+}
+
+impl Default for WatchOptions {
+    fn default() -> Self {
+        WatchOptions {
+            interval: Duration::from_secs(5),
+            active_app: true,
+            media: true,
+        }
+    }
+}
+
+/// State of a device as of its last poll
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DeviceState {
+    pub power: POWERSTATE,
+    pub active_app: Option<ActiveApp>,      // None if not polled, or the device isn't answering
+    pub media: Option<MediaPlayerState>,    // None if not polled, or the device isn't answering
+}
+
+impl DeviceState {
+    /// Whether the device is answering requests
+    pub fn is_present(&self) -> bool {
+        matches!(self.power, POWERSTATE::ON | POWERSTATE::DISPLAYOFF)
+    }
+}
+
+/// Change in a watched device's state
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(tag = "event", rename_all = "snake_case"))]
+pub enum DeviceEvent {
+    Appeared { id: String, device: Device },                // Device started answering
+    Disappeared { id: String },                             // Device stopped answering, e.g. it's off or unplugged
+    PowerChanged { id: String, power: POWERSTATE },
+    ActiveAppChanged { id: String, app: ActiveApp },
+    MediaChanged { id: String, media: MediaPlayerState },   // Player state, app or error changed (not just the position)
+}
+
+impl DeviceEvent {
+    /// Id of the device this event is about
+    pub fn id(&self) -> &str {
+        match self {
+            DeviceEvent::Appeared { id, .. }
+            | DeviceEvent::Disappeared { id }
+            | DeviceEvent::PowerChanged { id, .. }
+            | DeviceEvent::ActiveAppChanged { id, .. }
+            | DeviceEvent::MediaChanged { id, .. } => id
+        }
+    }
+}
+
+/// Last polled state of each device, by id
+type States = Arc<Mutex<HashMap<String, DeviceState>>>;
+
+/// Device being polled
+#[derive(Debug)]
+struct Watched {
+    options: WatchOptions,
+    task: JoinHandle<()>,
+}
+
+/// Polls devices in the background, sending every subscriber what changed; dropping this stops polling
+#[derive(Debug)]
+pub struct DeviceWatcher {
+    events: broadcast::Sender<DeviceEvent>,
+    watched: Mutex<HashMap<String, Watched>>,
+    states: States,
+    min_interval: Duration,     // Shorter intervals are raised to this
+}
+
+impl Default for DeviceWatcher {
+    fn default() -> Self {
+        DeviceWatcher::new()
+    }
+}
+
+impl DeviceWatcher {
+    /// Create a watcher that isn't watching anything yet
+    pub fn new() -> DeviceWatcher {
+        DeviceWatcher::with_min_interval(MIN_POLL_INTERVAL)
+    }
+
+    /// Create a watcher with a different polling floor, e.g. for mock devices on localhost
+    pub(crate) fn with_min_interval(min_interval: Duration) -> DeviceWatcher {
+        let (events, _) = broadcast::channel(EVENT_BUFFER);
+        DeviceWatcher { events, watched: Mutex::new(HashMap::new()), states: States::default(), min_interval }
+    }
+
+    /// Shortest time between polls of a device; shorter intervals are raised to this
+    pub fn min_interval(&self) -> Duration {
+        self.min_interval
+    }
+
+    /// Start polling a device, identified in events by the given id (e.g. its serial number).
+    /// Watching an id again replaces its device and options, without repeating events for what's already known.
+    pub fn watch(&self, id: impl Into<String>, device: Device, options: WatchOptions) {
+        let id = id.into();
+        let span = tracing::debug_span!("device_watch", %id, device = %device.base_url());
+        let interval = options.interval.max(self.min_interval);
+        let task = tokio::spawn(poll(id.clone(), device, options.clone(), interval, self.states.clone(), self.events.clone()).instrument(span));
+        if let Some(replaced) = lock(&self.watched).insert(id, Watched { options, task }) {
+            replaced.task.abort();
+        }
+    }
+
+    /// Stop polling a device, returning whether it was being watched
+    pub fn unwatch(&self, id: &str) -> bool {
+        let watched = lock(&self.watched).remove(id);
+        if let Some(watched) = &watched {
+            watched.task.abort();
+        }
+        lock(&self.states).remove(id);
+        watched.is_some()
+    }
+
+    /// Options a device is being watched with, if it's being watched
+    pub fn options(&self, id: &str) -> Option<WatchOptions> {
+        lock(&self.watched).get(id).map(|watched| watched.options.clone())
+    }
+
+    /// Ids of every device being watched
+    pub fn watched(&self) -> Vec<String> {
+        lock(&self.watched).keys().cloned().collect()
+    }
+
+    /// State of a device as of its last poll
+    pub fn state(&self, id: &str) -> Option<DeviceState> {
+        lock(&self.states).get(id).cloned()
+    }
+
+    /// Receive events from now on
+    pub fn subscribe(&self) -> DeviceEvents {
+        let events = futures::stream::unfold(self.events.subscribe(), |mut receiver| async move {
+            loop {
+                match receiver.recv().await {
+                    Ok(event) => return Some((event, receiver)),
+                    Err(broadcast::error::RecvError::Lagged(missed)) => debug!(missed, "subscriber fell behind, skipping events"),
+                    Err(broadcast::error::RecvError::Closed) => return None,
+                }
+            }
+        });
+        DeviceEvents { events: Box::pin(events) }
+    }
+}
+
+impl Drop for DeviceWatcher {
+    fn drop(&mut self) {
+        for (_, watched) in lock(&self.watched).drain() {
+            watched.task.abort();
+        }
+    }
+}
+
+/// Events from a DeviceWatcher; ends when the watcher is dropped
+pub struct DeviceEvents {
+    events: Pin<Box<dyn Stream<Item = DeviceEvent> + Send>>,
+}
+
+impl fmt::Debug for DeviceEvents {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DeviceEvents").finish_non_exhaustive()
+    }
+}
+
+impl Stream for DeviceEvents {
+    type Item = DeviceEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<DeviceEvent>> {
+        self.events.as_mut().poll_next(cx)
+    }
+}
+
+/// Lock shared state, ignoring poisoning (it's always left consistent)
+fn lock<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Poll a device until aborted, sending events for whatever changed
+async fn poll(id: String, device: Device, options: WatchOptions, interval: Duration, states: States, events: broadcast::Sender<DeviceEvent>) {
+    let mut interval = tokio::time::interval(interval);
+    // A device that's slow to answer shouldn't get a burst of polls afterwards
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        let last = lock(&states).get(&id).cloned();
+        let state = fetch(&device, &options, last.as_ref()).await;
+        lock(&states).insert(id.clone(), state.clone());
+        for event in changes(&id, &device, last.as_ref(), &state) {
+            trace!(?event, "device changed");
+            // Nobody subscribed is fine, there may be someone next time
+            let _ = events.send(event);
+        }
+    }
+}
+
+/// Poll a device's state, keeping what we knew if an optional query fails
+async fn fetch(device: &Device, options: &WatchOptions, last: Option<&DeviceState>) -> DeviceState {
+    let mut state = DeviceState { power: device.get_power_state().await, ..DeviceState::default() };
+    // Don't bother asking a device that isn't answering
+    if state.is_present() {
+        if options.active_app {
+            state.active_app = device.get_active_app().await.ok().or_else(|| last.and_then(|last| last.active_app.clone()));
+        }
+        if options.media {
+            state.media = device.get_media_player().await.ok().or_else(|| last.and_then(|last| last.media.clone()));
+        }
+    }
+    state
+}
+
+/// Events for the differences between two polls
+fn changes(id: &str, device: &Device, last: Option<&DeviceState>, now: &DeviceState) -> Vec<DeviceEvent> {
+    let id = String::from(id);
+    let was_present = last.is_some_and(DeviceState::is_present);
+    let mut events = Vec::new();
+    if now.is_present() && !was_present {
+        events.push(DeviceEvent::Appeared { id: id.clone(), device: device.clone() });
+    }
+    if !now.is_present() && was_present {
+        events.push(DeviceEvent::Disappeared { id: id.clone() });
+    }
+    if last.map(|last| &last.power) != Some(&now.power) {
+        events.push(DeviceEvent::PowerChanged { id: id.clone(), power: now.power.clone() });
+    }
+    if let Some(app) = &now.active_app {
+        if last.and_then(|last| last.active_app.as_ref()) != Some(app) {
+            events.push(DeviceEvent::ActiveAppChanged { id: id.clone(), app: app.clone() });
+        }
+    }
+    if let Some(media) = &now.media {
+        if last.and_then(|last| last.media.as_ref()).map(transition) != Some(transition(media)) {
+            events.push(DeviceEvent::MediaChanged { id, media: media.clone() });
+        }
+    }
+    events
+}
+
+/// The parts of the media player state that count as a transition (position changes every poll)
+fn transition(media: &MediaPlayerState) -> (&PlayerState, Option<&AppId>, bool) {
+    (&media.state, media.plugin.as_ref().map(|plugin| &plugin.id), media.error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockDevice;
+    use futures::StreamExt;
+
+    fn state(power: POWERSTATE, media: Option<PlayerState>) -> DeviceState {
+        DeviceState {
+            power,
+            active_app: Some(ActiveApp::Home { screensaver: None }),
+            media: media.map(|state| MediaPlayerState { state, ..MediaPlayerState::default() })
+        }
+    }
+
+    async fn next(events: &mut DeviceEvents) -> DeviceEvent {
+        tokio::time::timeout(Duration::from_secs(3), events.next()).await.expect("no device event").unwrap()
+    }
+
+    #[test]
+    fn reports_only_what_changed() {
+        let device = Device::from_ipv4("10.0.0.1", 8060);
+        let on = state(POWERSTATE::ON, Some(PlayerState::Play));
+
+        let first = changes("A", &device, None, &on);
+        assert!(matches!(first.as_slice(), [
+            DeviceEvent::Appeared { .. }, DeviceEvent::PowerChanged { .. }, DeviceEvent::ActiveAppChanged { .. }, DeviceEvent::MediaChanged { .. }
+        ]));
+        // Playback moving along isn't a transition
+        let mut later = on.clone();
+        later.media.as_mut().unwrap().position = Some(Duration::from_secs(42));
+        assert!(changes("A", &device, Some(&on), &later).is_empty());
+
+        let paused = state(POWERSTATE::ON, Some(PlayerState::Pause));
+        assert!(matches!(changes("A", &device, Some(&on), &paused).as_slice(), [DeviceEvent::MediaChanged { .. }]));
+
+        // Going away doesn't report the app or player, which weren't polled
+        let off = DeviceState { power: POWERSTATE::OFF, ..DeviceState::default() };
+        let events = changes("A", &device, Some(&on), &off);
+        assert_eq!(events, vec![DeviceEvent::Disappeared { id: String::from("A") }, DeviceEvent::PowerChanged { id: String::from("A"), power: POWERSTATE::OFF }]);
+        assert!(changes("A", &device, Some(&off), &off).is_empty());
+    }
+
+    #[tokio::test]
+    async fn polls_devices_for_changes() {
+        let mock = MockDevice::start().await.unwrap();
+        let watcher = DeviceWatcher::with_min_interval(Duration::from_millis(10));
+        let mut events = watcher.subscribe();
+        let options = WatchOptions { interval: Duration::from_millis(50), ..WatchOptions::default() };
+        watcher.watch("mock", mock.device(), options.clone());
+
+        assert!(matches!(next(&mut events).await, DeviceEvent::Appeared { ref id, .. } if id == "mock"));
+        assert_eq!(next(&mut events).await, DeviceEvent::PowerChanged { id: String::from("mock"), power: POWERSTATE::ON });
+        assert!(matches!(next(&mut events).await, DeviceEvent::ActiveAppChanged { app: ActiveApp::Home { .. }, .. }));
+        assert!(matches!(next(&mut events).await, DeviceEvent::MediaChanged { .. }));
+
+        mock.device().launch_app_by_id("12").await.unwrap();
+        match next(&mut events).await {
+            DeviceEvent::ActiveAppChanged { app, .. } => assert_eq!(app.app().unwrap().name, "Netflix"),
+            event => panic!("unexpected {:?}", event)
+        }
+        mock.set_power(POWERSTATE::DISPLAYOFF);
+        assert_eq!(next(&mut events).await, DeviceEvent::PowerChanged { id: String::from("mock"), power: POWERSTATE::DISPLAYOFF });
+        assert_eq!(watcher.state("mock").unwrap().power, POWERSTATE::DISPLAYOFF);
+
+        // Changing options doesn't repeat what's already known
+        watcher.watch("mock", mock.device(), WatchOptions { media: false, ..options });
+        assert_eq!(watcher.options("mock").map(|o| o.media), Some(false));
+        assert!(tokio::time::timeout(Duration::from_millis(200), events.next()).await.is_err());
+
+        assert!(watcher.unwatch("mock"));
+        assert!(watcher.watched().is_empty());
+        drop(watcher);
+        assert_eq!(events.next().await, None);
+    }
+
+    #[tokio::test]
+    async fn polls_at_most_once_a_second() {
+        let mock = MockDevice::start().await.unwrap();
+        let watcher = DeviceWatcher::new();
+        assert_eq!(watcher.min_interval(), Duration::from_secs(1));
+        watcher.watch("mock", mock.device(), WatchOptions { interval: Duration::from_millis(50), ..WatchOptions::default() });
+
+        // Polls at 0s and 1s, rather than every 50ms
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        let polls = mock.requests().iter().filter(|r| r.path == "query/device-info").count();
+        assert_eq!(polls, 2);
+    }
+}